@@ -0,0 +1,161 @@
+//! Aliases let several spellings of a tag mean the same thing, like `dl` for `deep-learning`.
+//! Tags are replaced by the tag they're an alias of when they're added, so only the canonical
+//! tag ends up stored, and `tagg find` looks for both the canonical tag and its aliases (since
+//! files may have been tagged with an alias before it was made one).
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// The table of aliases, from the alias to the canonical tag.
+/// The canonical tags are never aliases themselves, so resolving an alias only takes one lookup.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Aliases {
+    aliases: BTreeMap<String, String>,
+}
+impl Aliases {
+    pub fn is_empty(&self) -> bool {
+        self.aliases.is_empty()
+    }
+
+    /// The alias and the tag it stands for, sorted by alias
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.aliases.iter().map(|(a, b)| (a.as_str(), b.as_str()))
+    }
+
+    /// The canonical tag for `tag`, which is `tag` itself if it isn't an alias
+    pub fn resolve<'a>(&'a self, tag: &'a str) -> &'a str {
+        self.aliases.get(tag).map(String::as_str).unwrap_or(tag)
+    }
+
+    /// Replace any aliases in the tags with their canonical tag.
+    /// Returns the aliases that were replaced, along with what they were replaced with.
+    pub fn resolve_all(&self, tags: &mut [String]) -> Vec<(String, String)> {
+        let mut replaced = Vec::new();
+        for tag in tags.iter_mut() {
+            if let Some(canonical) = self.aliases.get(tag.as_str()) {
+                replaced.push((std::mem::replace(tag, canonical.clone()), canonical.clone()));
+            }
+        }
+        replaced
+    }
+
+    /// The aliases of the canonical tag
+    pub fn aliases_of(&self, tag: &str) -> Vec<&str> {
+        self.aliases
+            .iter()
+            .filter(|(_, canonical)| *canonical == tag)
+            .map(|(alias, _)| alias.as_str())
+            .collect()
+    }
+
+    /// Make `alias` stand for `tag`.
+    /// If `tag` is itself an alias then `alias` stands for what it stands for instead, and if
+    /// `alias` had aliases of its own then they're moved over to `tag`.
+    pub fn add(&mut self, alias: &str, tag: &str) -> eyre::Result<()> {
+        let canonical = self.resolve(tag).to_string();
+        if alias == canonical && alias != tag {
+            return Err(eyre::eyre!(
+                "{:?} is already an alias of {:?}, use `tagg alias remove {}` first to swap them",
+                tag,
+                alias,
+                tag
+            ));
+        } else if alias == canonical {
+            return Err(eyre::eyre!("{:?} can't be an alias of itself", alias));
+        }
+        let tag = canonical;
+
+        for canonical in self.aliases.values_mut() {
+            if canonical == alias {
+                *canonical = tag.clone();
+            }
+        }
+        self.aliases.insert(alias.to_string(), tag);
+        Ok(())
+    }
+
+    /// Replace the tags that the aliases stand for, when tags are renamed by `rename`.
+    /// Aliases which would end up standing for themselves are removed.  
+    /// Returns the number of aliases which were changed or removed.
+    pub fn rename_tags(&mut self, rename: impl Fn(&str) -> Option<String>) -> usize {
+        let renamed: BTreeMap<String, String> = self
+            .aliases
+            .iter()
+            .map(|(alias, tag)| (alias.clone(), rename(tag).unwrap_or_else(|| tag.clone())))
+            .collect();
+
+        let mut changed = 0;
+        let mut aliases = BTreeMap::new();
+        for (alias, tag) in renamed.iter() {
+            // The tag may have been renamed to what is itself an alias
+            let tag = renamed.get(tag).unwrap_or(tag);
+            if *tag != self.aliases[alias] {
+                changed += 1;
+            }
+            if alias != tag {
+                aliases.insert(alias.clone(), tag.clone());
+            }
+        }
+        self.aliases = aliases;
+        changed
+    }
+
+    /// Stop `alias` from being an alias, returning the tag it stood for
+    pub fn remove(&mut self, alias: &str) -> Option<String> {
+        self.aliases.remove(alias)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Aliases;
+
+    #[test]
+    fn test_aliases() {
+        let mut aliases = Aliases::default();
+        aliases.add("dl", "deeplearning").unwrap();
+        // Renaming the canonical tag moves its aliases over
+        aliases.add("deeplearning", "deep-learning").unwrap();
+        // An alias of an alias is an alias of the canonical tag
+        aliases.add("d-l", "dl").unwrap();
+        assert!(aliases.add("deep-learning", "dl").is_err());
+
+        assert_eq!(aliases.resolve("dl"), "deep-learning");
+        assert_eq!(aliases.resolve("d-l"), "deep-learning");
+        assert_eq!(aliases.resolve("paper"), "paper");
+        assert_eq!(
+            aliases.aliases_of("deep-learning"),
+            ["d-l", "deeplearning", "dl"]
+        );
+
+        let mut tags = vec!["paper".to_string(), "dl".to_string()];
+        let replaced = aliases.resolve_all(&mut tags);
+        assert_eq!(tags, ["paper", "deep-learning"]);
+        assert_eq!(replaced, [("dl".to_string(), "deep-learning".to_string())]);
+
+        assert_eq!(aliases.remove("dl").as_deref(), Some("deep-learning"));
+        assert_eq!(aliases.resolve("dl"), "dl");
+    }
+
+    #[test]
+    fn test_rename_tags() {
+        let mut aliases = Aliases::default();
+        aliases.add("dl", "deep-learning").unwrap();
+        aliases.add("nn", "neural-net").unwrap();
+        aliases.add("ml", "machine-learning").unwrap();
+
+        let changed = aliases.rename_tags(|tag| match tag {
+            "deep-learning" => Some("deep/learning".to_string()),
+            // Renamed into its own alias, which stops being one
+            "neural-net" => Some("nn".to_string()),
+            _ => None,
+        });
+        assert_eq!(changed, 2);
+        assert_eq!(aliases.resolve("dl"), "deep/learning");
+        assert_eq!(aliases.resolve("nn"), "nn");
+        assert_eq!(aliases.resolve("ml"), "machine-learning");
+        assert_eq!(aliases.iter().count(), 2);
+    }
+}
@@ -0,0 +1,66 @@
+use std::io::Write;
+
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+use crate::{
+    query::{Expr, Query, TagPattern},
+    storage::StorageBackend,
+    tagg::Tagg,
+    AliasCommand,
+};
+
+use super::grey;
+
+pub(crate) fn alias(tagg: &mut Tagg, command: AliasCommand) -> eyre::Result<()> {
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+
+    match command {
+        AliasCommand::Add { alias, tag } => {
+            let aliases = &mut tagg.state_mut()?.aliases;
+            aliases.add(&alias, &tag)?;
+            let tag = aliases.resolve(&alias).to_string();
+            tagg.save_state()?;
+            writeln!(&mut stdout, "{:?} now stands for {:?}", alias, tag)?;
+
+            // Files tagged before the alias existed keep the alias as their tag
+            let query = Query {
+                expr: Expr::Tag(TagPattern::Exact(alias.clone())),
+                case_insensitive: false,
+            };
+            let tagged = tagg.storage()?.find(&query)?.len();
+            if tagged > 0 {
+                eprintln!(
+                    "INFO: {} stored files have the tag {:?}, use `tagg tag rename {} {}` to replace it with {:?}",
+                    tagged, alias, alias, tag, tag
+                );
+            }
+        }
+        AliasCommand::Remove { alias } => match tagg.state_mut()?.aliases.remove(&alias) {
+            Some(tag) => {
+                tagg.save_state()?;
+                writeln!(&mut stdout, "{:?} no longer stands for {:?}", alias, tag)?;
+            }
+            None => {
+                eprintln!("WARN: {:?} is not an alias", alias);
+            }
+        },
+        AliasCommand::List {} => {
+            let aliases = &tagg.state()?.aliases;
+            if aliases.is_empty() {
+                writeln!(&mut stdout, "There are no aliases")?;
+            }
+
+            for (alias, tag) in aliases.iter() {
+                stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
+                write!(&mut stdout, "  {}", alias)?;
+                stdout.set_color(&grey())?;
+                write!(&mut stdout, " -> ")?;
+                stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
+                writeln!(&mut stdout, "{}", tag)?;
+            }
+            stdout.reset()?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use std::{collections::HashMap, io::Write, path::Path};
+
+use inquire::Select;
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+use crate::storage::{Storage, StorageBackend};
+
+use super::print_file;
+
+/// What to do with a file that is byte-identical to a file already in storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateAction {
+    /// Merge the tags and comments into the existing entry, and don't store the file again
+    Merge,
+    /// Don't do anything with the file
+    Skip,
+    /// Store the file anyway, creating a second copy
+    Store,
+}
+impl DuplicateAction {
+    const OPTIONS: [&'static str; 3] = [
+        "Merge the tags and comments into the existing entry",
+        "Skip the file",
+        "Store it anyway",
+    ];
+}
+
+/// Check if there is a stored file with the same hash, and if there is ask the user what to do.
+/// If the user chooses to merge then the tags and comments are merged into the existing entry,
+/// unless `dry` is set, in which case the merge is only shown.  
+/// Returns [`DuplicateAction::Store`] if there was no duplicate.
+pub fn resolve_duplicate(
+    storage: &mut dyn StorageBackend,
+    path: &Path,
+    hash: &str,
+    tags: &[String],
+    comments: &HashMap<String, String>,
+    dry: bool,
+) -> eyre::Result<DuplicateAction> {
+    let existing = storage.find_by_hash(hash)?;
+    if existing.is_empty() {
+        return Ok(DuplicateAction::Store);
+    }
+
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+    stdout.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))?;
+    writeln!(
+        &mut stdout,
+        "{:?} is identical to files already in storage:",
+        path
+    )?;
+    for file in existing.iter() {
+        print_file(
+            &mut stdout,
+            &file.filename,
+            file.original_filename.as_deref(),
+            &file.tags,
+        )?;
+    }
+    stdout.reset()?;
+
+    let choice = Select::new(
+        "What do you want to do with it?",
+        DuplicateAction::OPTIONS.to_vec(),
+    )
+    .prompt()?;
+    let action = match DuplicateAction::OPTIONS.iter().position(|x| *x == choice) {
+        Some(0) => DuplicateAction::Merge,
+        Some(1) => DuplicateAction::Skip,
+        _ => DuplicateAction::Store,
+    };
+
+    if action == DuplicateAction::Merge {
+        let mut file = existing
+            .into_iter()
+            .next()
+            .expect("There to be a file with the hash");
+        file.tags.extend(tags.iter().cloned());
+        file.tags.sort();
+        file.tags.dedup();
+
+        for (title, comment) in comments.iter() {
+            match file.comments.get(title) {
+                Some(existing) if existing != comment => {
+                    eprintln!(
+                        "WARN: {:?} already had a {:?} comment, so the new one was not merged: {}",
+                        file.filename, title, comment
+                    );
+                }
+                Some(_) => {}
+                None => {
+                    file.comments.insert(title.clone(), comment.clone());
+                }
+            }
+        }
+
+        if !dry {
+            storage.update(&file)?;
+        }
+
+        print_file(
+            &mut stdout,
+            &file.filename,
+            file.original_filename.as_deref(),
+            &file.tags,
+        )?;
+    }
+
+    Ok(action)
+}
+
+/// List the groups of stored files which have identical contents
+pub fn dupes(storage: &Storage) -> eyre::Result<()> {
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+
+    let groups = storage.duplicate_groups();
+    if groups.is_empty() {
+        writeln!(&mut stdout, "No duplicates found.")?;
+    }
+
+    for (i, group) in groups.iter().enumerate() {
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))?;
+        writeln!(&mut stdout, "Group {} ({} files):", i + 1, group.len())?;
+        for file in group {
+            print_file(
+                &mut stdout,
+                &file.filename,
+                file.original_filename.as_deref(),
+                &file.tags,
+            )?;
+        }
+    }
+    stdout.reset()?;
+
+    let unhashed = storage.files.iter().filter(|x| x.hash.is_none()).count();
+    if unhashed > 0 {
+        eprintln!(
+            "INFO: {} files have no stored hash and were not checked, use `tagg fsck` to hash them",
+            unhashed
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,238 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+};
+
+use inquire::Confirm;
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+use crate::{
+    lock::StateLock,
+    state::{backup_path, AddedFile},
+    storage::StorageBackend,
+    tagg::Tagg,
+    util::hash_file,
+};
+
+use super::{grey, print_file};
+
+/// Check the storage folder against the state, and offer to repair any problems found.
+pub fn fsck(tagg: &mut Tagg, full: bool, dry: bool) -> eyre::Result<()> {
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+
+    let storage_path = tagg.config.storage_path(&tagg.config_path)?;
+    // The state and config could be stored inside the storage folder, but they're obviously not orphans
+    let mut ignored = vec![
+        tagg.state_path.canonicalize().ok(),
+        tagg.staging_path.canonicalize().ok(),
+        tagg.config_path.canonicalize().ok(),
+        StateLock::lock_path(&tagg.state_path).canonicalize().ok(),
+        tagg.config
+            .database_path(&tagg.config_path, &tagg.state_path)?
+            .canonicalize()
+            .ok(),
+        tagg.journal_path()?.canonicalize().ok(),
+    ];
+    for n in 1..=tagg.config.state_backups {
+        ignored.push(backup_path(&tagg.state_path, n).canonicalize().ok());
+    }
+
+    let mut on_disk = HashSet::new();
+    for entry in std::fs::read_dir(&storage_path)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+
+        let path = entry.path();
+        if ignored.contains(&path.canonicalize().ok()) {
+            continue;
+        }
+
+        on_disk.insert(entry.file_name().to_string_lossy().into_owned());
+    }
+
+    let hash_added_files = tagg.config.hash_added_files;
+    let (mut storage, staging) = tagg.storage_and_staging()?;
+    let mut files = storage.all()?;
+
+    let mut missing = Vec::new();
+    let mut changed = Vec::new();
+    let mut unhashed = Vec::new();
+    for (i, file) in files.iter().enumerate() {
+        if !on_disk.remove(&file.filename) {
+            missing.push(i);
+            continue;
+        }
+
+        let path = storage_path.join(&file.filename);
+        match file.content_changed(&path, full)? {
+            Some(true) => changed.push(i),
+            Some(false) if file.is_image() && file.perceptual_hash.is_none() => unhashed.push(i),
+            Some(false) => {}
+            None => unhashed.push(i),
+        }
+    }
+    // Whatever is left on disk isn't referenced by any entry, though it may have already been re-adopted
+    let mut orphans = Vec::new();
+    for name in on_disk {
+        let path = storage_path.join(&name);
+        if !staging
+            .registration_area
+            .iter()
+            .any(|added_file| added_file.path == path)
+        {
+            orphans.push(name);
+        }
+    }
+    orphans.sort();
+
+    if missing.is_empty() && changed.is_empty() && unhashed.is_empty() && orphans.is_empty() {
+        writeln!(&mut stdout, "No problems found.")?;
+        return Ok(());
+    }
+
+    write_section(
+        &mut stdout,
+        "Missing from the storage folder:",
+        missing.len(),
+    )?;
+    for &i in missing.iter() {
+        print_file(
+            &mut stdout,
+            &files[i].filename,
+            files[i].original_filename.as_deref(),
+            &files[i].tags,
+        )?;
+    }
+
+    write_section(&mut stdout, "Not referenced by any entry:", orphans.len())?;
+    for orphan in orphans.iter() {
+        stdout.set_color(&grey())?;
+        writeln!(&mut stdout, "  {}", orphan)?;
+    }
+
+    write_section(
+        &mut stdout,
+        "Changed since they were committed:",
+        changed.len(),
+    )?;
+    for &i in changed.iter() {
+        print_file(
+            &mut stdout,
+            &files[i].filename,
+            files[i].original_filename.as_deref(),
+            &files[i].tags,
+        )?;
+    }
+
+    write_section(&mut stdout, "Without a stored hash:", unhashed.len())?;
+    for &i in unhashed.iter() {
+        print_file(
+            &mut stdout,
+            &files[i].filename,
+            files[i].original_filename.as_deref(),
+            &files[i].tags,
+        )?;
+    }
+    stdout.reset()?;
+
+    if dry {
+        return Ok(());
+    }
+
+    let mut modified = false;
+    let mut staging_modified = false;
+
+    let mut refresh: Vec<usize> = Vec::new();
+    // A changed file might be corrupted, so it takes an explicit yes to accept its new contents
+    if !changed.is_empty()
+        && Confirm::new(&format!(
+            "Record new hashes for the {} files that changed since they were committed?",
+            changed.len()
+        ))
+        .with_default(false)
+        .prompt()?
+    {
+        refresh.extend(changed.iter().copied());
+    }
+    if !unhashed.is_empty()
+        && Confirm::new(&format!(
+            "Compute the missing hashes of {} files?",
+            unhashed.len()
+        ))
+        .with_default(true)
+        .prompt()?
+    {
+        refresh.extend(unhashed.iter().copied());
+    }
+    if !refresh.is_empty() {
+        let mut refreshed = Vec::new();
+        for i in refresh {
+            let path = storage_path.join(&files[i].filename);
+            files[i].refresh_content_info(&path)?;
+            refreshed.push(files[i].clone());
+        }
+        storage.update_all(&refreshed)?;
+        modified = true;
+    }
+
+    if !orphans.is_empty()
+        && Confirm::new(&format!(
+            "Re-adopt {} unreferenced files into the registration-area?",
+            orphans.len()
+        ))
+        .with_default(true)
+        .prompt()?
+    {
+        for orphan in orphans {
+            let path = storage_path.join(&orphan);
+            let hash = if hash_added_files {
+                Some(hash_file(&path)?)
+            } else {
+                None
+            };
+            staging.registration_area.push(AddedFile {
+                path,
+                hash,
+                allow_duplicate: false,
+                tags: Vec::new(),
+                comment: HashMap::new(),
+            });
+        }
+        staging_modified = true;
+    }
+
+    if !missing.is_empty()
+        && Confirm::new(&format!(
+            "Drop {} entries whose files are missing?",
+            missing.len()
+        ))
+        .with_default(false)
+        .prompt()?
+    {
+        for &i in missing.iter() {
+            storage.delete(&files[i].filename)?;
+        }
+        modified = true;
+    }
+
+    if modified {
+        tagg.save_state()?;
+    }
+    if staging_modified {
+        tagg.save_staging()?;
+    }
+
+    Ok(())
+}
+
+fn write_section(out: &mut impl WriteColor, title: &str, count: usize) -> eyre::Result<()> {
+    if count == 0 {
+        return Ok(());
+    }
+
+    out.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))?;
+    writeln!(out, "{} ({})", title, count)?;
+    Ok(())
+}
@@ -0,0 +1,205 @@
+use std::io::Write;
+
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+use crate::{
+    journal::{Difference, EntryKind, Journal, JournalEntry},
+    storage::StorageBackend,
+    tagg::Tagg,
+};
+
+use super::{grey, light_grey, print_file, write_tags};
+
+/// Show how the information about a single file changed over time, from the journal
+pub fn history(tagg: &mut Tagg, prefix: &str) -> eyre::Result<()> {
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+
+    let journal = tagg.load_journal()?;
+    let filename = match find_filename(&mut stdout, tagg, &journal, prefix)? {
+        Some(filename) => filename,
+        None => return Ok(()),
+    };
+
+    let history = journal.history(&filename);
+    if history.is_empty() {
+        writeln!(
+            &mut stdout,
+            "There are no changes to {} in the journal",
+            filename
+        )?;
+        return Ok(());
+    }
+
+    stdout.set_color(ColorSpec::new().set_bold(true))?;
+    writeln!(&mut stdout, "History of {}", filename)?;
+    stdout.reset()?;
+
+    for entry in history {
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
+        write!(&mut stdout, "  #{:<4}", entry.id)?;
+        stdout.set_color(&grey())?;
+        write!(&mut stdout, " {}  ", entry.time)?;
+        stdout.reset()?;
+        writeln!(&mut stdout, "{}", entry.command)?;
+
+        write_entry(&mut stdout, entry, &filename)?;
+    }
+    stdout.reset()?;
+
+    Ok(())
+}
+
+/// Find the file with the prefix in storage, or in the journal if it is no longer stored
+fn find_filename(
+    out: &mut impl WriteColor,
+    tagg: &mut Tagg,
+    journal: &Journal,
+    prefix: &str,
+) -> eyre::Result<Option<String>> {
+    let stored = tagg.storage()?.find_from_prefix(prefix)?;
+    if stored.len() == 1 {
+        return Ok(stored.into_iter().next().map(|x| x.filename));
+    } else if stored.len() > 1 {
+        writeln!(
+            out,
+            "There was more than one entry which would match the prefix {:?}",
+            prefix
+        )?;
+        for file in stored {
+            print_file(
+                out,
+                &file.filename,
+                file.original_filename.as_deref(),
+                &file.tags,
+            )?;
+        }
+        return Ok(None);
+    }
+
+    let mut removed: Vec<&str> = journal
+        .entry
+        .iter()
+        .flat_map(|entry| entry.changes.iter())
+        .map(|change| change.filename.as_str())
+        .filter(|filename| filename.starts_with(prefix))
+        .collect();
+    removed.sort_unstable();
+    removed.dedup();
+    match removed.as_slice() {
+        [] => {
+            eprintln!("WARN: Failed to find file with prefix {:?}", prefix);
+            Ok(None)
+        }
+        [filename] => Ok(Some(filename.to_string())),
+        _ => {
+            writeln!(
+                out,
+                "There was more than one removed file which would match the prefix {:?}",
+                prefix
+            )?;
+            for filename in removed {
+                writeln!(out, "  {}", filename)?;
+            }
+            Ok(None)
+        }
+    }
+}
+
+fn write_entry(
+    out: &mut impl WriteColor,
+    entry: &JournalEntry,
+    filename: &str,
+) -> eyre::Result<()> {
+    match entry.kind {
+        EntryKind::Undo => {
+            out.set_color(&grey())?;
+            writeln!(out, "      undid #{}", entry.target.unwrap_or(0))?;
+            return Ok(());
+        }
+        EntryKind::Redo => {
+            out.set_color(&grey())?;
+            writeln!(out, "      redid #{}", entry.target.unwrap_or(0))?;
+            return Ok(());
+        }
+        EntryKind::Change | EntryKind::Barrier => {}
+    }
+
+    let commit = entry.commits.iter().find(|x| x.filename == filename);
+    for change in entry.changes.iter().filter(|x| x.filename == filename) {
+        match (&change.before, &change.after, commit) {
+            (None, Some(after), Some(commit)) => {
+                out.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
+                write!(out, "      committed from {:?} with ", commit.added.path)?;
+                write_tags(out, &after.tags)?;
+                continue;
+            }
+            (None, Some(after), None) => {
+                out.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
+                write!(out, "      added with ")?;
+                write_tags(out, &after.tags)?;
+                continue;
+            }
+            (Some(_), None, _) => {
+                out.set_color(ColorSpec::new().set_fg(Some(Color::Red)))?;
+                writeln!(out, "      removed")?;
+                continue;
+            }
+            (Some(_), Some(_), Some(commit)) => {
+                out.set_color(&grey())?;
+                writeln!(out, "      merged {:?} into it", commit.added.path)?;
+            }
+            _ => {}
+        }
+
+        for difference in change.differences() {
+            write_difference(out, &difference)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_difference(out: &mut impl WriteColor, difference: &Difference) -> eyre::Result<()> {
+    match difference {
+        Difference::AddedTag(tag) => {
+            out.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
+            writeln!(out, "      + {}", tag)?;
+        }
+        Difference::RemovedTag(tag) => {
+            out.set_color(ColorSpec::new().set_fg(Some(Color::Red)))?;
+            writeln!(out, "      - {}", tag)?;
+        }
+        Difference::Comment {
+            title,
+            before,
+            after,
+        } => {
+            out.set_color(&grey())?;
+            write!(out, "      {}: ", title)?;
+            out.set_color(&light_grey())?;
+            write_change(out, before.as_deref(), after.as_deref())?;
+        }
+        Difference::OriginalFilename { before, after } => {
+            out.set_color(&grey())?;
+            write!(out, "      original filename: ")?;
+            out.set_color(&light_grey())?;
+            write_change(out, before.as_deref(), after.as_deref())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_change(
+    out: &mut impl WriteColor,
+    before: Option<&str>,
+    after: Option<&str>,
+) -> eyre::Result<()> {
+    match (before, after) {
+        (Some(before), Some(after)) => writeln!(out, "{:?} -> {:?}", before, after)?,
+        (None, Some(after)) => writeln!(out, "set to {:?}", after)?,
+        (Some(before), None) => writeln!(out, "removed {:?}", before)?,
+        (None, None) => writeln!(out)?,
+    }
+    Ok(())
+}
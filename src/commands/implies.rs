@@ -0,0 +1,207 @@
+use std::io::Write;
+
+use inquire::Confirm;
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+use crate::{
+    implications::ImpliedTags,
+    namespaces,
+    query::{Expr, Query, TagPattern},
+    storage::StorageBackend,
+    tagg::Tagg,
+    ImpliesCommand,
+};
+
+use super::grey;
+
+pub(crate) fn implies(tagg: &mut Tagg, command: ImpliesCommand) -> eyre::Result<()> {
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+
+    match command {
+        ImpliesCommand::Add { tag, implied } => {
+            let mut tags = [tag, implied];
+            tagg.resolve_aliases(&mut tags)?;
+            // The implied tag gets added to files, so it has to fit its namespace like any other
+            namespaces::check_tags(&tagg.config.namespaces, &tags)?;
+            let [tag, implied] = tags;
+
+            tagg.state_mut()?.implications.add(&tag, &implied)?;
+            tagg.save_state()?;
+            writeln!(&mut stdout, "{:?} now implies {:?}", tag, implied)?;
+
+            if tagg.config.implied_tags == ImpliedTags::Stored {
+                // Files tagged before the rule existed don't have the implied tag yet
+                let query = Query {
+                    expr: Expr::And(vec![
+                        Expr::Tag(TagPattern::Exact(tag.clone())),
+                        Expr::Not(Box::new(Expr::Tag(TagPattern::Exact(implied.clone())))),
+                    ]),
+                    case_insensitive: false,
+                };
+                let missing = tagg.storage()?.find(&query)?.len();
+                if missing > 0 {
+                    eprintln!(
+                        "INFO: {} stored files have the tag {:?} but not {:?}, use `tagg implies apply` to add it",
+                        missing, tag, implied
+                    );
+                }
+            }
+        }
+        ImpliesCommand::Remove { tag, implied } => {
+            let mut tags = [tag, implied];
+            tagg.resolve_aliases(&mut tags)?;
+            let [tag, implied] = tags;
+
+            if tagg.state_mut()?.implications.remove(&tag, &implied) {
+                tagg.save_state()?;
+                writeln!(&mut stdout, "{:?} no longer implies {:?}", tag, implied)?;
+            } else {
+                eprintln!(
+                    "WARN: There is no rule that {:?} implies {:?}",
+                    tag, implied
+                );
+            }
+        }
+        ImpliesCommand::List {} => {
+            let implications = &tagg.state()?.implications;
+            if implications.is_empty() {
+                writeln!(&mut stdout, "There are no implication rules")?;
+            }
+
+            for (tag, implied) in implications.iter() {
+                stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
+                write!(&mut stdout, "  {}", tag)?;
+                stdout.set_color(&grey())?;
+                write!(&mut stdout, " => ")?;
+                stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
+                writeln!(&mut stdout, "{}", implied)?;
+            }
+            stdout.reset()?;
+        }
+        ImpliesCommand::Apply { dry } => {
+            if tagg.config.implied_tags == ImpliedTags::Virtual {
+                eprintln!(
+                    "WARN: `implied-tags` is set to `virtual`, so implied tags are normally not stored"
+                );
+            }
+
+            let implications = tagg.state()?.implications.clone();
+            let mut storage = tagg.storage()?;
+            let mut files = storage.all()?;
+            files.retain_mut(|file| !implications.apply(&mut file.tags).is_empty());
+
+            writeln!(
+                &mut stdout,
+                "Adding implied tags would change {} stored files",
+                files.len()
+            )?;
+            if dry || files.is_empty() {
+                return Ok(());
+            }
+
+            let apply = Confirm::new("Add the implied tags?")
+                .with_default(false)
+                .prompt()?;
+            if !apply {
+                return Ok(());
+            }
+
+            storage.update_all(&files)?;
+            tagg.save_state()?;
+            writeln!(
+                &mut stdout,
+                "Added implied tags to {} stored files",
+                files.len()
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        config::Config,
+        lock::{LockMode, StateLock},
+        state::{Staging, State},
+        tagg::Tagg,
+        util::TempDir,
+        ImpliesCommand,
+    };
+
+    use super::implies;
+
+    /// A tagg with the state in `dir`, and `year` as an integer namespace
+    fn tagg(dir: &TempDir, state: State) -> Tagg {
+        let state_path = dir.join("state.toml");
+        state.save_to(&state_path, 0).unwrap();
+
+        let config: Config = toml::from_str(
+            r#"
+            storage-path = "storage"
+            hash-added-files = false
+            registration-delay-limit = 3600
+            extract-pdf-titles = false
+
+            [namespaces]
+            year = "integer"
+            "#,
+        )
+        .unwrap();
+        let lock = StateLock::acquire(&state_path, LockMode::Exclusive, Duration::ZERO).unwrap();
+        Tagg::new(
+            dir.join("config.toml"),
+            state_path,
+            dir.join("staging.toml"),
+            config,
+            Staging::default(),
+            lock,
+            false,
+        )
+    }
+
+    #[test]
+    fn test_remove_alias() {
+        let dir = TempDir::new();
+        let mut state = State::parse("").unwrap();
+        state.aliases.add("feline", "cat").unwrap();
+        state.implications.add("cat", "animal").unwrap();
+        let mut tagg = tagg(&dir, state);
+
+        implies(
+            &mut tagg,
+            ImpliesCommand::Remove {
+                tag: "feline".to_string(),
+                implied: "animal".to_string(),
+            },
+        )
+        .unwrap();
+        assert!(State::load_from(&dir.join("state.toml"))
+            .unwrap()
+            .implications
+            .is_empty());
+    }
+
+    #[test]
+    fn test_add_checks_namespaces() {
+        let dir = TempDir::new();
+        let mut tagg = tagg(&dir, State::parse("").unwrap());
+
+        let add = |tagg: &mut Tagg, tag: &str, implied: &str| {
+            implies(
+                tagg,
+                ImpliesCommand::Add {
+                    tag: tag.to_string(),
+                    implied: implied.to_string(),
+                },
+            )
+        };
+        assert!(add(&mut tagg, "book", "year:abc").is_err());
+        assert!(add(&mut tagg, "year:abc", "book").is_err());
+        add(&mut tagg, "book", "year:2019").unwrap();
+        assert_eq!(tagg.state().unwrap().implications.iter().count(), 1);
+    }
+}
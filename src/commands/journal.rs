@@ -0,0 +1,326 @@
+use std::{io::Write, path::Path};
+
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+use crate::{
+    journal::{CommittedFile, EntryKind, Journal, JournalEntry, PendingEntry, TagRules},
+    state::AddedFile,
+    tagg::Tagg,
+};
+
+use super::grey;
+
+/// Undo the most recent change which hasn't been undone yet
+pub fn undo(tagg: &mut Tagg) -> eyre::Result<()> {
+    let journal = tagg.load_journal()?;
+    let (done, _) = journal.stacks();
+    let entry = match done.last().and_then(|id| journal.get(*id)) {
+        Some(entry) => entry.clone(),
+        None => {
+            println!("There is nothing to undo");
+            print_barrier(&journal);
+            return Ok(());
+        }
+    };
+    if let Some(staged) = &entry.staged {
+        check_staging(tagg, &entry, &staged.after)?;
+    }
+    if let Some(rules) = &entry.rules {
+        check_rules(tagg, &entry, &rules.after)?;
+    }
+
+    // Put the files back first, so that if that fails the catalogue still matches the storage
+    for commit in entry.commits.iter().rev() {
+        uncommit(tagg, commit)?;
+    }
+
+    let (storage, staging) = tagg.untracked_storage_and_staging()?;
+    for change in entry.changes.iter().rev() {
+        change.apply(storage, true)?;
+    }
+    for commit in entry.commits.iter() {
+        staging.registration_area.push(commit.added.clone());
+    }
+    if let Some(staged) = &entry.staged {
+        staging.registration_area = staged.before.clone();
+    }
+    if let Some(rules) = &entry.rules {
+        rules.before.restore(tagg.state_mut()?);
+    }
+
+    tagg.save_state()?;
+    if !entry.commits.is_empty() || entry.staged.is_some() {
+        tagg.save_staging()?;
+    }
+    tagg.append_journal(EntryKind::Undo, Some(entry.id), PendingEntry::default())?;
+
+    println!("Undid #{}: {}", entry.id, entry.command);
+    Ok(())
+}
+
+/// Redo the most recently undone change
+pub fn redo(tagg: &mut Tagg) -> eyre::Result<()> {
+    let journal = tagg.load_journal()?;
+    let (_, undone) = journal.stacks();
+    let entry = match undone.last().and_then(|id| journal.get(*id)) {
+        Some(entry) => entry.clone(),
+        None => {
+            println!("There is nothing to redo");
+            print_barrier(&journal);
+            return Ok(());
+        }
+    };
+    if let Some(staged) = &entry.staged {
+        check_staging(tagg, &entry, &staged.before)?;
+    }
+    if let Some(rules) = &entry.rules {
+        check_rules(tagg, &entry, &rules.before)?;
+    }
+
+    for commit in entry.commits.iter() {
+        recommit(tagg, commit)?;
+    }
+
+    let (storage, staging) = tagg.untracked_storage_and_staging()?;
+    for change in entry.changes.iter() {
+        change.apply(storage, false)?;
+    }
+    if let Some(staged) = &entry.staged {
+        staging.registration_area = staged.after.clone();
+    }
+    if let Some(rules) = &entry.rules {
+        rules.after.restore(tagg.state_mut()?);
+    }
+
+    tagg.save_state()?;
+    if !entry.commits.is_empty() || entry.staged.is_some() {
+        tagg.save_staging()?;
+    }
+    tagg.append_journal(EntryKind::Redo, Some(entry.id), PendingEntry::default())?;
+
+    println!("Redid #{}: {}", entry.id, entry.command);
+    Ok(())
+}
+
+/// Check that the registration-area is still `expected`, the way the entry left it (or found it,
+/// when redoing), so that undoing or redoing the entry doesn't throw away later changes to it
+fn check_staging(tagg: &Tagg, entry: &JournalEntry, expected: &[AddedFile]) -> eyre::Result<()> {
+    if tagg.staging.registration_area != expected {
+        return Err(eyre::eyre!(
+            "The registration-area was changed since #{} ({}), so it can't be reverted without losing those changes",
+            entry.id,
+            entry.command
+        ));
+    }
+    Ok(())
+}
+
+/// Check that the aliases and implication rules are still `expected`, like [`check_staging`]
+fn check_rules(tagg: &mut Tagg, entry: &JournalEntry, expected: &TagRules) -> eyre::Result<()> {
+    if TagRules::of(tagg.state()?) != *expected {
+        return Err(eyre::eyre!(
+            "The aliases or implication rules were changed since #{} ({}), so they can't be reverted without losing those changes",
+            entry.id,
+            entry.command
+        ));
+    }
+    Ok(())
+}
+
+/// Explain that nothing before the last barrier can be undone or redone, if there is one
+fn print_barrier(journal: &Journal) {
+    if let Some(barrier) = journal.last_barrier() {
+        println!(
+            "#{} ({}) can't be undone, so nothing before it can be undone or redone",
+            barrier.id, barrier.command
+        );
+    }
+}
+
+/// Bring a committed file back to where it was before it was committed, and remove the copy in
+/// storage.
+/// The file is restored from the trash if possible, otherwise it is copied back out of storage.
+fn uncommit(tagg: &mut Tagg, commit: &CommittedFile) -> eyre::Result<()> {
+    let original = &commit.added.path;
+    let stored = tagg.get_storage_path(&commit.filename)?;
+
+    if !original.exists() {
+        let restored = commit.trashed && restore_from_trash(original)?;
+        if restored {
+            if tagg.verbose {
+                eprintln!("INFO: Restored {:?} from the trash", original);
+            }
+        } else if stored.exists() {
+            if tagg.verbose {
+                eprintln!("INFO: Copying {:?} back to {:?}", stored, original);
+            }
+            std::fs::copy(&stored, original)?;
+        } else {
+            return Err(eyre::eyre!(
+                "Can't bring back {:?}, it is neither in the trash nor in storage as {:?}",
+                original,
+                stored
+            ));
+        }
+    }
+
+    // A merged file shares the stored copy with the file it was merged into
+    if !commit.merged && stored.exists() {
+        if tagg.verbose {
+            eprintln!("INFO: Removing {:?} from storage", stored);
+        }
+        std::fs::remove_file(&stored)?;
+    }
+
+    Ok(())
+}
+
+/// Commit a file again after its commit was undone
+fn recommit(tagg: &mut Tagg, commit: &CommittedFile) -> eyre::Result<()> {
+    let original = &commit.added.path;
+    if !original.exists() {
+        return Err(eyre::eyre!(
+            "Can't commit {:?} again since it no longer exists",
+            original
+        ));
+    }
+
+    let stored = tagg.get_storage_path(&commit.filename)?;
+    if !commit.merged && !stored.exists() {
+        if tagg.verbose {
+            eprintln!(
+                "INFO: Copying {:?} to storage destination {:?}",
+                original, stored
+            );
+        }
+        std::fs::copy(original, &stored)?;
+    }
+
+    if commit.trashed {
+        if tagg.verbose {
+            eprintln!("INFO: Moving original file to trash");
+        }
+        trash::delete(original)?;
+    }
+
+    tagg.staging
+        .registration_area
+        .retain(|added_file| added_file.path != *original);
+
+    Ok(())
+}
+
+/// Restore the most recently trashed file which was at `path`.
+/// Returns whether it was restored.
+#[cfg(any(
+    target_os = "windows",
+    all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        not(target_os = "android")
+    )
+))]
+fn restore_from_trash(path: &Path) -> eyre::Result<bool> {
+    let items = match trash::os_limited::list() {
+        Ok(items) => items,
+        Err(err) => {
+            eprintln!("WARN: Failed to look through the trash: {}", err);
+            return Ok(false);
+        }
+    };
+
+    let item = items
+        .into_iter()
+        .filter(|item| item.original_path() == path)
+        .max_by_key(|item| item.time_deleted);
+    if let Some(item) = item {
+        if let Err(err) = trash::os_limited::restore_all([item]) {
+            eprintln!("WARN: Failed to restore {:?} from the trash: {}", path, err);
+            return Ok(false);
+        }
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+/// The trash can't be looked through on this platform, so the file is always copied back out of
+/// storage instead
+#[cfg(not(any(
+    target_os = "windows",
+    all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        not(target_os = "android")
+    )
+)))]
+fn restore_from_trash(_path: &Path) -> eyre::Result<bool> {
+    Ok(false)
+}
+
+/// List the entries in the journal, most recent first
+pub fn log(tagg: &mut Tagg, limit: Option<usize>) -> eyre::Result<()> {
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+
+    let journal = tagg.load_journal()?;
+    if journal.entry.is_empty() {
+        writeln!(&mut stdout, "The journal is empty")?;
+        return Ok(());
+    }
+
+    let (_, undone) = journal.stacks();
+    let limit = limit.unwrap_or(usize::MAX);
+    for entry in journal.entry.iter().rev().take(limit) {
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
+        write!(&mut stdout, "  #{:<4}", entry.id)?;
+        stdout.set_color(&grey())?;
+        write!(&mut stdout, " {}  ", entry.time)?;
+        stdout.reset()?;
+        write!(&mut stdout, "{}", entry.command)?;
+
+        stdout.set_color(&grey())?;
+        write!(&mut stdout, "  {}", summary(entry))?;
+        if undone.contains(&entry.id) {
+            stdout.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))?;
+            write!(&mut stdout, "  (undone)")?;
+        }
+        stdout.reset()?;
+        writeln!(&mut stdout)?;
+    }
+
+    Ok(())
+}
+
+fn summary(entry: &JournalEntry) -> String {
+    match entry.kind {
+        EntryKind::Undo => format!("undid #{}", entry.target.unwrap_or(0)),
+        EntryKind::Redo => format!("redid #{}", entry.target.unwrap_or(0)),
+        EntryKind::Barrier => "can't be undone".to_string(),
+        EntryKind::Change if !entry.commits.is_empty() => {
+            format!("committed {}", files(entry.commits.len()))
+        }
+        EntryKind::Change => {
+            let mut changed = Vec::new();
+            if !entry.changes.is_empty() || (entry.staged.is_none() && entry.rules.is_none()) {
+                changed.push(files(entry.changes.len()));
+            }
+            if entry.staged.is_some() {
+                changed.push("the registration-area".to_string());
+            }
+            if entry.rules.is_some() {
+                changed.push("the aliases or implication rules".to_string());
+            }
+            format!("changed {}", changed.join(" and "))
+        }
+    }
+}
+
+fn files(count: usize) -> String {
+    if count == 1 {
+        "1 file".to_string()
+    } else {
+        format!("{} files", count)
+    }
+}
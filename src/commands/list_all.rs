@@ -1,13 +1,13 @@
 use termcolor::{ColorChoice, StandardStream};
 
-use crate::state::State;
+use crate::storage::FileInfo;
 
 use super::print_file_comments;
 
-pub fn list_all(state: &State) -> eyre::Result<()> {
+pub fn list_all(files: &[FileInfo]) -> eyre::Result<()> {
     let mut stdout = StandardStream::stdout(ColorChoice::Always);
 
-    for file in state.storage.files.iter() {
+    for file in files.iter() {
         print_file_comments(
             &mut stdout,
             &file.filename,
@@ -0,0 +1,142 @@
+use std::{collections::HashMap, io::Write};
+
+use termcolor::{ColorChoice, StandardStream};
+
+use crate::{
+    journal::{EntryKind, PendingEntry},
+    storage::{FileInfo, SqliteStorage, StorageBackend, StorageBackendKind},
+    tagg::Tagg,
+};
+
+/// Copy the catalogue from the configured storage-backend into the `to` backend, and check that
+/// every entry arrived intact.
+/// The source is only emptied if `remove_source` is set and the copy was verified.
+pub fn migrate(tagg: &mut Tagg, to: StorageBackendKind, remove_source: bool) -> eyre::Result<()> {
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+
+    let from = tagg.config.storage_backend;
+    let verbose = tagg.verbose;
+    if from == to {
+        return Err(eyre::eyre!(
+            "The storage-backend is already {}, there is nothing to migrate",
+            to.name()
+        ));
+    }
+
+    let database_path = tagg
+        .config
+        .database_path(&tagg.config_path, &tagg.state_path)?;
+    let mut database = SqliteStorage::open(&database_path)?;
+    let state = tagg.state_mut()?;
+    let (source, target): (&mut dyn StorageBackend, &mut dyn StorageBackend) = match to {
+        StorageBackendKind::Toml => (&mut database, &mut state.storage),
+        StorageBackendKind::Sqlite => (&mut state.storage, &mut database),
+    };
+
+    if target.count()? > 0 {
+        return Err(eyre::eyre!(
+            "The {} storage-backend already has files in it, refusing to mix two catalogues",
+            to.name()
+        ));
+    }
+
+    let files = source.all()?;
+    if verbose {
+        eprintln!(
+            "INFO: Copying {} files from {} to {}",
+            files.len(),
+            from.name(),
+            to.name()
+        );
+    }
+    target.insert_all(files.clone())?;
+
+    if let Err(err) = verify(&files, target) {
+        if to == StorageBackendKind::Sqlite {
+            // Don't leave a half-copied database around to be mixed with the next attempt
+            drop(database);
+            std::fs::remove_file(&database_path)?;
+        }
+        // The toml backend is only written when the state is saved, which doesn't happen here
+        return Err(err);
+    }
+
+    writeln!(
+        &mut stdout,
+        "Copied {} files from {} to {}, and verified their entries and hashes",
+        files.len(),
+        from.name(),
+        to.name()
+    )?;
+
+    if remove_source {
+        match from {
+            StorageBackendKind::Toml => state.storage.files.clear(),
+            StorageBackendKind::Sqlite => {
+                drop(database);
+                std::fs::remove_file(&database_path)?;
+            }
+        }
+        writeln!(&mut stdout, "Removed the files from {}", from.name())?;
+    }
+
+    tagg.save_state()?;
+    // The journal's entries describe the catalogue in the old backend
+    tagg.append_journal(EntryKind::Barrier, None, PendingEntry::default())?;
+
+    eprintln!(
+        "INFO: Set `storage-backend = \"{}\"` in {:?} to start using it",
+        to.name(),
+        tagg.config_path
+    );
+
+    Ok(())
+}
+
+/// Check that the target has exactly the same entries as were copied into it
+fn verify(files: &[FileInfo], target: &dyn StorageBackend) -> eyre::Result<()> {
+    let count = target.count()?;
+    if count != files.len() {
+        return Err(eyre::eyre!(
+            "Expected {} files after copying, but there were {}",
+            files.len(),
+            count
+        ));
+    }
+
+    let copied: HashMap<String, FileInfo> = target
+        .all()?
+        .into_iter()
+        .map(|x| (x.filename.clone(), x))
+        .collect();
+    let mut mismatched = 0;
+    for file in files {
+        match copied.get(&file.filename) {
+            Some(copy) if copy == file => {}
+            Some(copy) if copy.hash != file.hash => {
+                eprintln!(
+                    "WARN: {:?} has the hash {:?} after copying, rather than {:?}",
+                    file.filename, copy.hash, file.hash
+                );
+                mismatched += 1;
+            }
+            Some(_) => {
+                eprintln!("WARN: {:?} differs after copying", file.filename);
+                mismatched += 1;
+            }
+            None => {
+                eprintln!("WARN: {:?} is missing after copying", file.filename);
+                mismatched += 1;
+            }
+        }
+    }
+
+    if mismatched > 0 {
+        return Err(eyre::eyre!(
+            "{} files did not copy correctly, so the migration was undone",
+            mismatched
+        ));
+    }
+
+    Ok(())
+}
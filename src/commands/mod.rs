@@ -4,16 +4,33 @@ use std::{borrow::Cow, collections::HashMap, path::PathBuf};
 use inquire::Confirm;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
-use crate::storage::Storage;
-use crate::util::{expand_path, extract_title};
+use crate::commands::dupes::{resolve_duplicate, DuplicateAction};
+use crate::commands::tags::TagEdit;
+use crate::journal::CommittedFile;
+use crate::namespaces;
+use crate::query::Query;
+use crate::storage::{Storage, StorageBackend};
+use crate::util::{expand_path, extract_title, hash_file};
+use crate::vfs;
 use crate::{
     state::{AddedFile, COMMENT_MAIN},
     storage::FileInfo,
     tagg::Tagg,
-    Commands,
+    Commands, TagCommand,
 };
 
+pub mod alias;
+pub mod dupes;
+pub mod fsck;
+pub mod history;
+pub mod implies;
+pub mod journal;
 pub mod list_all;
+pub mod migrate;
+pub mod state;
+pub mod tag_session;
+pub mod tags;
+pub mod view;
 
 pub(crate) fn grey() -> ColorSpec {
     let mut spec = ColorSpec::new();
@@ -42,7 +59,7 @@ pub(crate) fn dispatch(tagg: &mut Tagg, command: Commands) -> eyre::Result<()> {
             stdout.set_color(ColorSpec::new().set_fg(None))?;
             writeln!(&mut stdout, "` to remove it from the registration-area)")?;
 
-            for added_file in tagg.state.registration_area.iter() {
+            for added_file in tagg.staging.registration_area.iter() {
                 let name = added_file
                     .path
                     .file_name()
@@ -55,12 +72,44 @@ pub(crate) fn dispatch(tagg: &mut Tagg, command: Commands) -> eyre::Result<()> {
             }
 
             stdout.set_color(ColorSpec::new().set_fg(None))?;
+
+            if !tagg.staging.expired.is_empty() {
+                writeln!(
+                    &mut stdout,
+                    "Files that expired out of the Registration-Area:"
+                )?;
+
+                write!(&mut stdout, "  (use `")?;
+                stdout.set_color(&grey())?;
+                write!(&mut stdout, "tagg restore-staged [file]")?;
+                stdout.set_color(ColorSpec::new().set_fg(None))?;
+                writeln!(&mut stdout, "` to bring them back)")?;
+
+                for added_file in tagg.staging.expired.iter() {
+                    let name = added_file
+                        .path
+                        .file_name()
+                        .unwrap_or(added_file.path.as_os_str())
+                        .to_string_lossy();
+                    stdout.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))?;
+                    write!(&mut stdout, "    {}  ", name)?;
+
+                    write_tags(&mut stdout, &added_file.tags)?;
+                }
+
+                stdout.set_color(ColorSpec::new().set_fg(None))?;
+            }
         }
         Commands::Add {
             files,
             comment,
             tags,
+            new_tag,
         } => {
+            let mut tags = tags;
+            tagg.resolve_aliases(&mut tags)?;
+            namespaces::check_tags(&tagg.config.namespaces, &tags)?;
+            tags::check_vocabulary(tagg, &tags, new_tag)?;
             println!("Adding files {:?} with tags {:?}", files, tags);
 
             'outer: for file in files {
@@ -91,14 +140,29 @@ pub(crate) fn dispatch(tagg: &mut Tagg, command: Commands) -> eyre::Result<()> {
                     continue;
                 }
 
-                // TODO: Check hash?
-                for added_file in tagg.state.registration_area.iter_mut() {
+                let hash = if tagg.config.hash_added_files {
+                    Some(hash_file(&path)?)
+                } else {
+                    None
+                };
+
+                for added_file in tagg.staging.registration_area.iter_mut() {
                     if path == added_file.path {
                         if tagg.verbose {
                             eprintln!("INFO: {:?} already existed in the registration area.", path);
                         }
 
-                        added_file.tags.extend(tags.clone().into_iter());
+                        if hash.is_some() && added_file.hash != hash {
+                            if added_file.hash.is_some() {
+                                eprintln!(
+                                    "WARN: {:?} has changed since it was added, updating its stored hash",
+                                    path
+                                );
+                            }
+                            added_file.hash = hash.clone();
+                        }
+
+                        added_file.tags.extend(tags.clone());
                         let tag_count = added_file.tags.len();
 
                         // We have to sort so that dedup can work
@@ -145,8 +209,6 @@ pub(crate) fn dispatch(tagg: &mut Tagg, command: Commands) -> eyre::Result<()> {
 
                 // If we're down here then it didn't already exist.
 
-                // TODO: Get hash of file and store it
-                let hash = None;
                 let comment = {
                     let mut comments = HashMap::new();
                     if let Some(comment) = comment.clone() {
@@ -156,23 +218,41 @@ pub(crate) fn dispatch(tagg: &mut Tagg, command: Commands) -> eyre::Result<()> {
                     comments
                 };
 
+                let mut allow_duplicate = false;
+                if let Some(hash) = hash.as_deref() {
+                    match resolve_duplicate(
+                        &mut tagg.storage()?,
+                        &path,
+                        hash,
+                        &tags,
+                        &comment,
+                        false,
+                    )? {
+                        DuplicateAction::Merge | DuplicateAction::Skip => continue,
+                        DuplicateAction::Store => allow_duplicate = true,
+                    }
+                }
+
                 let added_file = AddedFile {
                     path,
                     hash,
+                    allow_duplicate,
                     comment,
                     tags: tags.clone(),
                 };
 
-                tagg.state.registration_area.push(added_file);
+                tagg.staging.registration_area.push(added_file);
             }
 
+            // Only written if a duplicate was merged into the storage
             tagg.save_state()?;
+            tagg.save_staging()?;
         }
         Commands::Drop { files } => {
             for file in files {
                 let file = Some(Cow::Owned(file));
                 let mut found = false;
-                tagg.state.registration_area.retain(|added_file| {
+                tagg.staging.registration_area.retain(|added_file| {
                     if file == added_file.path.file_name().map(|x| x.to_string_lossy()) {
                         found = true;
                         false
@@ -185,48 +265,82 @@ pub(crate) fn dispatch(tagg: &mut Tagg, command: Commands) -> eyre::Result<()> {
                 }
             }
 
-            tagg.save_state()?;
+            tagg.save_staging()?;
+        }
+        Commands::RestoreStaged { files, discard } => {
+            let staging = &mut tagg.staging;
+            let chosen: Vec<AddedFile> = if files.is_empty() {
+                std::mem::take(&mut staging.expired)
+            } else {
+                let mut chosen = Vec::new();
+                for file in files {
+                    let file = Some(Cow::Owned(file));
+                    let position = staging
+                        .expired
+                        .iter()
+                        .position(|x| file == x.path.file_name().map(|x| x.to_string_lossy()));
+                    if let Some(position) = position {
+                        chosen.push(staging.expired.remove(position));
+                    } else {
+                        eprintln!("Failed to find {:?} in the expired files", file);
+                    }
+                }
+                chosen
+            };
+
+            for added_file in chosen {
+                if discard {
+                    println!("Discarded {:?}", added_file.path);
+                } else if staging
+                    .registration_area
+                    .iter()
+                    .any(|x| x.path == added_file.path)
+                {
+                    eprintln!(
+                        "WARN: {:?} was added to the registration-area again since it expired, so the expired entry was dropped",
+                        added_file.path
+                    );
+                } else {
+                    println!("Restored {:?}", added_file.path);
+                    staging.registration_area.push(added_file);
+                }
+            }
+
+            tagg.save_staging()?;
         }
         Commands::Commit { dry, soft } => {
-            if tagg.state.registration_area.is_empty() {
+            if tagg.staging.registration_area.is_empty() {
                 eprintln!("There was no files in the registration area to commit.");
                 return Ok(());
             }
 
-            while let Some(added_file) = tagg.state.registration_area.pop() {
-                commit_file(tagg, added_file, dry, soft)?;
+            // Go backwards so that committed files being removed doesn't shift the files we have yet to visit
+            for index in (0..tagg.staging.registration_area.len()).rev() {
+                commit_file(tagg, index, dry, soft)?;
             }
         }
-        Commands::AddTags { tags, files } => {
-            for file in files {
-                if let Some(file) =
-                    get_single_file_mut_from_prefix(&mut stdout, &mut tagg.state.storage, &file)?
-                {
-                    file.tags.extend(tags.iter().cloned());
-                    let tag_count_after = file.tags.len();
-
-                    file.tags.sort();
-                    file.tags.dedup();
-
-                    if file.tags.len() < tag_count_after {
-                        let removed = tag_count_after - file.tags.len();
-                        eprintln!(
-                            "INFO: {:?} has #{} new tags ignored due to being duplicates",
-                            file.filename, removed
-                        );
-                    }
-
-                    // TODO: inform on no tag change
-                    print_file(
-                        &mut stdout,
-                        &file.filename,
-                        file.original_filename.as_deref(),
-                        &file.tags,
-                    )?;
-                }
-            }
-
-            tagg.save_state()?;
+        Commands::AddTags {
+            files,
+            tags,
+            staged,
+            new_tag,
+        } => {
+            tags::edit_tags(tagg, files, tags, TagEdit::Add, staged, new_tag)?;
+        }
+        Commands::RemoveTags {
+            files,
+            tags,
+            staged,
+        } => {
+            tags::edit_tags(tagg, files, tags, TagEdit::Remove, staged, false)?;
+        }
+        Commands::SetTags {
+            files,
+            tags,
+            staged,
+            new_tag,
+        } => {
+            tags::edit_tags(tagg, files, tags, TagEdit::Set, staged, new_tag)?;
         }
         Commands::SetComment {
             files,
@@ -234,11 +348,11 @@ pub(crate) fn dispatch(tagg: &mut Tagg, command: Commands) -> eyre::Result<()> {
             title,
         } => {
             let title = title.unwrap_or_else(|| COMMENT_MAIN.to_string());
+            let mut storage = tagg.storage()?;
             for file in files {
-                if let Some(file) =
-                    get_single_file_mut_from_prefix(&mut stdout, &mut tagg.state.storage, &file)?
-                {
+                if let Some(mut file) = get_single_file_from_prefix(&mut stdout, &storage, &file)? {
                     file.comments.insert(title.clone(), message.clone());
+                    storage.update(&file)?;
                 }
             }
 
@@ -246,21 +360,25 @@ pub(crate) fn dispatch(tagg: &mut Tagg, command: Commands) -> eyre::Result<()> {
         }
         Commands::SetTitle { file, message } => {
             let title = "title".to_string();
-            if let Some(file) =
-                get_single_file_mut_from_prefix(&mut stdout, &mut tagg.state.storage, &file)?
-            {
+            let mut storage = tagg.storage()?;
+            if let Some(mut file) = get_single_file_from_prefix(&mut stdout, &storage, &file)? {
                 file.comments.insert(title, message);
+                storage.update(&file)?;
             }
 
             tagg.save_state()?;
         }
         Commands::GenerateTitles { dry } => {
-            for file in tagg.state.storage.files.iter_mut() {
+            let storage_path = expand_path(&tagg.config.storage_path);
+            let verbose = tagg.verbose;
+            let mut storage = tagg.storage()?;
+            let mut updated = Vec::new();
+            for mut file in storage.all()? {
                 if file.comments.contains_key("title") {
                     continue;
                 }
 
-                let mut path = expand_path(&tagg.config.storage_path);
+                let mut path = storage_path.clone();
                 path.push(&file.filename);
                 let extension = path
                     .extension()
@@ -268,90 +386,222 @@ pub(crate) fn dispatch(tagg: &mut Tagg, command: Commands) -> eyre::Result<()> {
                     .unwrap_or(Cow::Borrowed(""));
 
                 if let Some(title) = extract_title(&path, &extension) {
-                    if tagg.verbose {
+                    if verbose {
                         eprintln!("Set {}'s title to {:?}", file.filename, title);
                     }
                     file.comments.insert("title".to_string(), title);
-                } else if tagg.verbose {
+                    updated.push(file);
+                } else if verbose {
                     eprintln!("Failed to get title for {:?}", path);
                 }
             }
 
             if !dry {
+                storage.update_all(&updated)?;
                 tagg.save_state()?;
             }
         }
         Commands::SetDesc { file, message } => {
             let title = "desc".to_string();
-            if let Some(file) =
-                get_single_file_mut_from_prefix(&mut stdout, &mut tagg.state.storage, &file)?
-            {
+            let mut storage = tagg.storage()?;
+            if let Some(mut file) = get_single_file_from_prefix(&mut stdout, &storage, &file)? {
                 file.comments.insert(title, message);
+                storage.update(&file)?;
             }
 
             tagg.save_state()?;
         }
-        Commands::ListAll {} => {
-            list_all::list_all(&tagg.state)?;
-        }
-        Commands::Find { tags, case_insensitive } => {
-            'outer: for file in tagg.state.storage.files.iter() {
-                for tag in tags.iter() {
-                    if let Some(tag) = tag.strip_prefix('-') {
-                        if case_insensitive { // This if-else block seems redundant(can't figure out yet how exactly find works with all the clauses...)
-                            if file.tags.iter().any(|x| x.as_str().eq_ignore_ascii_case(tag)) {
-                                // We found a filtered out tag
-                                continue 'outer;
-                            }
-                        }
-                        else {
-                            if file.tags.iter().any(|x| x.as_str() == tag) {
-                            // We found a filtered out tag
-                            continue 'outer;
-                            }
-                        }
-                    } else {
-                        let tag = if let Some(tag) = tag.strip_prefix('+') {
-                            tag
-                        } else {
-                            tag.as_str()
-                        };
-                        if case_insensitive {
-                            if !file.tags.iter().any(|x| x.as_str().eq_ignore_ascii_case(tag)) {
-                                // We didn't find the tag
-                                continue 'outer;
-                            }
-                        }
-                        else {
-                            if !file.tags.iter().any(|x| x.as_str() == tag) {
-                            // We found a filtered out tag
-                            continue 'outer;
-                            }
-                        }
+        Commands::Fsck { full, dry } => {
+            fsck::fsck(tagg, full, dry)?;
+        }
+        Commands::Dupes {} => {
+            dupes::dupes(&Storage::from_backend(&tagg.storage()?)?)?;
+        }
+        Commands::Similar { file, distance } => {
+            let distance = distance.unwrap_or(tagg.config.similar_distance);
+            let storage_path = tagg.config.storage_path(&tagg.config_path)?;
+            let mut storage = tagg.storage()?;
+            let (filename, hash) = if let Some(mut file) =
+                get_single_file_from_prefix(&mut stdout, &storage, &file)?
+            {
+                if !file.is_image() {
+                    eprintln!("{:?} is not an image", file.filename);
+                    return Ok(());
+                }
+
+                if file.perceptual_hash.is_none() {
+                    // Files committed before perceptual hashes existed won't have one
+                    file.refresh_perceptual_hash(&storage_path.join(&file.filename));
+                    if file.perceptual_hash.is_some() {
+                        storage.update(&file)?;
                     }
                 }
 
+                if let Some(hash) = file.perceptual_hash() {
+                    (file.filename.clone(), hash)
+                } else {
+                    return Ok(());
+                }
+            } else {
+                return Ok(());
+            };
+
+            let storage = Storage::from_backend(&storage)?;
+            tagg.save_state()?;
+
+            for (file_distance, similar) in storage.find_similar(hash, distance) {
+                if similar.filename == filename {
+                    continue;
+                }
+
+                stdout.set_color(&grey())?;
+                write!(&mut stdout, "  {:>2}", file_distance)?;
+                print_file(
+                    &mut stdout,
+                    &similar.filename,
+                    similar.original_filename.as_deref(),
+                    &similar.tags,
+                )?;
+            }
+        }
+        Commands::ListAll {} => {
+            list_all::list_all(&tagg.storage()?.all()?)?;
+        }
+        Commands::Find {
+            query,
+            case_insensitive,
+        } => {
+            let text = query.join(" ");
+            let query = match Query::parse_args(&query) {
+                Ok(query) => tagg
+                    .query_context()?
+                    .prepare(query.with_case_insensitive(case_insensitive))?,
+                Err(err) => {
+                    eprintln!("Failed to parse query:");
+                    eprintln!("{}", err.display_with_source(&text));
+                    return Err(err.into());
+                }
+            };
+
+            for file in tagg.storage()?.find(&query)? {
                 print_file_comments_colored(
                     &mut stdout,
                     &file.filename,
                     file.original_filename.as_deref(),
                     &file.tags,
                     &file.comments,
-                    &tags,
-                    &case_insensitive,
+                    &query,
                 )?;
             }
         }
+        Commands::Ls { path } => {
+            let context = tagg.query_context()?;
+            let storage = Storage::from_backend(&tagg.storage()?)?;
+            match vfs::list(&storage, &path, &context) {
+                Ok(entries) => {
+                    for entry in entries {
+                        match entry {
+                            vfs::Entry::Dir(name) => {
+                                stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
+                                writeln!(&mut stdout, "{}/", name)?;
+                            }
+                            vfs::Entry::File { name, .. } => {
+                                stdout.reset()?;
+                                writeln!(&mut stdout, "{}", name)?;
+                            }
+                        }
+                    }
+                    stdout.reset()?;
+                }
+                Err(vfs::VfsError::NotFound) => {
+                    let file = vfs::resolve(&storage, &path, &context)?;
+                    writeln!(
+                        &mut stdout,
+                        "{}",
+                        tagg.get_storage_path(&file.filename)?.display()
+                    )?;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        #[cfg(all(feature = "mount", target_os = "linux"))]
+        Commands::Mount { dir } => {
+            crate::mount::mount(tagg, &dir)?;
+        }
+        #[cfg(not(all(feature = "mount", target_os = "linux")))]
+        Commands::Mount { .. } => {
+            return Err(eyre::eyre!(
+                "This tagg was built without mounting, which needs the `mount` feature and Linux"
+            ));
+        }
+        Commands::View { command } => {
+            view::view(tagg, command)?;
+        }
+        Commands::State { command } => {
+            state::state(tagg, command)?;
+        }
+        Commands::Migrate { to, remove_source } => {
+            migrate::migrate(tagg, to, remove_source)?;
+        }
+        Commands::Undo {} => {
+            journal::undo(tagg)?;
+        }
+        Commands::Redo {} => {
+            journal::redo(tagg)?;
+        }
+        Commands::Log { limit } => {
+            journal::log(tagg, limit)?;
+        }
+        Commands::History { file } => {
+            history::history(tagg, &file)?;
+        }
+        Commands::Tags {
+            sort,
+            prefix,
+            namespace,
+            related,
+            tree,
+        } => {
+            tags::list_tags(tagg, sort, prefix, namespace, related, tree)?;
+        }
+        Commands::Alias { command } => {
+            alias::alias(tagg, command)?;
+        }
+        Commands::TagSession {} => {
+            tag_session::tag_session(tagg)?;
+        }
+        Commands::Implies { command } => {
+            implies::implies(tagg, command)?;
+        }
+        Commands::Tag { command } => match command {
+            TagCommand::Rename {
+                old,
+                new,
+                descendants,
+                dry,
+                yes,
+            } => {
+                tags::rewrite_tags(tagg, vec![old], new, descendants, dry, yes)?;
+            }
+            TagCommand::Merge {
+                tags,
+                into,
+                descendants,
+                dry,
+                yes,
+            } => {
+                tags::rewrite_tags(tagg, tags, into, descendants, dry, yes)?;
+            }
+        },
         // TODO: Command to open based on tags?
         // TODO: Command to open based on old filename?
         // TODO: Way of displaying clickable links to the user in search/list-all that will automatically xdg-open them?
         Commands::Open { files, using } => {
             for file in files {
                 if let Some(file) =
-                    get_single_file_mut_from_prefix(&mut stdout, &mut tagg.state.storage, &file)?
+                    get_single_file_from_prefix(&mut stdout, &tagg.storage()?, &file)?
                 {
-                    let filename = file.filename.clone();
-                    let path = tagg.get_storage_path(&filename)?;
+                    let path = tagg.get_storage_path(&file.filename)?;
                     if let Some(using) = using.as_deref() {
                         open::with(&path, using)?;
                     } else {
@@ -366,19 +616,17 @@ pub(crate) fn dispatch(tagg: &mut Tagg, command: Commands) -> eyre::Result<()> {
     Ok(())
 }
 
-pub(crate) fn get_single_file_mut_from_prefix<'a, 'b: 'a>(
+pub(crate) fn get_single_file_from_prefix(
     out: &mut impl WriteColor,
-    storage: &'a mut Storage,
-    file: &'b str,
-) -> eyre::Result<Option<&'a mut FileInfo>> {
-    let mut files = storage.find_file_mut_from_prefix(file);
-    let first = files.next();
-    let second = files.next();
-    if first.is_none() {
+    storage: &dyn StorageBackend,
+    file: &str,
+) -> eyre::Result<Option<FileInfo>> {
+    let mut files = storage.find_from_prefix(file)?;
+    if files.is_empty() {
         eprintln!("WARN: Failed to find file with prefix {:?}", file);
         Ok(None)
-    } else if second.is_none() {
-        Ok(first)
+    } else if files.len() == 1 {
+        Ok(files.pop())
     } else {
         writeln!(
             out,
@@ -471,35 +719,23 @@ pub(crate) fn write_tags<T: AsRef<str>>(out: &mut impl WriteColor, tags: &[T]) -
     Ok(())
 }
 
-pub(crate) fn write_matched_tags<T: AsRef<str>, U: AsRef<str>>(
+pub(crate) fn write_matched_tags<T: AsRef<str>>(
     out: &mut impl WriteColor,
     tags: &[T],
-    matched_tags: &[U],
-    case_insensitive: &bool,
+    query: &Query,
 ) -> eyre::Result<()> {
     out.set_color(&grey())?;
     write!(out, "[")?;
 
     for (i, tag) in tags.iter().enumerate() {
         let tag = tag.as_ref();
-        // add a clause here to color the specific taggs used with `$ tagg find tag1 tag2 ...`
-        if !case_insensitive {
-            if matched_tags.iter().any(|x| x.as_ref() == tag) {
-                out.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
-                write!(out, "{}", tag)?;
-            } else {
-                out.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
-                write!(out, "{}", tag)?;
-            }
+        // Color the tags that the query was looking for
+        if query.matches_tag(tag) {
+            out.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
         } else {
-            if matched_tags.iter().any(|x| x.as_ref().eq_ignore_ascii_case(tag)) {
-                out.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
-                write!(out, "{}", tag)?;
-            } else {
-                out.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
-                write!(out, "{}", tag)?;
-            }
+            out.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
         }
+        write!(out, "{}", tag)?;
 
         if i + 1 < tags.len() {
             out.set_color(ColorSpec::new().set_fg(None))?;
@@ -512,14 +748,13 @@ pub(crate) fn write_matched_tags<T: AsRef<str>, U: AsRef<str>>(
     Ok(())
 }
 
-pub(crate) fn print_file_comments_colored<T: AsRef<str>, U: AsRef<str>>(
+pub(crate) fn print_file_comments_colored<T: AsRef<str>>(
     out: &mut impl WriteColor,
     filename: &str,
     original_filename: Option<&str>,
     tags: &[T],
     comments: &HashMap<String, String>,
-    matched_tags: &[U],
-    case_insensitive: &bool,
+    query: &Query,
 ) -> eyre::Result<()> {
     out.set_color(&grey())?;
     write!(out, "  {} ", filename)?;
@@ -531,7 +766,7 @@ pub(crate) fn print_file_comments_colored<T: AsRef<str>, U: AsRef<str>>(
         write!(out, ") ")?;
     }
 
-    write_matched_tags(out, tags, matched_tags, case_insensitive)?;
+    write_matched_tags(out, tags, query)?;
 
     for (title, comment) in comments.iter() {
         write!(out, "    - ")?;
@@ -548,8 +783,90 @@ pub(crate) fn print_file_comments_colored<T: AsRef<str>, U: AsRef<str>>(
     Ok(())
 }
 
-fn commit_file(tagg: &mut Tagg, added_file: AddedFile, dry: bool, soft: bool) -> eyre::Result<()> {
-    // TODO: check hash
+/// Commit the file at `index` in the registration-area.  
+/// If the file no longer exists, or it has changed since it was added and the user doesn't
+/// confirm, then it is left in the registration-area.
+fn commit_file(tagg: &mut Tagg, index: usize, dry: bool, soft: bool) -> eyre::Result<()> {
+    let implications = tagg.stored_implications()?;
+    let (mut storage, staging) = tagg.storage_and_staging()?;
+    let added_file = &staging.registration_area[index];
+    let mut tags = added_file.tags.clone();
+    if let Some(implications) = &implications {
+        implications.apply(&mut tags);
+    }
+    let hash = match added_file.exists_hash_correct()? {
+        Some(true) => added_file.hash.clone(),
+        Some(false) => {
+            eprintln!(
+                "WARN: {:?} has changed since it was added to the registration-area",
+                added_file.path
+            );
+            let commit = Confirm::new("Do you want to commit the changed file anyway?")
+                .with_default(false)
+                .prompt()?;
+            if !commit {
+                eprintln!("INFO: Left {:?} in the registration-area", added_file.path);
+                return Ok(());
+            }
+            None
+        }
+        None => {
+            eprintln!(
+                "WARN: Skipped {:?} because it no longer exists, use `tagg drop` to remove it from the registration-area",
+                added_file.path
+            );
+            return Ok(());
+        }
+    };
+    let hash = if let Some(hash) = hash {
+        hash
+    } else {
+        hash_file(&added_file.path)?
+    };
+
+    if !added_file.allow_duplicate {
+        let action = resolve_duplicate(
+            &mut storage,
+            &added_file.path,
+            &hash,
+            &tags,
+            &added_file.comment,
+            dry,
+        )?;
+        match action {
+            DuplicateAction::Merge => {
+                let added_file = tagg.staging.registration_area.remove(index);
+                if !dry {
+                    if !soft {
+                        if tagg.verbose {
+                            eprintln!("INFO: Moving original file to trash");
+                        }
+                        trash::delete(&added_file.path)?;
+                    }
+                    if let Some(existing) = tagg.storage()?.find_by_hash(&hash)?.into_iter().next()
+                    {
+                        tagg.record_commit(CommittedFile {
+                            filename: existing.filename,
+                            trashed: !soft,
+                            merged: true,
+                            added: added_file,
+                        });
+                    }
+                    tagg.save_state()?;
+                    tagg.save_staging()?;
+                }
+                return Ok(());
+            }
+            DuplicateAction::Skip => {
+                eprintln!("INFO: Left {:?} in the registration-area", added_file.path);
+                return Ok(());
+            }
+            DuplicateAction::Store => {}
+        }
+    }
+
+    let added_file = tagg.staging.registration_area.remove(index);
+    let committed = added_file.clone();
 
     let original_filename = added_file
         .path
@@ -581,6 +898,17 @@ fn commit_file(tagg: &mut Tagg, added_file: AddedFile, dry: bool, soft: bool) ->
     // Ensure that the destination doesn't exist, just in case
     assert!(!dest_path.exists(), "Unique name for file already existed! This may be a one-in-a-bazillion occurrence, but it is probably a bug. If this happens repeatedly, then it's a bug.");
 
+    let mut file_info = FileInfo {
+        filename,
+        original_filename,
+        hash: Some(hash),
+        size: None,
+        modified: None,
+        perceptual_hash: None,
+        comments,
+        tags,
+    };
+
     if !dry {
         if tagg.verbose {
             eprintln!(
@@ -589,7 +917,9 @@ fn commit_file(tagg: &mut Tagg, added_file: AddedFile, dry: bool, soft: bool) ->
             );
         }
         // We can just error on failure because we're adding the files one-at-a-time and then saving
-        std::fs::copy(&added_file.path, dest_path)?;
+        std::fs::copy(&added_file.path, &dest_path)?;
+        file_info.refresh_metadata(&dest_path)?;
+        file_info.refresh_perceptual_hash(&dest_path);
 
         if !soft {
             if tagg.verbose {
@@ -597,22 +927,25 @@ fn commit_file(tagg: &mut Tagg, added_file: AddedFile, dry: bool, soft: bool) ->
             }
             trash::delete(&added_file.path)?;
         }
-    } else if tagg.verbose {
-        eprintln!("INFO: Dry run commit, thus did not copy file or remove it");
+    } else {
+        file_info.refresh_metadata(&added_file.path)?;
+        file_info.refresh_perceptual_hash(&added_file.path);
+        if tagg.verbose {
+            eprintln!("INFO: Dry run commit, thus did not copy file or remove it");
+        }
     }
 
-    let file_info = FileInfo {
-        filename,
-        original_filename,
-        comments,
-        tags: added_file.tags,
-    };
-
-    tagg.state.storage.files.push(file_info);
-
     if !dry {
+        tagg.record_commit(CommittedFile {
+            filename: file_info.filename.clone(),
+            trashed: !soft,
+            merged: false,
+            added: committed,
+        });
+        tagg.storage()?.insert(file_info)?;
         // We save the state after each entry to avoid wacky duplicates
         tagg.save_state()?;
+        tagg.save_staging()?;
     } else if tagg.verbose {
         eprintln!("INFO: Dry run commit, thus did not save state");
     }
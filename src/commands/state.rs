@@ -0,0 +1,88 @@
+use std::{io::Write, time::UNIX_EPOCH};
+
+use inquire::Confirm;
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+use crate::{
+    journal::{EntryKind, PendingEntry},
+    state::{backup_path, rotate_backups, State},
+    tagg::Tagg,
+    util::{expand_path, unix_to_datetime, write_atomic},
+    StateCommand,
+};
+
+use super::grey;
+
+pub(crate) fn state(tagg: &mut Tagg, command: StateCommand) -> eyre::Result<()> {
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+    let backups = tagg.config.state_backups;
+
+    match command {
+        StateCommand::Backups {} => {
+            let mut found = false;
+            for n in 1..=backups {
+                let path = backup_path(&tagg.state_path, n);
+                let modified = if let Ok(meta) = std::fs::metadata(&path) {
+                    meta.modified()
+                        .ok()
+                        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                        .map(|x| unix_to_datetime(x.as_secs()).to_string())
+                        .unwrap_or_default()
+                } else {
+                    continue;
+                };
+                found = true;
+
+                stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
+                write!(&mut stdout, "  {:>2}", n)?;
+                stdout.set_color(&grey())?;
+                writeln!(&mut stdout, "  {}  {}", modified, path.display())?;
+            }
+            stdout.reset()?;
+
+            if !found {
+                writeln!(&mut stdout, "There are no backups of the state file.")?;
+            }
+        }
+        StateCommand::Restore { backup } => {
+            let path = match backup.parse::<usize>() {
+                Ok(n) => backup_path(&tagg.state_path, n),
+                Err(_) => expand_path(&backup),
+            };
+            if !path.exists() {
+                return Err(eyre::eyre!("There is no backup at {:?}", path));
+            }
+
+            let data = std::fs::read_to_string(&path)?;
+            // Make sure that it is actually a usable state file before replacing the current one
+            let restored = State::parse(&data)?;
+
+            writeln!(
+                &mut stdout,
+                "{:?} has {} files and {} views",
+                path,
+                restored.storage.files.len(),
+                restored.views.len()
+            )?;
+            let restore = Confirm::new("Replace the current state file with it?")
+                .with_default(false)
+                .prompt()?;
+            if !restore {
+                return Ok(());
+            }
+
+            // The contents were already read, so it doesn't matter that this moves the backup
+            rotate_backups(&tagg.state_path, backups.max(1))?;
+            write_atomic(&tagg.state_path, data.as_bytes())?;
+            // The entries since the backup was made describe changes it doesn't have
+            tagg.append_journal(EntryKind::Barrier, None, PendingEntry::default())?;
+            writeln!(
+                &mut stdout,
+                "Restored the state file from {:?}, the previous state file is now backup 1",
+                path
+            )?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use std::io::Write;
+
+use inquire::{
+    autocompletion::Replacement, Autocomplete, Confirm, CustomUserError, InquireError, Text,
+};
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+use crate::{
+    hierarchy::tag_and_ancestors,
+    namespaces,
+    state::COMMENT_MAIN,
+    storage::StorageBackend,
+    tagg::Tagg,
+    util::{extract_title, format_size},
+};
+
+use super::{
+    grey,
+    tags::{check_vocabulary, write_tag_changes, TagEdit},
+};
+
+/// Go through the files in the registration-area one at a time, asking for their tags, comment,
+/// title and description.
+/// The registration-area is saved after each file, so stopping part way keeps what was entered.
+pub fn tag_session(tagg: &mut Tagg) -> eyre::Result<()> {
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+
+    let count = tagg.staging.registration_area.len();
+    if count == 0 {
+        writeln!(&mut stdout, "There are no files in the registration-area")?;
+        return Ok(());
+    }
+
+    let (storage, staging) = tagg.storage_and_staging()?;
+    let mut known: Vec<String> = storage
+        .all()?
+        .iter()
+        .map(|file| &file.tags)
+        .chain(staging.registration_area.iter().map(|file| &file.tags))
+        .flatten()
+        .flat_map(|tag| tag_and_ancestors(tag))
+        .map(str::to_string)
+        .collect();
+    known.sort();
+    known.dedup();
+
+    for index in 0..count {
+        let added_file = &tagg.staging.registration_area[index];
+        let path = added_file.path.clone();
+        let name = path
+            .file_name()
+            .unwrap_or(path.as_os_str())
+            .to_string_lossy()
+            .into_owned();
+        let extension = path
+            .extension()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        writeln!(&mut stdout)?;
+        stdout.set_color(&grey())?;
+        write!(&mut stdout, "[{}/{}] ", index + 1, count)?;
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
+        write!(&mut stdout, "{}", name)?;
+        stdout.set_color(&grey())?;
+        match std::fs::metadata(&path) {
+            Ok(meta) => writeln!(&mut stdout, " ({})", format_size(meta.len()))?,
+            Err(_) => writeln!(&mut stdout, " (no longer exists)")?,
+        }
+
+        let extracted_title = if added_file.comment.contains_key("title") {
+            None
+        } else {
+            extract_title(&path, &extension)
+        };
+        if let Some(title) = added_file.comment.get("title").or(extracted_title.as_ref()) {
+            writeln!(&mut stdout, "  title: {}", title)?;
+        }
+        stdout.reset()?;
+
+        let preview = Confirm::new("Open the file to preview it?")
+            .with_default(false)
+            .prompt()?;
+        if preview {
+            open::that(&path)?;
+        }
+
+        let mut initial = added_file.tags.join(" ");
+        let tags = loop {
+            let Some(text) = Text::new("Tags:")
+                .with_initial_value(&initial)
+                .with_autocomplete(TagCompleter {
+                    tags: known.clone(),
+                })
+                .with_help_message("space separated, tab to complete, esc to skip this file")
+                .prompt_skippable()?
+            else {
+                break None;
+            };
+            let mut tags: Vec<String> = text.split_whitespace().map(str::to_string).collect();
+            tagg.resolve_aliases(&mut tags)?;
+
+            // Tags that don't fit are asked for again, rather than ending the session
+            let checked = namespaces::check_tags(&tagg.config.namespaces, &tags)
+                .and_then(|()| check_vocabulary(tagg, &tags, false));
+            match checked {
+                Ok(()) => break Some(tags),
+                Err(err) if err.downcast_ref::<InquireError>().is_some() => return Err(err),
+                Err(err) => {
+                    eprintln!("WARN: {}", err);
+                    initial = text;
+                }
+            }
+        };
+        let Some(tags) = tags else {
+            continue;
+        };
+
+        let added_file = &tagg.staging.registration_area[index];
+        let comment = prompt_comment("Comment:", added_file.comment.get(COMMENT_MAIN))?;
+        let title = prompt_comment(
+            "Title:",
+            added_file.comment.get("title").or(extracted_title.as_ref()),
+        )?;
+        let desc = prompt_comment("Description:", added_file.comment.get("desc"))?;
+
+        let added_file = &mut tagg.staging.registration_area[index];
+        let before = added_file.tags.clone();
+        TagEdit::Set.apply(&mut added_file.tags, &tags);
+        for (title, value) in [(COMMENT_MAIN, comment), ("title", title), ("desc", desc)] {
+            if value.is_empty() {
+                added_file.comment.remove(title);
+            } else {
+                added_file.comment.insert(title.to_string(), value);
+            }
+        }
+        write_tag_changes(&mut stdout, &before, &added_file.tags)?;
+
+        known.extend(tags);
+        known.sort();
+        known.dedup();
+        tagg.save_staging()?;
+    }
+
+    writeln!(&mut stdout)?;
+    writeln!(
+        &mut stdout,
+        "Went through {} files, use `tagg commit` to store them",
+        count
+    )?;
+
+    Ok(())
+}
+
+/// Ask for the text of a comment, starting from its current value.
+/// An empty answer removes the comment.
+fn prompt_comment(message: &str, current: Option<&String>) -> eyre::Result<String> {
+    let current = current.map(String::as_str).unwrap_or("");
+    let text = Text::new(message)
+        .with_initial_value(current)
+        .with_help_message("leave empty for none, esc to keep it as it is")
+        .prompt_skippable()?;
+    Ok(text
+        .unwrap_or_else(|| current.to_string())
+        .trim()
+        .to_string())
+}
+
+/// Completes the last of the space separated tags being typed from the existing tags
+#[derive(Debug, Clone)]
+struct TagCompleter {
+    tags: Vec<String>,
+}
+impl Autocomplete for TagCompleter {
+    fn get_suggestions(&mut self, input: &str) -> Result<Vec<String>, CustomUserError> {
+        let (typed, last) = match input.rsplit_once(' ') {
+            Some((typed, last)) => (Some(typed), last),
+            None => (None, input),
+        };
+        if last.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let already: Vec<&str> = typed.into_iter().flat_map(str::split_whitespace).collect();
+        Ok(self
+            .tags
+            .iter()
+            .filter(|tag| tag.starts_with(last) && !already.contains(&tag.as_str()))
+            .take(10)
+            .map(|tag| match typed {
+                Some(typed) => format!("{} {}", typed, tag),
+                None => tag.clone(),
+            })
+            .collect())
+    }
+
+    fn get_completion(
+        &mut self,
+        input: &str,
+        highlighted_suggestion: Option<String>,
+    ) -> Result<Replacement, CustomUserError> {
+        match highlighted_suggestion {
+            Some(suggestion) => Ok(Some(suggestion)),
+            None => Ok(self.get_suggestions(input)?.into_iter().next()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use inquire::Autocomplete;
+
+    use super::TagCompleter;
+
+    #[test]
+    fn test_tag_completer() {
+        let mut completer = TagCompleter {
+            tags: ["book", "deep-learning", "deep-sea", "paper"]
+                .iter()
+                .map(|x| x.to_string())
+                .collect(),
+        };
+        assert_eq!(
+            completer.get_suggestions("paper dee").unwrap(),
+            ["paper deep-learning", "paper deep-sea"]
+        );
+        assert_eq!(completer.get_suggestions("b").unwrap(), ["book"]);
+        assert!(completer.get_suggestions("book ").unwrap().is_empty());
+        assert!(completer.get_suggestions("book b").unwrap().is_empty());
+        assert_eq!(
+            completer.get_completion("book pa", None).unwrap().as_deref(),
+            Some("book paper")
+        );
+    }
+}
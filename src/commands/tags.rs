@@ -0,0 +1,662 @@
+use inquire::Confirm;
+use std::io::Write;
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::{
+    hierarchy::{tag_and_ancestors, tag_tree, TagNode, SEPARATORS},
+    namespaces,
+    query::{Expr, Query, TagPattern},
+    state::AddedFile,
+    storage::{FileInfo, StorageBackend},
+    tagg::Tagg,
+};
+
+use super::{get_single_file_from_prefix, grey, print_file};
+
+/// How the given tags change the tags a file already has
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagEdit {
+    Add,
+    Remove,
+    /// Replace all of the file's tags
+    Set,
+}
+impl TagEdit {
+    /// Apply the edit to the tags, keeping them sorted and without duplicates
+    pub fn apply(self, current: &mut Vec<String>, tags: &[String]) {
+        match self {
+            TagEdit::Add => current.extend(tags.iter().cloned()),
+            TagEdit::Remove => current.retain(|tag| !tags.contains(tag)),
+            TagEdit::Set => *current = tags.to_vec(),
+        }
+
+        // We have to sort so that dedup can work
+        current.sort();
+        current.dedup();
+    }
+}
+
+/// How `tagg tags` orders the tags
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TagSort {
+    /// Most used first
+    Count,
+    /// Alphabetically
+    Name,
+}
+
+/// List the tags on the stored files along with how many files have each, or if `related` is
+/// given then the tags which are most often on the same files as it.  
+/// If `tree` then the tags are shown as a hierarchy, see [`crate::hierarchy`].
+pub fn list_tags(
+    tagg: &mut Tagg,
+    sort: TagSort,
+    prefix: Option<String>,
+    namespace: Option<String>,
+    related: Option<String>,
+    tree: bool,
+) -> eyre::Result<()> {
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+
+    // The related tag is found like `tagg find` would, through its aliases and virtual implied
+    // tags, and none of its names are listed as related to it
+    let mut related_names = Vec::new();
+    let mut related_query = None;
+    if let Some(related) = &related {
+        let context = tagg.query_context()?;
+        let canonical = context.aliases.resolve(related);
+        related_names.push(canonical.to_string());
+        related_names.extend(
+            context
+                .aliases
+                .aliases_of(canonical)
+                .into_iter()
+                .map(str::to_string),
+        );
+        related_query = Some(context.prepare(Query {
+            expr: Expr::Tag(TagPattern::Exact(related.clone())),
+            case_insensitive: false,
+        })?);
+    }
+
+    let mut files = tagg.storage()?.all()?;
+    if let (Some(related), Some(query)) = (&related, &related_query) {
+        files.retain(|file| query.matches(file));
+        writeln!(
+            &mut stdout,
+            "{} files are tagged {:?}, which also have:",
+            files.len(),
+            related
+        )?;
+    }
+    let namespace = namespace.map(|namespace| format!("{}:", namespace));
+    let shown = |tag: &str| {
+        !related_names.iter().any(|x| x == tag)
+            && prefix.as_ref().is_none_or(|x| tag.starts_with(x.as_str()))
+            && namespace
+                .as_ref()
+                .is_none_or(|x| tag.starts_with(x.as_str()))
+    };
+
+    if tree {
+        let mut nodes = tag_tree(&files);
+        retain_nodes(&mut nodes, &shown, sort);
+        write_nodes(&mut stdout, &nodes, 0)?;
+        stdout.reset()?;
+        return Ok(());
+    }
+
+    let mut counts = count_tags(&files);
+    counts.retain(|(tag, _)| shown(tag));
+    if sort == TagSort::Name {
+        counts.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+
+    for (tag, count) in counts {
+        stdout.set_color(&grey())?;
+        write!(&mut stdout, "  {:>5}  ", count)?;
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
+        writeln!(&mut stdout, "{}", tag)?;
+    }
+    stdout.reset()?;
+
+    Ok(())
+}
+
+/// Keep the nodes which are shown, or which have children that are shown, and sort them
+fn retain_nodes(nodes: &mut Vec<TagNode>, shown: &impl Fn(&str) -> bool, sort: TagSort) {
+    for node in nodes.iter_mut() {
+        retain_nodes(&mut node.children, shown, sort);
+    }
+    nodes.retain(|node| shown(&node.tag) || !node.children.is_empty());
+    if sort == TagSort::Count {
+        // The sort is stable, so equal counts stay in alphabetical order
+        nodes.sort_by_key(|node| std::cmp::Reverse(node.count));
+    }
+}
+
+fn write_nodes(out: &mut impl WriteColor, nodes: &[TagNode], depth: usize) -> eyre::Result<()> {
+    for node in nodes {
+        out.set_color(&grey())?;
+        write!(
+            out,
+            "  {:>5}  {:indent$}",
+            node.count,
+            "",
+            indent = depth * 2
+        )?;
+        out.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
+        writeln!(out, "{}", node.label())?;
+        write_nodes(out, &node.children, depth + 1)?;
+    }
+    Ok(())
+}
+
+/// Count how many of the files have each tag, most used first and then alphabetically
+fn count_tags(files: &[FileInfo]) -> Vec<(String, usize)> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for file in files {
+        for tag in file.tags.iter() {
+            *counts.entry(tag).or_default() += 1;
+        }
+    }
+
+    let mut counts: Vec<(String, usize)> = counts
+        .into_iter()
+        .map(|(tag, count)| (tag.to_string(), count))
+        .collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
+/// Edit the tags of stored files, or of files in the registration-area if `staged`, and report
+/// how each file's tags changed
+pub fn edit_tags(
+    tagg: &mut Tagg,
+    files: Vec<String>,
+    tags: Vec<String>,
+    edit: TagEdit,
+    staged: bool,
+    new_tag: bool,
+) -> eyre::Result<()> {
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+
+    let mut tags = tags;
+    tagg.resolve_aliases(&mut tags)?;
+    if edit != TagEdit::Remove {
+        namespaces::check_tags(&tagg.config.namespaces, &tags)?;
+        check_vocabulary(tagg, &tags, new_tag)?;
+    }
+
+    if staged {
+        for file in files {
+            let staging = &mut tagg.staging;
+            if let Some(index) =
+                get_single_staged_from_prefix(&mut stdout, &staging.registration_area, &file)?
+            {
+                let added_file = &mut staging.registration_area[index];
+                let before = added_file.tags.clone();
+                edit.apply(&mut added_file.tags, &tags);
+
+                let name = added_file
+                    .path
+                    .file_name()
+                    .unwrap_or(added_file.path.as_os_str())
+                    .to_string_lossy();
+                print_file(&mut stdout, &name, None, &added_file.tags)?;
+                write_tag_changes(&mut stdout, &before, &added_file.tags)?;
+            }
+        }
+
+        tagg.save_staging()?;
+    } else {
+        // Removing a tag leaves what it implied, since that may have been added on its own.
+        // Files in the registration-area get the implied tags when they're committed.
+        let implications = match edit {
+            TagEdit::Remove => None,
+            _ => tagg.stored_implications()?,
+        };
+
+        let mut storage = tagg.storage()?;
+        for file in files {
+            if let Some(mut file) = get_single_file_from_prefix(&mut stdout, &storage, &file)? {
+                let before = file.tags.clone();
+                edit.apply(&mut file.tags, &tags);
+                if let Some(implications) = &implications {
+                    implications.apply(&mut file.tags);
+                }
+                storage.update(&file)?;
+
+                print_file(
+                    &mut stdout,
+                    &file.filename,
+                    file.original_filename.as_deref(),
+                    &file.tags,
+                )?;
+                write_tag_changes(&mut stdout, &before, &file.tags)?;
+            }
+        }
+
+        tagg.save_state()?;
+    }
+
+    Ok(())
+}
+
+/// In `strict-vocabulary` mode, check that the tags are already on some stored file or file in
+/// the registration-area (or are an ancestor of such a tag).  
+/// New tags are shown along with the existing tags they're closest to, in case they're a typo,
+/// and have to be confirmed unless `new_tag`.
+pub fn check_vocabulary(tagg: &mut Tagg, tags: &[String], new_tag: bool) -> eyre::Result<()> {
+    if !tagg.config.strict_vocabulary || tags.is_empty() {
+        return Ok(());
+    }
+
+    let verbose = tagg.verbose;
+    let (storage, staging) = tagg.storage_and_staging()?;
+    let stored = storage.all()?;
+    let known: HashSet<&str> = stored
+        .iter()
+        .map(|file| &file.tags)
+        .chain(staging.registration_area.iter().map(|file| &file.tags))
+        .flatten()
+        .flat_map(|tag| tag_and_ancestors(tag))
+        .collect();
+
+    for tag in tags {
+        if known.contains(tag.as_str()) {
+            continue;
+        }
+
+        if new_tag {
+            if verbose {
+                eprintln!("INFO: Adding the new tag {:?}", tag);
+            }
+            continue;
+        }
+
+        let suggestions = suggest_tags(&known, tag);
+
+        if suggestions.is_empty() {
+            eprintln!("WARN: {:?} is a new tag", tag);
+        } else {
+            eprintln!(
+                "WARN: {:?} is a new tag, did you mean {}?",
+                tag,
+                suggestions
+                    .iter()
+                    .map(|x| format!("{:?}", x))
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            );
+        }
+        let accept = Confirm::new(&format!("Add the new tag {:?}?", tag))
+            .with_default(false)
+            .prompt()?;
+        if !accept {
+            return Err(eyre::eyre!(
+                "Didn't add the new tag {:?}, pass `--new-tag` to add new tags without asking",
+                tag
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// The existing tags that are a couple of edits away from `tag`, most similar first
+fn suggest_tags<'a>(known: &HashSet<&'a str>, tag: &str) -> Vec<&'a str> {
+    let mut similar: Vec<(&str, f64)> = known
+        .iter()
+        .filter(|x| strsim::damerau_levenshtein(tag, x) <= 2)
+        .map(|x| (*x, strsim::normalized_damerau_levenshtein(tag, x)))
+        // Short tags are only a couple of edits away from lots of unrelated tags
+        .filter(|(_, similarity)| *similarity >= 0.6)
+        .collect();
+    similar.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    similar.into_iter().take(3).map(|(x, _)| x).collect()
+}
+
+/// Which tags [`rewrite_tags`] replaces, and what with
+#[derive(Debug, Clone, Copy)]
+struct TagRename<'a> {
+    from: &'a [String],
+    into: &'a str,
+    /// Whether descendants of the tags are moved under `into`, like `old/child` to `new/child`
+    descendants: bool,
+}
+impl TagRename<'_> {
+    /// The tag that `tag` is replaced with, if it is replaced
+    fn rename(&self, tag: &str) -> Option<String> {
+        if self.from.iter().any(|from| from == tag) {
+            return Some(self.into.to_string());
+        }
+        if !self.descendants {
+            return None;
+        }
+        self.from.iter().find_map(|from| {
+            tag.strip_prefix(from.as_str())
+                .filter(|rest| rest.starts_with(SEPARATORS))
+                .map(|rest| format!("{}{}", self.into, rest))
+        })
+    }
+}
+
+/// Replace the tags `from` with `into` on every stored file and every file in the
+/// registration-area (including those that expired out of it), along with their descendants if
+/// `descendants`. Aliases and implication rules are rewritten to match.  
+/// The number of files that would change is shown first, and nothing is changed if `dry`.
+/// The change has to be confirmed unless `yes`, which also accepts `into` being a new tag in
+/// `strict-vocabulary` mode.
+pub fn rewrite_tags(
+    tagg: &mut Tagg,
+    from: Vec<String>,
+    into: String,
+    descendants: bool,
+    dry: bool,
+    yes: bool,
+) -> eyre::Result<()> {
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+
+    let mut into = [into];
+    tagg.resolve_aliases(&mut into)?;
+    check_vocabulary(tagg, &into, yes)?;
+    let [into] = into;
+    let from: Vec<String> = from.into_iter().filter(|tag| *tag != into).collect();
+    if from.is_empty() {
+        return Err(eyre::eyre!("There are no tags to turn into {:?}", into));
+    }
+    let rename = TagRename {
+        from: &from,
+        into: &into,
+        descendants,
+    };
+
+    let state = tagg.state()?;
+    let mut aliases = state.aliases.clone();
+    let renamed_aliases = aliases.rename_tags(|tag| rename.rename(tag));
+    let mut implications = state.implications.clone();
+    let renamed_rules = implications
+        .rename_tags(|tag| rename.rename(tag))
+        .map_err(|err| eyre::eyre!("Can't rewrite the implication rules: {}", err))?;
+
+    let namespaces = tagg.config.namespaces.clone();
+    let (mut storage, staging) = tagg.storage_and_staging()?;
+    let mut stored = storage.all()?;
+
+    // The renamed tags have to fit their namespace, like the tags given to `add-tags`
+    let mut renamed_tags = BTreeSet::from([into.clone()]);
+    let staged_files = staging
+        .registration_area
+        .iter()
+        .chain(staging.expired.iter());
+    let tags = stored
+        .iter()
+        .map(|file| &file.tags)
+        .chain(staged_files.map(|file| &file.tags))
+        .flatten();
+    for tag in tags {
+        renamed_tags.extend(rename.rename(tag));
+    }
+    namespaces::check_tags(&namespaces, &Vec::from_iter(renamed_tags))?;
+
+    stored.retain_mut(|file| replace_tags(&mut file.tags, rename));
+    let mut staged = 0;
+    for added_file in staging
+        .registration_area
+        .iter()
+        .chain(staging.expired.iter())
+    {
+        if added_file
+            .tags
+            .iter()
+            .any(|tag| rename.rename(tag).is_some())
+        {
+            staged += 1;
+        }
+    }
+
+    writeln!(
+        &mut stdout,
+        "Turning {}{} into {:?} would change {} stored files, {} files in the registration-area, {} aliases and {} implication rules",
+        from.iter()
+            .map(|tag| format!("{:?}", tag))
+            .collect::<Vec<_>>()
+            .join(", "),
+        match (descendants, from.len()) {
+            (false, _) => "",
+            (true, 1) => " (and the tags under it)",
+            (true, _) => " (and the tags under them)",
+        },
+        into,
+        stored.len(),
+        staged,
+        renamed_aliases,
+        renamed_rules
+    )?;
+    let changes = stored.len() + staged + renamed_aliases + renamed_rules;
+    if dry || changes == 0 {
+        return Ok(());
+    }
+
+    if !yes {
+        let rewrite = Confirm::new("Rewrite the tags?")
+            .with_default(false)
+            .prompt()?;
+        if !rewrite {
+            return Ok(());
+        }
+    }
+
+    storage.update_all(&stored)?;
+    for added_file in staging
+        .registration_area
+        .iter_mut()
+        .chain(staging.expired.iter_mut())
+    {
+        replace_tags(&mut added_file.tags, rename);
+    }
+    if renamed_aliases > 0 || renamed_rules > 0 {
+        let state = tagg.state_mut()?;
+        state.aliases = aliases;
+        state.implications = implications;
+    }
+
+    tagg.save_state()?;
+    if staged > 0 {
+        tagg.save_staging()?;
+    }
+
+    writeln!(
+        &mut stdout,
+        "Rewrote the tags of {} stored files and {} files in the registration-area, {} aliases and {} implication rules",
+        stored.len(),
+        staged,
+        renamed_aliases,
+        renamed_rules
+    )?;
+
+    Ok(())
+}
+
+/// Replace the tags that are renamed, keeping the tags sorted and without duplicates like
+/// [`TagEdit::apply`] does.  
+/// Returns whether any tags were replaced.
+fn replace_tags(tags: &mut Vec<String>, rename: TagRename) -> bool {
+    let mut replaced = false;
+    for tag in tags.iter_mut() {
+        if let Some(renamed) = rename.rename(tag) {
+            *tag = renamed;
+            replaced = true;
+        }
+    }
+    if replaced {
+        tags.sort();
+        tags.dedup();
+    }
+    replaced
+}
+
+/// Find the file in the registration-area whose file name starts with `prefix`.
+/// A file name which is exactly `prefix` is chosen even if others start with it.
+pub(crate) fn get_single_staged_from_prefix(
+    out: &mut impl WriteColor,
+    registration_area: &[AddedFile],
+    prefix: &str,
+) -> eyre::Result<Option<usize>> {
+    let name = |added_file: &AddedFile| {
+        added_file
+            .path
+            .file_name()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+
+    if let Some(index) = registration_area.iter().position(|x| name(x) == prefix) {
+        return Ok(Some(index));
+    }
+
+    let found: Vec<usize> = registration_area
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| name(x).starts_with(prefix))
+        .map(|(i, _)| i)
+        .collect();
+    match found.as_slice() {
+        [] => {
+            eprintln!(
+                "WARN: Failed to find file with prefix {:?} in the registration-area",
+                prefix
+            );
+            Ok(None)
+        }
+        [index] => Ok(Some(*index)),
+        _ => {
+            writeln!(
+                out,
+                "There was more than one file in the registration-area which would match the prefix {:?}",
+                prefix
+            )?;
+            for index in found {
+                let added_file = &registration_area[index];
+                print_file(
+                    out,
+                    &added_file.path.to_string_lossy(),
+                    None,
+                    &added_file.tags,
+                )?;
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Write the tags which were added and removed, or that nothing changed
+pub(crate) fn write_tag_changes(
+    out: &mut impl WriteColor,
+    before: &[String],
+    after: &[String],
+) -> eyre::Result<()> {
+    let added: Vec<&String> = after.iter().filter(|x| !before.contains(x)).collect();
+    let removed: Vec<&String> = before.iter().filter(|x| !after.contains(x)).collect();
+
+    if added.is_empty() && removed.is_empty() {
+        out.set_color(&grey())?;
+        writeln!(out, "    (no change)")?;
+    }
+    for tag in added {
+        out.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
+        writeln!(out, "    + {}", tag)?;
+    }
+    for tag in removed {
+        out.set_color(ColorSpec::new().set_fg(Some(Color::Red)))?;
+        writeln!(out, "    - {}", tag)?;
+    }
+    out.reset()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::FileInfo;
+
+    use std::collections::HashSet;
+
+    use super::{count_tags, replace_tags, suggest_tags, TagEdit, TagRename};
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_tag_edit() {
+        let mut current = tags(&["paper", "draft"]);
+        TagEdit::Add.apply(&mut current, &tags(&["math", "paper"]));
+        assert_eq!(current, tags(&["draft", "math", "paper"]));
+
+        TagEdit::Remove.apply(&mut current, &tags(&["draft", "missing"]));
+        assert_eq!(current, tags(&["math", "paper"]));
+
+        TagEdit::Set.apply(&mut current, &tags(&["book", "book", "fiction"]));
+        assert_eq!(current, tags(&["book", "fiction"]));
+    }
+
+    #[test]
+    fn test_suggest_tags() {
+        let known = HashSet::from(["deep-learning", "deep-sea", "paper", "papers", "book"]);
+        assert_eq!(suggest_tags(&known, "deeplearning"), ["deep-learning"]);
+        assert_eq!(suggest_tags(&known, "papr"), ["paper", "papers"]);
+        assert!(suggest_tags(&known, "fiction").is_empty());
+        assert!(suggest_tags(&known, "bog").is_empty());
+    }
+
+    #[test]
+    fn test_count_tags() {
+        let file = |file_tags: &[&str]| FileInfo {
+            tags: tags(file_tags),
+            ..Default::default()
+        };
+        let files = [
+            file(&["paper", "math"]),
+            file(&["paper", "draft"]),
+            file(&["book", "math", "paper"]),
+        ];
+
+        assert_eq!(
+            count_tags(&files),
+            [
+                ("paper".to_string(), 3),
+                ("math".to_string(), 2),
+                ("book".to_string(), 1),
+                ("draft".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replace_tags() {
+        let from = tags(&["deeplearning", "dl"]);
+        let mut rename = TagRename {
+            from: &from,
+            into: "deep-learning",
+            descendants: false,
+        };
+        let mut current = tags(&["deep-learning", "dl", "paper", "deeplearning", "dl/rnn"]);
+        assert!(replace_tags(&mut current, rename));
+        assert_eq!(current, tags(&["deep-learning", "dl/rnn", "paper"]));
+
+        assert!(!replace_tags(&mut current, rename));
+        assert_eq!(current, tags(&["deep-learning", "dl/rnn", "paper"]));
+
+        rename.descendants = true;
+        current.push("dlx".to_string());
+        assert!(replace_tags(&mut current, rename));
+        assert_eq!(
+            current,
+            tags(&["deep-learning", "deep-learning/rnn", "dlx", "paper"])
+        );
+    }
+}
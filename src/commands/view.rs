@@ -0,0 +1,141 @@
+use std::{io::Write, path::PathBuf};
+
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+use crate::{
+    storage::Storage,
+    tagg::Tagg,
+    view::{View, VIEW_MANIFEST},
+    ViewCommand,
+};
+
+use super::grey;
+
+pub(crate) fn view(tagg: &mut Tagg, command: ViewCommand) -> eyre::Result<()> {
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+    let storage_path = tagg.config.storage_path(&tagg.config_path)?;
+
+    match command {
+        ViewCommand::Build {
+            dir,
+            query,
+            link,
+            titles,
+        } => {
+            // Checked before anything is changed
+            tagg.prepare_query(&query)?;
+
+            let path = absolute_path(&dir)?;
+            let view = View {
+                path: path.clone(),
+                query,
+                link,
+                titles,
+            };
+
+            let context = tagg.query_context()?;
+            let storage = Storage::from_backend(&tagg.storage()?)?;
+            let state = tagg.state_mut()?;
+            if let Some(existing) = state.views.iter_mut().find(|x| x.path == path) {
+                if existing.link != view.link || existing.titles != view.titles {
+                    // The names or kind of links would change, so start over
+                    remove_links(existing)?;
+                }
+                *existing = view.clone();
+            } else {
+                state.views.push(view.clone());
+            }
+
+            let changes = view.refresh(&storage, &storage_path, &context)?;
+            writeln!(
+                &mut stdout,
+                "Built view {:?}: {} added, {} removed",
+                view.path, changes.added, changes.removed
+            )?;
+
+            tagg.save_state()?;
+        }
+        ViewCommand::Refresh { dirs } => {
+            let paths = dirs
+                .iter()
+                .map(|dir| absolute_path(dir))
+                .collect::<eyre::Result<Vec<PathBuf>>>()?;
+            let context = tagg.query_context()?;
+            let storage = Storage::from_backend(&tagg.storage()?)?;
+            let state = tagg.state()?;
+            for path in paths.iter() {
+                if !state.views.iter().any(|x| x.path == *path) {
+                    eprintln!("WARN: {:?} is not a view", path);
+                }
+            }
+
+            for view in state.views.iter() {
+                if !paths.is_empty() && !paths.contains(&view.path) {
+                    continue;
+                }
+
+                let changes = view.refresh(&storage, &storage_path, &context)?;
+                writeln!(
+                    &mut stdout,
+                    "Refreshed view {:?}: {} added, {} removed, {} unchanged",
+                    view.path, changes.added, changes.removed, changes.unchanged
+                )?;
+            }
+        }
+        ViewCommand::List {} => {
+            for view in tagg.state()?.views.iter() {
+                stdout.set_color(ColorSpec::new().set_fg(Some(Color::Blue)))?;
+                write!(&mut stdout, "  {}", view.path.display())?;
+                stdout.set_color(&grey())?;
+                writeln!(&mut stdout, " ({:?}) {}", view.link, view.query)?;
+            }
+            stdout.reset()?;
+        }
+        ViewCommand::Remove { dir } => {
+            let path = absolute_path(&dir)?;
+            let state = tagg.state_mut()?;
+            if let Some(index) = state.views.iter().position(|x| x.path == path) {
+                let view = state.views.remove(index);
+                remove_links(&view)?;
+                // Only removes the folder if nothing else was put in it
+                let _ = std::fs::remove_dir(&view.path);
+                tagg.save_state()?;
+            } else {
+                eprintln!("{:?} is not a view", path);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the links that tagg created in the view, along with the manifest
+fn remove_links(view: &View) -> eyre::Result<()> {
+    let manifest_path = view.path.join(VIEW_MANIFEST);
+    if !manifest_path.exists() {
+        return Ok(());
+    }
+
+    for name in std::fs::read_to_string(&manifest_path)?.lines() {
+        let link_path = view.path.join(name);
+        if !name.is_empty() && link_path.symlink_metadata().is_ok() {
+            std::fs::remove_file(link_path)?;
+        }
+    }
+    std::fs::remove_file(manifest_path)?;
+
+    Ok(())
+}
+
+/// Views are stored with absolute paths, since tagg can be run from anywhere.
+/// The folder might not exist yet, so this can't just canonicalize.
+fn absolute_path(dir: &str) -> eyre::Result<PathBuf> {
+    let path = crate::util::expand_path(dir);
+    let path = if path.is_absolute() {
+        path
+    } else {
+        std::env::current_dir()?.join(path)
+    };
+
+    Ok(path.canonicalize().unwrap_or(path))
+}
@@ -1,7 +1,13 @@
 use serde::{Deserialize, Serialize};
-use std::path::{Path, PathBuf};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
 
-use crate::util::expand_path;
+use crate::{
+    implications::ImpliedTags, namespaces::NamespaceType, storage::StorageBackendKind,
+    util::expand_path,
+};
 
 // We don't allow modifying the storage location with an env var, since you could cause issues by having
 // the state have files that the storage doesn't have.
@@ -11,6 +17,8 @@ use crate::util::expand_path;
 pub const CONFIG_ENV_VAR: &str = "TAGG_CONFIG";
 /// The name of the environment variable that can be used to override where we store the state
 pub const STATE_ENV_VAR: &str = "TAGG_STATE";
+/// The name of the environment variable that can be used to override where we store the registration-area
+pub const STAGING_ENV_VAR: &str = "TAGG_STAGING";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
@@ -28,17 +36,79 @@ pub struct Config {
     #[serde(default)]
     pub state_path: Option<String>,
 
+    /// The location where the program should store the registration-area.  
+    /// This can be overwritten with the `TAGG_STAGING` environment variable.  
+    /// Defaults to `staging.toml` next to the state file.
+    #[serde(default)]
+    pub staging_path: Option<String>,
+
+    /// Where the information about the stored files is kept: `toml` keeps it inside the state
+    /// file, `sqlite` keeps it in a database which is faster for large catalogues.
+    #[serde(default)]
+    pub storage_backend: StorageBackendKind,
+
+    /// The location of the database for the `sqlite` storage-backend.  
+    /// Defaults to `storage.sqlite` next to the state file.
+    #[serde(default)]
+    pub database_path: Option<String>,
+
+    /// The location of the journal of changes to the catalogue, used by `tagg undo`.  
+    /// Defaults to `journal.toml` next to the state file.
+    #[serde(default)]
+    pub journal_path: Option<String>,
+
+    /// How many previous versions of the state file to keep, as `<state-file>.1` (the most
+    /// recent) up to `<state-file>.<n>`. They can be restored with `tagg state restore <n>`.
+    #[serde(default = "default_state_backups")]
+    pub state_backups: usize,
+
+    /// How many seconds to wait for another tagg process to finish with the state before giving
+    /// up. `0` fails immediately.
+    #[serde(default)]
+    pub lock_timeout: u64,
+
     /// Whether or not adding a file should hash it to alert you if it gets changed.  
     /// This can help avoid some accidental problems where you have an old `tagg add` around.  
     pub hash_added_files: bool,
 
-    /// How long the registration of files should be limited by. After this amount of time
+    /// How long the registration of files should be limited by, in seconds. After this amount of time
     /// after the last file added to the registration-area, the area is cleared (the files being
     /// unchanged) to avoid accidentally including them if you forget.  
+    /// The cleared files can be brought back with `tagg restore-staged`.  
+    /// `0` disables this.
     pub registration_delay_limit: u64,
 
     /// Whether it should extract titles from pdf documents and store them
     pub extract_pdf_titles: bool,
+
+    /// The maximum number of differing bits between two images' perceptual hashes for
+    /// `tagg similar` to consider them similar. Out of 64 bits.
+    #[serde(default = "default_similar_distance")]
+    pub similar_distance: u32,
+
+    /// Whether adding a tag that isn't on any file yet should ask for confirmation (or need
+    /// `--new-tag`), suggesting existing tags that are spelled similarly.
+    #[serde(default)]
+    pub strict_vocabulary: bool,
+
+    /// When the tags implied by `tagg implies` rules are applied: `stored` adds them to files
+    /// when they're committed or have tags added, `virtual` never stores them but makes
+    /// `tagg find` treat files as having them.
+    #[serde(default)]
+    pub implied_tags: ImpliedTags,
+
+    /// The types of the values of namespaced tags like `year:2021`, see [`crate::namespaces`].  
+    /// This is a table, so it has to come after the other options in the config file.
+    #[serde(default)]
+    pub namespaces: BTreeMap<String, NamespaceType>,
+}
+
+fn default_similar_distance() -> u32 {
+    10
+}
+
+fn default_state_backups() -> usize {
+    5
 }
 impl Config {
     pub fn config_path() -> PathBuf {
@@ -82,6 +152,65 @@ impl Config {
         }
     }
 
+    pub fn staging_path(&self, config_path: &Path, state_path: &Path) -> eyre::Result<PathBuf> {
+        if let Ok(staging_path) = std::env::var(STAGING_ENV_VAR) {
+            Ok(expand_path(staging_path))
+        } else if let Some(staging_path) = &self.staging_path {
+            let staging_path = expand_path(staging_path);
+            if let Some(config_parent) = config_path.parent() {
+                let mut path = config_parent.to_path_buf();
+                path.push(staging_path);
+                Ok(path)
+            } else if staging_path.is_absolute() {
+                Ok(staging_path)
+            } else {
+                Err(eyre::eyre!(
+                    "Invalid config-path parent-folder when staging-path is relative"
+                ))
+            }
+        } else {
+            Ok(state_path.with_file_name("staging.toml"))
+        }
+    }
+
+    pub fn database_path(&self, config_path: &Path, state_path: &Path) -> eyre::Result<PathBuf> {
+        if let Some(database_path) = &self.database_path {
+            let database_path = expand_path(database_path);
+            if let Some(config_parent) = config_path.parent() {
+                let mut path = config_parent.to_path_buf();
+                path.push(database_path);
+                Ok(path)
+            } else if database_path.is_absolute() {
+                Ok(database_path)
+            } else {
+                Err(eyre::eyre!(
+                    "Invalid config-path parent-folder when database-path is relative"
+                ))
+            }
+        } else {
+            Ok(state_path.with_file_name("storage.sqlite"))
+        }
+    }
+
+    pub fn journal_path(&self, config_path: &Path, state_path: &Path) -> eyre::Result<PathBuf> {
+        if let Some(journal_path) = &self.journal_path {
+            let journal_path = expand_path(journal_path);
+            if let Some(config_parent) = config_path.parent() {
+                let mut path = config_parent.to_path_buf();
+                path.push(journal_path);
+                Ok(path)
+            } else if journal_path.is_absolute() {
+                Ok(journal_path)
+            } else {
+                Err(eyre::eyre!(
+                    "Invalid config-path parent-folder when journal-path is relative"
+                ))
+            }
+        } else {
+            Ok(state_path.with_file_name("journal.toml"))
+        }
+    }
+
     pub fn storage_path(&self, config_path: &Path) -> eyre::Result<PathBuf> {
         let storage_path = &self.storage_path;
         let storage_path = expand_path(storage_path);
@@ -0,0 +1,692 @@
+//! A small implementation of the Linux FUSE protocol, which `tagg mount` uses to serve the
+//! virtual filesystem of [`crate::vfs`].
+//!
+//! It only covers what a filesystem with a fixed layout needs: looking up and listing entries,
+//! and reading, writing and changing the attributes of files. Requests are answered one at a
+//! time, in the order that the kernel sends them.
+//! Errors are `errno` values, like [`libc::ENOENT`].
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::File,
+    io::{Read, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd},
+        net::UnixStream,
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The inode of the root directory
+pub const ROOT_ID: u64 = 1;
+
+/// The version of the protocol that we speak
+const KERNEL_VERSION: u32 = 7;
+const KERNEL_MINOR_VERSION: u32 = 31;
+/// The most data that the kernel sends in one write
+const MAX_WRITE: u32 = 128 * 1024;
+/// Room for the largest request, a write of [`MAX_WRITE`] bytes along with its headers
+const BUFFER_SIZE: usize = MAX_WRITE as usize + 4096;
+/// How long the kernel may cache names and attributes. The layout doesn't change while mounted,
+/// but the files can still be edited from outside of the mount.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+const IN_HEADER_LEN: usize = 40;
+const OUT_HEADER_LEN: usize = 16;
+const WRITE_IN_LEN: usize = 40;
+const DIRENT_LEN: usize = 24;
+
+mod opcode {
+    pub const LOOKUP: u32 = 1;
+    pub const FORGET: u32 = 2;
+    pub const GETATTR: u32 = 3;
+    pub const SETATTR: u32 = 4;
+    pub const SYMLINK: u32 = 6;
+    pub const MKNOD: u32 = 8;
+    pub const MKDIR: u32 = 9;
+    pub const UNLINK: u32 = 10;
+    pub const RMDIR: u32 = 11;
+    pub const RENAME: u32 = 12;
+    pub const LINK: u32 = 13;
+    pub const OPEN: u32 = 14;
+    pub const READ: u32 = 15;
+    pub const WRITE: u32 = 16;
+    pub const STATFS: u32 = 17;
+    pub const RELEASE: u32 = 18;
+    pub const FSYNC: u32 = 20;
+    pub const FLUSH: u32 = 25;
+    pub const INIT: u32 = 26;
+    pub const OPENDIR: u32 = 27;
+    pub const READDIR: u32 = 28;
+    pub const RELEASEDIR: u32 = 29;
+    pub const ACCESS: u32 = 34;
+    pub const CREATE: u32 = 35;
+    pub const INTERRUPT: u32 = 36;
+    pub const DESTROY: u32 = 38;
+    pub const BATCH_FORGET: u32 = 42;
+    pub const RENAME2: u32 = 45;
+}
+
+/// Flags of the `INIT` request that we accept when the kernel offers them
+const INIT_ASYNC_READ: u32 = 1 << 0;
+const INIT_BIG_WRITES: u32 = 1 << 5;
+const INIT_AUTO_INVAL_DATA: u32 = 1 << 12;
+
+/// Which fields a `SETATTR` request changes
+const SETATTR_MODE: u32 = 1 << 0;
+const SETATTR_UID: u32 = 1 << 1;
+const SETATTR_GID: u32 = 1 << 2;
+const SETATTR_SIZE: u32 = 1 << 3;
+const SETATTR_ATIME: u32 = 1 << 4;
+const SETATTR_MTIME: u32 = 1 << 5;
+const SETATTR_FH: u32 = 1 << 6;
+const SETATTR_ATIME_NOW: u32 = 1 << 7;
+const SETATTR_MTIME_NOW: u32 = 1 << 8;
+
+/// Set by SIGINT and SIGTERM, to stop serving and unmount
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// The attributes of a file or directory
+pub struct Attr {
+    pub ino: u64,
+    pub size: u64,
+    /// In 512 byte blocks
+    pub blocks: u64,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    /// The type and permissions, like `stat`'s `st_mode`
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+impl Attr {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        let (atime, atime_nsec) = timestamp(self.atime);
+        let (mtime, mtime_nsec) = timestamp(self.mtime);
+        let (ctime, ctime_nsec) = timestamp(self.ctime);
+        for x in [self.ino, self.size, self.blocks, atime, mtime, ctime] {
+            out.extend_from_slice(&x.to_ne_bytes());
+        }
+        let blksize = 4096;
+        for x in [
+            atime_nsec, mtime_nsec, ctime_nsec, self.mode, self.nlink, self.uid, self.gid,
+            // rdev, and flags at the end
+            0, blksize, 0,
+        ] {
+            out.extend_from_slice(&x.to_ne_bytes());
+        }
+    }
+}
+
+/// The changes asked for by a `SETATTR` request
+#[derive(Debug, Default)]
+pub struct SetAttr {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<SystemTime>,
+    pub mtime: Option<SystemTime>,
+}
+
+pub struct DirEntry {
+    pub ino: u64,
+    pub name: String,
+    pub is_dir: bool,
+}
+
+/// The operations of a filesystem. Inodes are chosen by the filesystem, except for the root
+/// which is [`ROOT_ID`].
+pub trait Filesystem {
+    fn lookup(&mut self, parent: u64, name: &str) -> Result<Attr, i32>;
+    fn getattr(&mut self, ino: u64) -> Result<Attr, i32>;
+    /// `fh` is the handle of the file, if it was changed through an open file
+    fn setattr(&mut self, ino: u64, fh: Option<u64>, changes: &SetAttr) -> Result<Attr, i32>;
+    /// Open a file with the `open(2)` flags, returning a handle for it
+    fn open(&mut self, ino: u64, flags: i32) -> Result<u64, i32>;
+    fn read(&mut self, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, i32>;
+    /// Returns how much was written
+    fn write(&mut self, fh: u64, offset: u64, data: &[u8]) -> Result<u32, i32>;
+    fn fsync(&mut self, fh: u64) -> Result<(), i32>;
+    fn release(&mut self, fh: u64);
+    /// List a directory, including `.` and `..`. It is listed once when it is opened.
+    fn list(&mut self, ino: u64) -> Result<Vec<DirEntry>, i32>;
+    fn statfs(&mut self) -> Result<libc::statvfs, i32>;
+}
+
+/// How the filesystem has to be unmounted
+enum Unmount {
+    /// With `umount2`, since we mounted it ourselves as root
+    Syscall,
+    /// With the `fusermount` program that mounted it
+    Fusermount(&'static str),
+}
+
+/// A mounted filesystem, which is unmounted when dropped
+pub struct Session {
+    device: File,
+    mountpoint: PathBuf,
+    unmount: Unmount,
+    /// The listings of the open directories
+    dirs: HashMap<u64, Vec<DirEntry>>,
+    next_dir: u64,
+}
+impl Session {
+    /// Mount an empty filesystem on the directory, which then has to be served with
+    /// [`Session::serve`]
+    pub fn mount(mountpoint: &Path) -> eyre::Result<Session> {
+        // Only root can mount directly, everyone else goes through the setuid fusermount
+        let (device, unmount) = if unsafe { libc::geteuid() } == 0 {
+            (mount_syscall(mountpoint)?, Unmount::Syscall)
+        } else {
+            mount_fusermount(mountpoint)?
+        };
+
+        Ok(Session {
+            device,
+            mountpoint: mountpoint.to_path_buf(),
+            unmount,
+            dirs: HashMap::new(),
+            next_dir: 1,
+        })
+    }
+
+    /// Answer requests until the filesystem is unmounted, or tagg gets SIGINT or SIGTERM
+    pub fn serve(&mut self, fs: &mut impl Filesystem) -> eyre::Result<()> {
+        catch_stop_signals();
+
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            if !self.wait_for_request()? {
+                return Ok(());
+            }
+
+            let len = match (&self.device).read(&mut buffer) {
+                Ok(len) => len,
+                Err(err) => match err.raw_os_error() {
+                    // Interrupted by a signal, or the request was interrupted before we read it
+                    Some(libc::EINTR | libc::ENOENT | libc::EAGAIN) => continue,
+                    // The filesystem was unmounted
+                    Some(libc::ENODEV) => return Ok(()),
+                    _ => return Err(err.into()),
+                },
+            };
+            if len < IN_HEADER_LEN {
+                return Err(eyre::eyre!("Got a FUSE request of only {} bytes", len));
+            }
+
+            let opcode = u32_at(&buffer, 4).unwrap_or(0);
+            let unique = u64_at(&buffer, 8).unwrap_or(0);
+            let ino = u64_at(&buffer, 16).unwrap_or(0);
+            let body = &buffer[IN_HEADER_LEN..len];
+            match self.dispatch(fs, opcode, ino, body) {
+                Some(Ok(data)) => self.reply(unique, 0, &data)?,
+                Some(Err(errno)) => self.reply(unique, -errno, &[])?,
+                None => {}
+            }
+
+            if opcode == opcode::DESTROY {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Wait until there is a request to read, checking for signals every so often.
+    /// Returns `false` if we should stop.
+    fn wait_for_request(&self) -> eyre::Result<bool> {
+        let mut poll = libc::pollfd {
+            fd: self.device.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            if STOP.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+            // The timeout covers a signal arriving just before we start waiting
+            let res = unsafe { libc::poll(&mut poll, 1, 1000) };
+            if res > 0 {
+                return Ok(true);
+            } else if res < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.raw_os_error() != Some(libc::EINTR) {
+                    return Err(err.into());
+                }
+            }
+        }
+    }
+
+    /// Handle a request, returning the body of the reply, or `None` if it doesn't get one
+    fn dispatch(
+        &mut self,
+        fs: &mut impl Filesystem,
+        opcode: u32,
+        ino: u64,
+        body: &[u8],
+    ) -> Option<Result<Vec<u8>, i32>> {
+        let reply = match opcode {
+            opcode::FORGET | opcode::BATCH_FORGET | opcode::INTERRUPT => return None,
+            opcode::INIT => init(body),
+            opcode::DESTROY | opcode::FLUSH | opcode::ACCESS => Ok(Vec::new()),
+            opcode::LOOKUP => name(body)
+                .and_then(|name| fs.lookup(ino, name))
+                .map(|attr| entry_out(&attr)),
+            opcode::GETATTR => fs.getattr(ino).map(|attr| attr_out(&attr)),
+            opcode::SETATTR => setattr(fs, ino, body).map(|attr| attr_out(&attr)),
+            opcode::OPEN => u32_at(body, 0)
+                .and_then(|flags| fs.open(ino, flags as i32))
+                .map(open_out),
+            opcode::READ => read(fs, body),
+            opcode::WRITE => write(fs, body),
+            opcode::FSYNC => u64_at(body, 0)
+                .and_then(|fh| fs.fsync(fh))
+                .map(|()| Vec::new()),
+            opcode::RELEASE => u64_at(body, 0).map(|fh| {
+                fs.release(fh);
+                Vec::new()
+            }),
+            opcode::OPENDIR => fs.list(ino).map(|entries| {
+                let fh = self.next_dir;
+                self.next_dir += 1;
+                self.dirs.insert(fh, entries);
+                open_out(fh)
+            }),
+            opcode::READDIR => self.readdir(body),
+            opcode::RELEASEDIR => u64_at(body, 0).map(|fh| {
+                self.dirs.remove(&fh);
+                Vec::new()
+            }),
+            opcode::STATFS => fs.statfs().map(|stat| statfs_out(&stat)),
+            // The layout comes from the tags, so it can't be changed through the filesystem
+            opcode::MKNOD
+            | opcode::MKDIR
+            | opcode::UNLINK
+            | opcode::RMDIR
+            | opcode::SYMLINK
+            | opcode::RENAME
+            | opcode::RENAME2
+            | opcode::LINK
+            | opcode::CREATE => Err(libc::EPERM),
+            _ => Err(libc::ENOSYS),
+        };
+        Some(reply)
+    }
+
+    fn readdir(&self, body: &[u8]) -> Result<Vec<u8>, i32> {
+        let fh = u64_at(body, 0)?;
+        let offset = u64_at(body, 8)?;
+        let size = u32_at(body, 16)? as usize;
+        let entries = self.dirs.get(&fh).ok_or(libc::EBADF)?;
+        Ok(encode_dir_entries(entries, offset, size))
+    }
+
+    fn reply(&self, unique: u64, error: i32, data: &[u8]) -> eyre::Result<()> {
+        let mut reply = Vec::with_capacity(OUT_HEADER_LEN + data.len());
+        reply.extend_from_slice(&((OUT_HEADER_LEN + data.len()) as u32).to_ne_bytes());
+        reply.extend_from_slice(&error.to_ne_bytes());
+        reply.extend_from_slice(&unique.to_ne_bytes());
+        reply.extend_from_slice(data);
+        match (&self.device).write(&reply) {
+            Ok(_) => Ok(()),
+            // The request was interrupted, and so is no longer waiting for a reply
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+impl Drop for Session {
+    fn drop(&mut self) {
+        // It may already have been unmounted from outside of tagg, so errors are ignored.
+        // The unmount is lazy, so that it isn't refused while something is using it.
+        match self.unmount {
+            Unmount::Syscall => {
+                if let Ok(path) = CString::new(self.mountpoint.as_os_str().as_bytes()) {
+                    unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) };
+                }
+            }
+            Unmount::Fusermount(program) => {
+                let _ = Command::new(program)
+                    .args(["-u", "-z", "--"])
+                    .arg(&self.mountpoint)
+                    .status();
+            }
+        }
+    }
+}
+
+fn mount_syscall(mountpoint: &Path) -> eyre::Result<File> {
+    let device = File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")
+        .map_err(|err| eyre::eyre!("Failed to open /dev/fuse: {}", err))?;
+
+    let target = CString::new(mountpoint.as_os_str().as_bytes())?;
+    let options = CString::new(format!(
+        "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+        device.as_raw_fd(),
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() },
+    ))?;
+    let res = unsafe {
+        libc::mount(
+            c"tagg".as_ptr(),
+            target.as_ptr(),
+            c"fuse.tagg".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV,
+            options.as_ptr().cast(),
+        )
+    };
+    if res != 0 {
+        return Err(eyre::eyre!(
+            "Failed to mount on {:?}: {}",
+            mountpoint,
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    Ok(device)
+}
+
+/// Mount through `fusermount3` (or the older `fusermount`), which opens the FUSE device and
+/// sends it back to us over a socket
+fn mount_fusermount(mountpoint: &Path) -> eyre::Result<(File, Unmount)> {
+    let (ours, theirs) = UnixStream::pair()?;
+    // fusermount has to inherit its end of the socket
+    if unsafe { libc::fcntl(theirs.as_raw_fd(), libc::F_SETFD, 0) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    for program in ["fusermount3", "fusermount"] {
+        let status = Command::new(program)
+            .args(["-o", "fsname=tagg,subtype=tagg,default_permissions", "--"])
+            .arg(mountpoint)
+            .env("_FUSE_COMMFD", theirs.as_raw_fd().to_string())
+            .status();
+        match status {
+            Ok(status) if status.success() => {
+                return Ok((receive_device(&ours)?, Unmount::Fusermount(program)))
+            }
+            Ok(status) => {
+                return Err(eyre::eyre!(
+                    "{} failed to mount on {:?} ({})",
+                    program,
+                    mountpoint,
+                    status
+                ))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Err(eyre::eyre!(
+        "Mounting without being root needs fusermount3 (or fusermount), which wasn't found"
+    ))
+}
+
+fn receive_device(socket: &UnixStream) -> eyre::Result<File> {
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr().cast(),
+        iov_len: data.len(),
+    };
+    // Aligned for the `cmsghdr` at its start
+    let mut control = [0u64; 8];
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = std::mem::size_of_val(&control) as _;
+
+    if unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, 0) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let cmsg = unsafe { libc::CMSG_FIRSTHDR(&message) };
+    if cmsg.is_null() || unsafe { (*cmsg).cmsg_type } != libc::SCM_RIGHTS {
+        return Err(eyre::eyre!("fusermount didn't send back the FUSE device"));
+    }
+    let fd = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int) };
+
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+extern "C" fn handle_stop(_signal: libc::c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+fn catch_stop_signals() {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_stop as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // Without SA_RESTART, so that waiting for a request is interrupted
+        action.sa_flags = 0;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+        libc::sigaction(libc::SIGTERM, &action, std::ptr::null_mut());
+    }
+}
+
+fn init(body: &[u8]) -> Result<Vec<u8>, i32> {
+    let major = u32_at(body, 0)?;
+    let max_readahead = u32_at(body, 8)?;
+    let flags = u32_at(body, 12)?;
+    if major != KERNEL_VERSION {
+        return Err(libc::EPROTO);
+    }
+
+    let mut out = Vec::with_capacity(64);
+    for x in [
+        KERNEL_VERSION,
+        KERNEL_MINOR_VERSION,
+        max_readahead,
+        flags & (INIT_ASYNC_READ | INIT_BIG_WRITES | INIT_AUTO_INVAL_DATA),
+    ] {
+        out.extend_from_slice(&x.to_ne_bytes());
+    }
+    // max_background and congestion_threshold, which only matter for async requests
+    out.extend_from_slice(&16u16.to_ne_bytes());
+    out.extend_from_slice(&12u16.to_ne_bytes());
+    out.extend_from_slice(&MAX_WRITE.to_ne_bytes());
+    // time_gran, timestamps have nanosecond precision
+    out.extend_from_slice(&1u32.to_ne_bytes());
+    // max_pages and the unused fields
+    out.resize(64, 0);
+    Ok(out)
+}
+
+fn setattr(fs: &mut impl Filesystem, ino: u64, body: &[u8]) -> Result<Attr, i32> {
+    let valid = u32_at(body, 0)?;
+    let time = |flag, now_flag, offset| -> Result<Option<SystemTime>, i32> {
+        Ok(if valid & now_flag != 0 {
+            Some(SystemTime::now())
+        } else if valid & flag != 0 {
+            let secs = u64_at(body, offset)?;
+            let nsecs = u32_at(body, offset + 24)?;
+            Some(UNIX_EPOCH + Duration::new(secs, nsecs))
+        } else {
+            None
+        })
+    };
+    let field = |flag, offset| -> Result<Option<u32>, i32> {
+        (valid & flag != 0)
+            .then(|| u32_at(body, offset))
+            .transpose()
+    };
+
+    let changes = SetAttr {
+        mode: field(SETATTR_MODE, 68)?,
+        uid: field(SETATTR_UID, 76)?,
+        gid: field(SETATTR_GID, 80)?,
+        size: (valid & SETATTR_SIZE != 0)
+            .then(|| u64_at(body, 16))
+            .transpose()?,
+        atime: time(SETATTR_ATIME, SETATTR_ATIME_NOW, 32)?,
+        mtime: time(SETATTR_MTIME, SETATTR_MTIME_NOW, 40)?,
+    };
+    let fh = (valid & SETATTR_FH != 0)
+        .then(|| u64_at(body, 8))
+        .transpose()?;
+    fs.setattr(ino, fh, &changes)
+}
+
+fn read(fs: &mut impl Filesystem, body: &[u8]) -> Result<Vec<u8>, i32> {
+    let fh = u64_at(body, 0)?;
+    let offset = u64_at(body, 8)?;
+    let size = u32_at(body, 16)?;
+    fs.read(fh, offset, size)
+}
+
+fn write(fs: &mut impl Filesystem, body: &[u8]) -> Result<Vec<u8>, i32> {
+    let fh = u64_at(body, 0)?;
+    let offset = u64_at(body, 8)?;
+    let size = u32_at(body, 16)? as usize;
+    let data = body
+        .get(WRITE_IN_LEN..WRITE_IN_LEN + size)
+        .ok_or(libc::EINVAL)?;
+    let written = fs.write(fh, offset, data)?;
+
+    let mut out = written.to_ne_bytes().to_vec();
+    out.extend_from_slice(&0u32.to_ne_bytes());
+    Ok(out)
+}
+
+fn entry_out(attr: &Attr) -> Vec<u8> {
+    let mut out = Vec::with_capacity(128);
+    // The inode and its generation, which is always 0 since inodes aren't reused
+    out.extend_from_slice(&attr.ino.to_ne_bytes());
+    out.extend_from_slice(&0u64.to_ne_bytes());
+    // How long the name and the attributes are valid for
+    out.extend_from_slice(&TIMEOUT.as_secs().to_ne_bytes());
+    out.extend_from_slice(&TIMEOUT.as_secs().to_ne_bytes());
+    out.extend_from_slice(&TIMEOUT.subsec_nanos().to_ne_bytes());
+    out.extend_from_slice(&TIMEOUT.subsec_nanos().to_ne_bytes());
+    attr.write_to(&mut out);
+    out
+}
+
+fn attr_out(attr: &Attr) -> Vec<u8> {
+    let mut out = Vec::with_capacity(104);
+    out.extend_from_slice(&TIMEOUT.as_secs().to_ne_bytes());
+    out.extend_from_slice(&TIMEOUT.subsec_nanos().to_ne_bytes());
+    out.extend_from_slice(&0u32.to_ne_bytes());
+    attr.write_to(&mut out);
+    out
+}
+
+fn open_out(fh: u64) -> Vec<u8> {
+    let mut out = fh.to_ne_bytes().to_vec();
+    // open_flags and padding
+    out.extend_from_slice(&0u64.to_ne_bytes());
+    out
+}
+
+fn statfs_out(stat: &libc::statvfs) -> Vec<u8> {
+    let mut out = Vec::with_capacity(80);
+    for x in [
+        stat.f_blocks,
+        stat.f_bfree,
+        stat.f_bavail,
+        stat.f_files,
+        stat.f_ffree,
+    ] {
+        out.extend_from_slice(&x.to_ne_bytes());
+    }
+    for x in [stat.f_bsize, stat.f_namemax, stat.f_frsize] {
+        out.extend_from_slice(&(x as u32).to_ne_bytes());
+    }
+    // padding and spare fields
+    out.resize(80, 0);
+    out
+}
+
+/// Encode as many of the entries after the offset as fit in `size` bytes. The offset of an
+/// entry is the index of the entry after it, which the kernel passes back to continue from.
+fn encode_dir_entries(entries: &[DirEntry], offset: u64, size: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (index, entry) in entries.iter().enumerate().skip(offset as usize) {
+        let len = DIRENT_LEN + entry.name.len();
+        let padded_len = (len + 7) & !7;
+        if out.len() + padded_len > size {
+            break;
+        }
+
+        let kind = if entry.is_dir {
+            libc::DT_DIR
+        } else {
+            libc::DT_REG
+        };
+        out.extend_from_slice(&entry.ino.to_ne_bytes());
+        out.extend_from_slice(&(index as u64 + 1).to_ne_bytes());
+        out.extend_from_slice(&(entry.name.len() as u32).to_ne_bytes());
+        out.extend_from_slice(&u32::from(kind).to_ne_bytes());
+        out.extend_from_slice(entry.name.as_bytes());
+        out.resize(out.len() + padded_len - len, 0);
+    }
+    out
+}
+
+/// The name at the start of the body, which ends with a nul
+fn name(body: &[u8]) -> Result<&str, i32> {
+    let name = body.split(|x| *x == 0).next().unwrap_or(body);
+    // Every name in the layout is valid UTF-8
+    std::str::from_utf8(name).map_err(|_| libc::ENOENT)
+}
+
+fn timestamp(time: SystemTime) -> (u64, u32) {
+    time.duration_since(UNIX_EPOCH)
+        .map(|x| (x.as_secs(), x.subsec_nanos()))
+        .unwrap_or((0, 0))
+}
+
+fn u32_at(body: &[u8], offset: usize) -> Result<u32, i32> {
+    body.get(offset..offset + 4)
+        .map(|x| u32::from_ne_bytes(x.try_into().expect("Slice to be 4 bytes")))
+        .ok_or(libc::EINVAL)
+}
+
+fn u64_at(body: &[u8], offset: usize) -> Result<u64, i32> {
+    body.get(offset..offset + 8)
+        .map(|x| u64::from_ne_bytes(x.try_into().expect("Slice to be 8 bytes")))
+        .ok_or(libc::EINVAL)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode_dir_entries, DirEntry, DIRENT_LEN};
+
+    #[test]
+    fn test_encode_dir_entries() {
+        let entries = ["a", "story-0ad415fd.epub", "c"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| DirEntry {
+                ino: i as u64 + 2,
+                name: name.to_string(),
+                is_dir: i == 0,
+            })
+            .collect::<Vec<_>>();
+
+        // Entries are padded to 8 bytes
+        let all = encode_dir_entries(&entries, 0, 4096);
+        assert_eq!(all.len(), 32 + 48 + 32);
+        assert_eq!(&all[DIRENT_LEN..DIRENT_LEN + 1], b"a");
+        // The offset of an entry is where to continue after it
+        assert_eq!(all[8], 1);
+
+        // Only whole entries that fit are included
+        let first = encode_dir_entries(&entries, 0, 79);
+        assert_eq!(first.len(), 32);
+
+        let rest = encode_dir_entries(&entries, 1, 4096);
+        assert_eq!(rest, all[32..]);
+        assert!(encode_dir_entries(&entries, 3, 4096).is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+//! Tags containing `/` or `:` form a hierarchy, where `topic/ml/transformers` is a child of
+//! `topic/ml`, which is a child of `topic`. Likewise `genre:fantasy` is a child of `genre`.
+//!
+//! Only the tags that were actually given are stored, but a file counts as having every ancestor
+//! of its tags when it is searched for, so `tagg find topic` finds files tagged
+//! `topic/ml/transformers`.
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::storage::FileInfo;
+
+/// The characters which separate a tag from its parent
+pub const SEPARATORS: [char; 2] = ['/', ':'];
+
+/// The tag followed by each of its ancestors, closest first.
+/// `topic/ml/transformers` gives `topic/ml/transformers`, `topic/ml`, `topic`.
+pub fn tag_and_ancestors(tag: &str) -> impl Iterator<Item = &str> {
+    let ends = tag
+        .char_indices()
+        .filter(|(_, c)| SEPARATORS.contains(c))
+        .map(|(i, _)| i)
+        // An empty parent, like for `/tmp`, isn't really a tag
+        .filter(|i| *i > 0)
+        .collect::<Vec<_>>();
+    std::iter::once(tag).chain(ends.into_iter().rev().map(|end| &tag[..end]))
+}
+
+/// The parent of the tag, if it has one
+pub fn parent(tag: &str) -> Option<&str> {
+    tag_and_ancestors(tag).nth(1)
+}
+
+/// A tag in the hierarchy of the tags used on some files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagNode {
+    pub tag: String,
+    /// The number of files with this tag or one of its descendants
+    pub count: usize,
+    pub children: Vec<TagNode>,
+}
+impl TagNode {
+    /// The last part of the tag, with the separator before it, like `/transformers` for
+    /// `topic/ml/transformers`.
+    /// Tags without a parent are shown in full.
+    pub fn label(&self) -> &str {
+        match parent(&self.tag) {
+            Some(parent) => &self.tag[parent.len()..],
+            None => &self.tag,
+        }
+    }
+}
+
+/// Build the hierarchy of the tags on the files, with every level sorted alphabetically
+pub fn tag_tree(files: &[FileInfo]) -> Vec<TagNode> {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for file in files {
+        // A file counts once for each node, even if it has several tags under it
+        let tags: HashSet<&str> = file
+            .tags
+            .iter()
+            .flat_map(|tag| tag_and_ancestors(tag))
+            .collect();
+        for tag in tags {
+            *counts.entry(tag).or_default() += 1;
+        }
+    }
+
+    let mut children: BTreeMap<Option<&str>, Vec<&str>> = BTreeMap::new();
+    for tag in counts.keys() {
+        children.entry(parent(tag)).or_default().push(tag);
+    }
+
+    fn build(
+        parent: Option<&str>,
+        counts: &BTreeMap<&str, usize>,
+        children: &BTreeMap<Option<&str>, Vec<&str>>,
+    ) -> Vec<TagNode> {
+        children
+            .get(&parent)
+            .map(|tags| {
+                tags.iter()
+                    .map(|tag| TagNode {
+                        tag: tag.to_string(),
+                        count: counts[tag],
+                        children: build(Some(tag), counts, children),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+    build(None, &counts, &children)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::FileInfo;
+
+    use super::{tag_and_ancestors, tag_tree};
+
+    #[test]
+    fn test_ancestors() {
+        let ancestors: Vec<&str> = tag_and_ancestors("topic/ml/transformers").collect();
+        assert_eq!(ancestors, ["topic/ml/transformers", "topic/ml", "topic"]);
+        let ancestors: Vec<&str> = tag_and_ancestors("genre:fantasy").collect();
+        assert_eq!(ancestors, ["genre:fantasy", "genre"]);
+        let ancestors: Vec<&str> = tag_and_ancestors("/odd").collect();
+        assert_eq!(ancestors, ["/odd"]);
+    }
+
+    #[test]
+    fn test_tag_tree() {
+        let file = |tags: &[&str]| FileInfo {
+            tags: tags.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        };
+        let files = [
+            file(&["topic/ml/transformers", "topic/ml/rnn", "paper"]),
+            file(&["topic/ml", "genre:fantasy"]),
+        ];
+
+        let tree = tag_tree(&files);
+        let roots: Vec<(&str, usize)> = tree.iter().map(|x| (x.label(), x.count)).collect();
+        assert_eq!(roots, [("genre", 1), ("paper", 1), ("topic", 2)]);
+
+        let ml = &tree[2].children[0];
+        assert_eq!(
+            (ml.tag.as_str(), ml.label(), ml.count),
+            ("topic/ml", "/ml", 2)
+        );
+        let leaves: Vec<(&str, usize)> = ml.children.iter().map(|x| (x.label(), x.count)).collect();
+        assert_eq!(leaves, [("/rnn", 1), ("/transformers", 1)]);
+    }
+}
@@ -0,0 +1,266 @@
+//! Implication rules say that a tag means a file should also have another tag, like `novel`
+//! implying `book`. Rules chain, so if `book` implies `text` then `novel` implies both.
+//!
+//! Depending on the `implied-tags` config option the implied tags are either added to files when
+//! they're committed or tagged, or never stored and only taken into account by `tagg find`.
+//! Rules apply to the descendants of a tag as well (see [`crate::hierarchy`]), so a rule for
+//! `genre` applies to files tagged `genre:fantasy`.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::hierarchy::tag_and_ancestors;
+
+/// When the tags implied by the rules are applied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImpliedTags {
+    /// Added to files when they're committed or have tags added
+    #[default]
+    Stored,
+    /// Not stored, but files are found by `tagg find` as if they had them
+    Virtual,
+}
+
+/// The rules, from a tag to the tags it directly implies
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Implications {
+    rules: BTreeMap<String, BTreeSet<String>>,
+}
+impl Implications {
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Each rule as the tag and the tag it implies, sorted
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.rules.iter().flat_map(|(tag, implied)| {
+            implied
+                .iter()
+                .map(move |implied| (tag.as_str(), implied.as_str()))
+        })
+    }
+
+    /// Add the rule that `tag` implies `implied`.
+    /// Fails if `implied` already implies `tag` or a descendant of it, since then neither could be
+    /// removed from a file without the other bringing it back.
+    pub fn add(&mut self, tag: &str, implied: &str) -> eyre::Result<()> {
+        if tag_and_ancestors(implied).any(|x| x == tag) {
+            return Err(eyre::eyre!(
+                "{:?} can't imply itself or a tag under it",
+                tag
+            ));
+        }
+        if let Some(chain) = self.chain(implied, tag) {
+            return Err(eyre::eyre!(
+                "{:?} implying {:?} would make a cycle, since {} already",
+                tag,
+                implied,
+                chain
+                    .iter()
+                    .map(|x| format!("{:?}", x))
+                    .collect::<Vec<_>>()
+                    .join(" implies ")
+            ));
+        }
+
+        self.rules
+            .entry(tag.to_string())
+            .or_default()
+            .insert(implied.to_string());
+        Ok(())
+    }
+
+    /// Remove the rule that `tag` implies `implied`, returning whether there was one
+    pub fn remove(&mut self, tag: &str, implied: &str) -> bool {
+        let Some(rules) = self.rules.get_mut(tag) else {
+            return false;
+        };
+        let removed = rules.remove(implied);
+        if rules.is_empty() {
+            self.rules.remove(tag);
+        }
+        removed
+    }
+
+    /// Replace the tags in the rules, when tags are renamed by `rename`.
+    /// Rules which would end up with a tag implying itself are removed.  
+    /// Returns the number of rules which were changed or removed, or fails without changing
+    /// anything if the renamed rules would form a cycle.
+    pub fn rename_tags(&mut self, rename: impl Fn(&str) -> Option<String>) -> eyre::Result<usize> {
+        let mut changed = 0;
+        let mut renamed = Implications::default();
+        for (tag, implied) in self.iter() {
+            let (new_tag, new_implied) = (rename(tag), rename(implied));
+            if new_tag.is_none() && new_implied.is_none() {
+                renamed.add(tag, implied)?;
+                continue;
+            }
+
+            changed += 1;
+            let tag = new_tag.as_deref().unwrap_or(tag);
+            let implied = new_implied.as_deref().unwrap_or(implied);
+            if tag != implied {
+                renamed.add(tag, implied)?;
+            }
+        }
+        *self = renamed;
+        Ok(changed)
+    }
+
+    /// The tags which the tag implies, directly or through other rules
+    pub fn implied_by(&self, tag: &str) -> BTreeSet<&str> {
+        let mut implied = BTreeSet::new();
+        let mut stack: Vec<&str> = tag_and_ancestors(tag).collect();
+        while let Some(tag) = stack.pop() {
+            for next in self.rules.get(tag).into_iter().flatten() {
+                if implied.insert(next.as_str()) {
+                    stack.extend(tag_and_ancestors(next));
+                }
+            }
+        }
+        implied
+    }
+
+    /// The tags which imply the tag or one of its descendants, directly or through other rules
+    pub fn implying(&self, tag: &str) -> BTreeSet<&str> {
+        self.implying_matching(|implied| tag_and_ancestors(implied).any(|x| x == tag))
+    }
+
+    /// The tags which imply a tag that `matches` accepts, directly or through other rules
+    pub fn implying_matching(&self, matches: impl Fn(&str) -> bool) -> BTreeSet<&str> {
+        let mut implying = BTreeSet::new();
+        let mut stack = Vec::new();
+        for (from, implied) in self.rules.iter() {
+            if implied.iter().any(|x| matches(x)) && implying.insert(from.as_str()) {
+                stack.push(from.as_str());
+            }
+        }
+        while let Some(tag) = stack.pop() {
+            for (from, implied) in self.rules.iter() {
+                let implies = implied
+                    .iter()
+                    .any(|x| tag_and_ancestors(x).any(|x| x == tag));
+                if implies && implying.insert(from.as_str()) {
+                    stack.push(from);
+                }
+            }
+        }
+        implying
+    }
+
+    /// Add the tags implied by the tags, keeping them sorted and without duplicates.
+    /// Returns the tags which were added.
+    pub fn apply(&self, tags: &mut Vec<String>) -> Vec<String> {
+        let mut added = Vec::new();
+        for tag in tags.iter() {
+            for implied in self.implied_by(tag) {
+                if !tags.iter().any(|x| x == implied) && !added.iter().any(|x| x == implied) {
+                    added.push(implied.to_string());
+                }
+            }
+        }
+
+        if !added.is_empty() {
+            tags.extend(added.iter().cloned());
+            tags.sort();
+            tags.dedup();
+        }
+        added
+    }
+
+    /// The chain of rules from `from` to `to` or a descendant of it, if `from` implies it.
+    /// Like [`Implications::implied_by`], the rules for the ancestors of each tag are followed.
+    fn chain<'a>(&'a self, from: &'a str, to: &str) -> Option<Vec<&'a str>> {
+        let mut visited = BTreeSet::new();
+        let mut stack = vec![vec![from]];
+        while let Some(chain) = stack.pop() {
+            let last = *chain.last().expect("Chains to not be empty");
+            if tag_and_ancestors(last).any(|x| x == to) {
+                return Some(chain);
+            }
+            if !visited.insert(last) {
+                continue;
+            }
+
+            let rules = tag_and_ancestors(last).filter_map(|x| self.rules.get(x));
+            for next in rules.flatten() {
+                let mut chain = chain.clone();
+                chain.push(next);
+                stack.push(chain);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Implications;
+
+    #[test]
+    fn test_implications() {
+        let mut implications = Implications::default();
+        implications.add("novel", "book").unwrap();
+        implications.add("book", "text").unwrap();
+        implications.add("arxiv", "paper").unwrap();
+        implications.add("genre", "fiction").unwrap();
+
+        assert!(implications.add("text", "novel").is_err());
+        assert!(implications.add("book", "book").is_err());
+        // Not a cycle, just two paths to the same tag
+        implications.add("novel", "text").unwrap();
+
+        // Rules apply to the descendants of a tag, which can make cycles too
+        assert!(implications.add("genre", "genre:fantasy").is_err());
+        assert!(implications.add("fiction", "genre:scifi").is_err());
+        implications.add("genre:fantasy", "fantasy").unwrap();
+        assert!(implications.remove("genre:fantasy", "fantasy"));
+
+        let mut tags = vec!["novel".to_string(), "genre:fantasy".to_string()];
+        let added = implications.apply(&mut tags);
+        assert_eq!(added, ["book", "text", "fiction"]);
+        assert_eq!(tags, ["book", "fiction", "genre:fantasy", "novel", "text"]);
+        assert!(implications.apply(&mut tags).is_empty());
+
+        assert_eq!(
+            implications
+                .implying("text")
+                .into_iter()
+                .collect::<Vec<_>>(),
+            ["book", "novel"]
+        );
+
+        assert!(implications.remove("novel", "text"));
+        assert!(!implications.remove("novel", "text"));
+        assert_eq!(implications.iter().count(), 4);
+    }
+
+    #[test]
+    fn test_rename_tags() {
+        let mut implications = Implications::default();
+        implications.add("novel", "book").unwrap();
+        implications.add("book", "text").unwrap();
+        implications.add("arxiv", "paper").unwrap();
+
+        // Merging `novel` into `book` leaves `book` implying itself, which is dropped
+        let changed = implications
+            .rename_tags(|tag| (tag == "novel").then(|| "book".to_string()))
+            .unwrap();
+        assert_eq!(changed, 1);
+        assert_eq!(
+            implications.iter().collect::<Vec<_>>(),
+            [("arxiv", "paper"), ("book", "text")]
+        );
+
+        // Merging `text` and `paper` would make `book` and `text` imply each other
+        let mut cyclic = implications.clone();
+        cyclic.add("paper", "book").unwrap();
+        assert!(cyclic
+            .rename_tags(|tag| (tag == "paper").then(|| "text".to_string()))
+            .is_err());
+        assert_eq!(cyclic.iter().count(), 3);
+    }
+}
@@ -0,0 +1,704 @@
+//! Every change to the catalogue is recorded in an append-only journal (`journal.toml` next to
+//! the state file), so that it can be looked back on with `tagg log` and reverted with
+//! `tagg undo`.
+//!
+//! Each entry holds the information of every file it touched from before and after the change.
+//! Undoing an entry doesn't remove it from the journal, instead an `undo` entry pointing at it is
+//! appended, and the same for redoing. Replaying the journal from the start tells which entries
+//! can currently be undone or redone.
+//!
+//! Changes to the registration-area, and to the aliases and implication rules, are recorded as
+//! they were from before and after the command.
+//! Restoring the state file from a backup and migrating between storage-backends can't be
+//! undone, so they're recorded as a barrier which undo and redo don't go past.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use toml::value::Datetime;
+
+use crate::{
+    aliases::Aliases,
+    implications::Implications,
+    query::Query,
+    state::{AddedFile, State},
+    storage::{FileInfo, StorageBackend},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EntryKind {
+    /// A command changed the catalogue
+    Change,
+    /// The entry `target` was undone
+    Undo,
+    /// The entry `target` was redone
+    Redo,
+    /// A command changed the catalogue in a way that can't be undone, so none of the entries
+    /// before it can be undone or redone either
+    Barrier,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct JournalEntry {
+    pub id: u64,
+    pub time: Datetime,
+    pub kind: EntryKind,
+    /// The command line that made the change
+    pub command: String,
+    /// The entry that was undone or redone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<FileChange>,
+
+    /// The files that were committed from the registration-area
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub commits: Vec<CommittedFile>,
+
+    /// The change to the registration-area, if it was changed without committing files from it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub staged: Option<StagedChange>,
+
+    /// The change to the aliases and implication rules, if they were changed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rules: Option<RulesChange>,
+}
+
+/// The files in the registration-area from before and after a command changed it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct StagedChange {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub before: Vec<AddedFile>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub after: Vec<AddedFile>,
+}
+
+/// A change to a single file's information
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FileChange {
+    pub filename: String,
+    /// `None` if the file was added to the catalogue
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<FileInfo>,
+    /// `None` if the file was removed from the catalogue
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<FileInfo>,
+}
+impl FileChange {
+    /// Make the storage match the `before` side of the change if `undo`, otherwise the `after`
+    /// side.
+    /// Files that still exist only have their tags, comments and original filename changed,
+    /// since the rest is information about the file's contents that may have been refreshed
+    /// since.
+    pub fn apply(&self, storage: &mut dyn StorageBackend, undo: bool) -> eyre::Result<()> {
+        let target = if undo { &self.before } else { &self.after };
+        let current = storage
+            .find_from_prefix(&self.filename)?
+            .into_iter()
+            .find(|x| x.filename == self.filename);
+
+        match (current, target) {
+            (Some(_), None) => {
+                storage.delete(&self.filename)?;
+            }
+            (None, Some(target)) => storage.insert(target.clone())?,
+            (Some(mut current), Some(target)) => {
+                current.original_filename = target.original_filename.clone();
+                current.tags = target.tags.clone();
+                current.comments = target.comments.clone();
+                storage.update(&current)?;
+            }
+            (None, None) => {}
+        }
+
+        Ok(())
+    }
+
+    /// The differences in the tags, comments, and original filename between both sides of the
+    /// change.  
+    /// A side which is `None` counts as having no tags or comments.
+    pub fn differences(&self) -> Vec<Difference> {
+        let empty = FileInfo::default();
+        let before = self.before.as_ref().unwrap_or(&empty);
+        let after = self.after.as_ref().unwrap_or(&empty);
+
+        let mut differences = Vec::new();
+        if self.before.is_some()
+            && self.after.is_some()
+            && before.original_filename != after.original_filename
+        {
+            differences.push(Difference::OriginalFilename {
+                before: before.original_filename.clone(),
+                after: after.original_filename.clone(),
+            });
+        }
+
+        for tag in after.tags.iter() {
+            if !before.tags.contains(tag) {
+                differences.push(Difference::AddedTag(tag.clone()));
+            }
+        }
+        for tag in before.tags.iter() {
+            if !after.tags.contains(tag) {
+                differences.push(Difference::RemovedTag(tag.clone()));
+            }
+        }
+
+        let mut titles: Vec<&String> = before
+            .comments
+            .keys()
+            .chain(after.comments.keys())
+            .collect();
+        titles.sort();
+        titles.dedup();
+        for title in titles {
+            let (old, new) = (before.comments.get(title), after.comments.get(title));
+            if old != new {
+                differences.push(Difference::Comment {
+                    title: title.clone(),
+                    before: old.cloned(),
+                    after: new.cloned(),
+                });
+            }
+        }
+
+        differences
+    }
+}
+
+/// A single difference in a file's information made by a [`FileChange`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    AddedTag(String),
+    RemovedTag(String),
+    /// A comment was set, changed, or removed
+    Comment {
+        title: String,
+        before: Option<String>,
+        after: Option<String>,
+    },
+    OriginalFilename {
+        before: Option<String>,
+        after: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CommittedFile {
+    /// The id of the file in storage which has the committed contents
+    pub filename: String,
+    /// Whether the original file was moved to the trash, rather than left where it was
+    pub trashed: bool,
+    /// Whether the file was merged into an identical file already in storage, rather than
+    /// being stored itself
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub merged: bool,
+    /// The registration-area entry that was committed
+    pub added: AddedFile,
+}
+
+/// The aliases and implication rules
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TagRules {
+    #[serde(default, skip_serializing_if = "Aliases::is_empty")]
+    pub aliases: Aliases,
+    #[serde(default, skip_serializing_if = "Implications::is_empty")]
+    pub implications: Implications,
+}
+impl TagRules {
+    pub fn of(state: &State) -> TagRules {
+        TagRules {
+            aliases: state.aliases.clone(),
+            implications: state.implications.clone(),
+        }
+    }
+
+    /// Replace the state's aliases and implication rules with these
+    pub fn restore(&self, state: &mut State) {
+        state.aliases = self.aliases.clone();
+        state.implications = self.implications.clone();
+    }
+}
+
+/// The aliases and implication rules from before and after a command changed them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RulesChange {
+    pub before: TagRules,
+    pub after: TagRules,
+}
+
+/// The changes made by the current command, which haven't been written to the journal yet
+#[derive(Debug, Clone, Default)]
+pub struct PendingEntry {
+    pub changes: Vec<FileChange>,
+    pub commits: Vec<CommittedFile>,
+    pub staged: Option<StagedChange>,
+    pub rules: Option<RulesChange>,
+}
+impl PendingEntry {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+            && self.commits.is_empty()
+            && self.staged.is_none()
+            && self.rules.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Journal {
+    #[serde(default)]
+    pub entry: Vec<JournalEntry>,
+}
+impl Journal {
+    /// Load the journal, which is empty if the file doesn't exist yet
+    pub fn load_from(path: &Path) -> eyre::Result<Journal> {
+        if !path.exists() {
+            return Ok(Journal::default());
+        }
+
+        let text = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&text)?)
+    }
+
+    /// Add the entry to the end of the journal file, without rewriting the rest of it
+    pub fn append_to(path: &Path, entry: JournalEntry) -> eyre::Result<()> {
+        // Each entry is an `[[entry]]` table, so appending one keeps the file valid
+        let text = toml::to_string(&Journal { entry: vec![entry] })?;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        writeln!(file, "{}", text)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    pub fn next_id(&self) -> u64 {
+        self.entry.last().map(|x| x.id + 1).unwrap_or(1)
+    }
+
+    /// The id of the next entry to append to the journal file.  
+    /// Only the end of the file is read, since entries are only ever appended and so the last
+    /// `[[entry]]` header starts the entry with the highest id.
+    pub fn next_id_in(path: &Path) -> eyre::Result<u64> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(1),
+            Err(err) => return Err(err.into()),
+        };
+        let len = file.metadata()?.len();
+
+        let mut chunk = 4096;
+        loop {
+            let start = len.saturating_sub(chunk);
+            file.seek(SeekFrom::Start(start))?;
+            let mut buf = Vec::new();
+            file.read_to_end(&mut buf)?;
+            let text = String::from_utf8_lossy(&buf);
+
+            let header = text
+                .rfind("\n[[entry]]\n")
+                .map(|x| x + 1)
+                .or_else(|| (start == 0 && text.starts_with("[[entry]]\n")).then_some(0));
+            if let Some(header) = header {
+                return match toml::from_str::<Journal>(&text[header..]) {
+                    Ok(last) => Ok(last.next_id()),
+                    // Not laid out the way tagg writes it, so go through all of it
+                    Err(_) => Ok(Journal::load_from(path)?.next_id()),
+                };
+            }
+            if start == 0 {
+                return Ok(1);
+            }
+            chunk *= 4;
+        }
+    }
+
+    /// The most recent entry which can't be undone
+    pub fn last_barrier(&self) -> Option<&JournalEntry> {
+        self.entry
+            .iter()
+            .rev()
+            .find(|x| x.kind == EntryKind::Barrier)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&JournalEntry> {
+        self.entry.iter().find(|x| x.id == id)
+    }
+
+    /// The entries which changed the file, along with the undos and redos of them, in the order
+    /// they happened
+    pub fn history(&self, filename: &str) -> Vec<&JournalEntry> {
+        let mut touched = Vec::new();
+        let mut history = Vec::new();
+        for entry in self.entry.iter() {
+            let relevant = match entry.kind {
+                EntryKind::Change => {
+                    entry.changes.iter().any(|x| x.filename == filename)
+                        || entry.commits.iter().any(|x| x.filename == filename)
+                }
+                EntryKind::Undo | EntryKind::Redo => {
+                    entry.target.is_some_and(|x| touched.contains(&x))
+                }
+                EntryKind::Barrier => false,
+            };
+            if relevant {
+                touched.push(entry.id);
+                history.push(entry);
+            }
+        }
+        history
+    }
+
+    /// The ids of the entries which can currently be undone, and those which can be redone.
+    /// The next one to undo or redo is last.
+    pub fn stacks(&self) -> (Vec<u64>, Vec<u64>) {
+        let mut done = Vec::new();
+        let mut undone = Vec::new();
+        for entry in self.entry.iter() {
+            match entry.kind {
+                EntryKind::Change => {
+                    done.push(entry.id);
+                    // Like in an editor, a new change means the undone ones can't be redone
+                    undone.clear();
+                }
+                EntryKind::Undo => {
+                    if let Some(index) = done.iter().rposition(|x| Some(*x) == entry.target) {
+                        undone.push(done.remove(index));
+                    }
+                }
+                EntryKind::Redo => {
+                    if let Some(index) = undone.iter().rposition(|x| Some(*x) == entry.target) {
+                        done.push(undone.remove(index));
+                    }
+                }
+                EntryKind::Barrier => {
+                    done.clear();
+                    undone.clear();
+                }
+            }
+        }
+        (done, undone)
+    }
+}
+
+/// A [`StorageBackend`] which remembers every change made through it, so that they can be
+/// written to the journal.  
+/// `modified` is set whenever anything is written, including changes that aren't worth
+/// recording in the journal.
+pub struct RecordingStorage<'a> {
+    storage: &'a mut dyn StorageBackend,
+    changes: &'a mut Vec<FileChange>,
+    modified: &'a mut bool,
+}
+impl<'a> RecordingStorage<'a> {
+    pub fn new(
+        storage: &'a mut dyn StorageBackend,
+        changes: &'a mut Vec<FileChange>,
+        modified: &'a mut bool,
+    ) -> RecordingStorage<'a> {
+        RecordingStorage {
+            storage,
+            changes,
+            modified,
+        }
+    }
+
+    fn get(&self, filename: &str) -> eyre::Result<Option<FileInfo>> {
+        Ok(self
+            .storage
+            .find_from_prefix(filename)?
+            .into_iter()
+            .find(|x| x.filename == filename))
+    }
+}
+impl StorageBackend for RecordingStorage<'_> {
+    fn find_from_prefix(&self, prefix: &str) -> eyre::Result<Vec<FileInfo>> {
+        self.storage.find_from_prefix(prefix)
+    }
+
+    fn find_by_hash(&self, hash: &str) -> eyre::Result<Vec<FileInfo>> {
+        self.storage.find_by_hash(hash)
+    }
+
+    fn find(&self, query: &Query) -> eyre::Result<Vec<FileInfo>> {
+        self.storage.find(query)
+    }
+
+    fn all(&self) -> eyre::Result<Vec<FileInfo>> {
+        self.storage.all()
+    }
+
+    fn count(&self) -> eyre::Result<usize> {
+        self.storage.count()
+    }
+
+    fn insert(&mut self, file: FileInfo) -> eyre::Result<()> {
+        self.insert_all(vec![file])
+    }
+
+    fn insert_all(&mut self, files: Vec<FileInfo>) -> eyre::Result<()> {
+        let changes: Vec<FileChange> = files
+            .iter()
+            .map(|file| FileChange {
+                filename: file.filename.clone(),
+                before: None,
+                after: Some(file.clone()),
+            })
+            .collect();
+        *self.modified = true;
+        self.storage.insert_all(files)?;
+        self.changes.extend(changes);
+        Ok(())
+    }
+
+    fn update(&mut self, file: &FileInfo) -> eyre::Result<()> {
+        self.update_all(std::slice::from_ref(file))
+    }
+
+    fn update_all(&mut self, files: &[FileInfo]) -> eyre::Result<()> {
+        let mut changes = Vec::new();
+        for file in files {
+            let before = self.get(&file.filename)?;
+            // Refreshing the information about the file's contents isn't something to undo
+            let described_same = before.as_ref().is_some_and(|before| {
+                before.original_filename == file.original_filename
+                    && before.tags == file.tags
+                    && before.comments == file.comments
+            });
+            if !described_same {
+                changes.push(FileChange {
+                    filename: file.filename.clone(),
+                    before,
+                    after: Some(file.clone()),
+                });
+            }
+        }
+        *self.modified = true;
+        self.storage.update_all(files)?;
+        self.changes.extend(changes);
+        Ok(())
+    }
+
+    fn delete(&mut self, filename: &str) -> eyre::Result<bool> {
+        let before = self.get(filename)?;
+        let deleted = self.storage.delete(filename)?;
+        if deleted {
+            *self.modified = true;
+            self.changes.push(FileChange {
+                filename: filename.to_string(),
+                before,
+                after: None,
+            });
+        }
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use crate::{
+        state::AddedFile,
+        storage::{FileInfo, Storage, StorageBackend},
+        util::{unix_to_datetime, TempDir},
+    };
+
+    use super::{
+        Difference, EntryKind, FileChange, Journal, JournalEntry, RecordingStorage, RulesChange,
+        StagedChange, TagRules,
+    };
+
+    fn file(filename: &str, tags: &[&str]) -> FileInfo {
+        FileInfo {
+            filename: filename.to_string(),
+            hash: Some("af13".to_string()),
+            tags: tags.iter().map(|x| x.to_string()).collect(),
+            comments: HashMap::from([("title".to_string(), "Toaster".to_string())]),
+            ..Default::default()
+        }
+    }
+
+    fn entry(id: u64, kind: EntryKind, target: Option<u64>) -> JournalEntry {
+        JournalEntry {
+            id,
+            time: unix_to_datetime(1668000000),
+            kind,
+            command: "tagg add-tags a0 -t kitchen".to_string(),
+            target,
+            changes: Vec::new(),
+            commits: Vec::new(),
+            staged: None,
+            rules: None,
+        }
+    }
+
+    #[test]
+    fn test_record_and_apply() {
+        let mut storage = Storage {
+            files: vec![file("a0.txt", &["kitchen"])],
+        };
+        let mut changes = Vec::new();
+        let mut modified = false;
+        let mut recording = RecordingStorage::new(&mut storage, &mut changes, &mut modified);
+        assert!(recording.find_from_prefix("a0").is_ok());
+        assert!(!*recording.modified);
+
+        let changed = file("a0.txt", &["kitchen", "toaster"]);
+        recording.update(&changed).unwrap();
+        // Updating without a difference, or only refreshing the hash, isn't a change
+        recording.update(&changed).unwrap();
+        let mut refreshed = changed.clone();
+        refreshed.size = Some(20);
+        recording.update(&refreshed).unwrap();
+        recording.insert(file("b1.txt", &[])).unwrap();
+        recording.delete("b1.txt").unwrap();
+        assert!(modified);
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0].before.as_ref().unwrap().tags, ["kitchen"]);
+        assert!(changes[1].before.is_none());
+        assert!(changes[2].after.is_none());
+
+        // The hash being refreshed since shouldn't be reverted by undoing a tag change
+        refreshed.hash = Some("7e2d".to_string());
+        storage.update(&refreshed).unwrap();
+        for change in changes.iter().rev() {
+            change.apply(&mut storage, true).unwrap();
+        }
+        assert_eq!(storage.files.len(), 1);
+        assert_eq!(storage.files[0].tags, ["kitchen"]);
+        assert_eq!(storage.files[0].hash.as_deref(), Some("7e2d"));
+
+        for change in changes.iter() {
+            change.apply(&mut storage, false).unwrap();
+        }
+        assert_eq!(storage.files.len(), 1);
+        assert_eq!(storage.files[0].tags, ["kitchen", "toaster"]);
+    }
+
+    #[test]
+    fn test_differences() {
+        let mut after = file("a0.txt", &["kitchen", "toaster"]);
+        after.comments.insert("desc".to_string(), "Red".to_string());
+        after
+            .comments
+            .insert("title".to_string(), "Red Toaster".to_string());
+        let change = FileChange {
+            filename: "a0.txt".to_string(),
+            before: Some(file("a0.txt", &["kitchen", "bread"])),
+            after: Some(after),
+        };
+
+        assert_eq!(
+            change.differences(),
+            vec![
+                Difference::AddedTag("toaster".to_string()),
+                Difference::RemovedTag("bread".to_string()),
+                Difference::Comment {
+                    title: "desc".to_string(),
+                    before: None,
+                    after: Some("Red".to_string()),
+                },
+                Difference::Comment {
+                    title: "title".to_string(),
+                    before: Some("Toaster".to_string()),
+                    after: Some("Red Toaster".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_journal() {
+        let mut first = entry(1, EntryKind::Change, None);
+        first.changes.push(FileChange {
+            filename: "a0.txt".to_string(),
+            before: Some(file("a0.txt", &[])),
+            after: Some(file("a0.txt", &["kitchen"])),
+        });
+        let mut second = entry(2, EntryKind::Change, None);
+        let mut after = TagRules::default();
+        after.aliases.add("picture", "img").unwrap();
+        after.implications.add("cat", "animal").unwrap();
+        second.rules = Some(RulesChange {
+            before: TagRules::default(),
+            after,
+        });
+        let mut journal = Journal {
+            entry: vec![first, second],
+        };
+
+        let text = toml::to_string(&journal).unwrap();
+        let parsed: Journal = toml::from_str(&text).unwrap();
+        assert_eq!(parsed.entry.len(), 2);
+        assert_eq!(parsed.entry[0].changes, journal.entry[0].changes);
+        assert_eq!(parsed.entry[1].rules, journal.entry[1].rules);
+        assert_eq!(parsed.next_id(), 3);
+
+        assert_eq!(journal.stacks(), (vec![1, 2], vec![]));
+        journal.entry.push(entry(3, EntryKind::Undo, Some(2)));
+        journal.entry.push(entry(4, EntryKind::Undo, Some(1)));
+        assert_eq!(journal.stacks(), (vec![], vec![2, 1]));
+        journal.entry.push(entry(5, EntryKind::Redo, Some(1)));
+        assert_eq!(journal.stacks(), (vec![1], vec![2]));
+        journal.entry.push(entry(6, EntryKind::Change, None));
+        assert_eq!(journal.stacks(), (vec![1, 6], vec![]));
+
+        let history: Vec<u64> = journal.history("a0.txt").iter().map(|x| x.id).collect();
+        assert_eq!(history, [1, 4, 5]);
+
+        // Nothing before a barrier can be undone
+        journal.entry.push(entry(7, EntryKind::Undo, Some(6)));
+        journal.entry.push(entry(8, EntryKind::Barrier, None));
+        assert_eq!(journal.stacks(), (vec![], vec![]));
+        journal.entry.push(entry(9, EntryKind::Change, None));
+        assert_eq!(journal.stacks(), (vec![9], vec![]));
+        assert_eq!(journal.last_barrier().map(|x| x.id), Some(8));
+    }
+
+    #[test]
+    fn test_next_id_in() {
+        let dir = TempDir::new();
+        let path = dir.join("journal.toml");
+        assert_eq!(Journal::next_id_in(&path).unwrap(), 1);
+
+        let added = AddedFile {
+            path: PathBuf::from("/files/toaster.txt"),
+            hash: None,
+            allow_duplicate: false,
+            tags: vec!["kitchen".to_string()],
+            comment: HashMap::new(),
+        };
+        for id in 1..=40 {
+            let mut entry = entry(id, EntryKind::Change, None);
+            entry.changes.push(FileChange {
+                filename: "a0.txt".to_string(),
+                before: Some(file("a0.txt", &[])),
+                after: Some(file("a0.txt", &["kitchen"])),
+            });
+            entry.staged = Some(StagedChange {
+                before: Vec::new(),
+                after: vec![added.clone()],
+            });
+            Journal::append_to(&path, entry).unwrap();
+        }
+        assert_eq!(Journal::next_id_in(&path).unwrap(), 41);
+
+        let journal = Journal::load_from(&path).unwrap();
+        assert_eq!(journal.entry.len(), 40);
+        assert_eq!(journal.entry[39].staged.as_ref().unwrap().after, [added]);
+    }
+}
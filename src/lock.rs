@@ -0,0 +1,113 @@
+//! Multiple tagg processes can run at once (like a script adding files while you tag others), and
+//! each one loads the state, changes it, and saves it again. Without locking, whichever saved
+//! last would silently throw away the other's changes.
+//!
+//! So every tagg process holds an advisory lock on `<state-file>.lock` for as long as it runs.
+//! Commands which only read take a shared lock, so they can run alongside each other, while
+//! commands which modify the state, the registration-area, or the storage take an exclusive one.
+//! The lock is on a separate file since the state file itself is replaced when it is saved.
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use fs2::FileExt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+#[derive(Debug)]
+pub struct StateLock {
+    file: File,
+    mode: LockMode,
+}
+impl StateLock {
+    /// The path of the lock file for the state file
+    pub fn lock_path(state_path: &Path) -> PathBuf {
+        let mut name = state_path.as_os_str().to_owned();
+        name.push(".lock");
+        PathBuf::from(name)
+    }
+
+    /// Lock the state file, waiting up to `timeout` for other tagg processes to finish with it
+    pub fn acquire(
+        state_path: &Path,
+        mode: LockMode,
+        timeout: Duration,
+    ) -> eyre::Result<StateLock> {
+        let path = StateLock::lock_path(state_path);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let start = Instant::now();
+        loop {
+            let result = match mode {
+                LockMode::Shared => FileExt::try_lock_shared(&file),
+                LockMode::Exclusive => FileExt::try_lock_exclusive(&file),
+            };
+            match result {
+                Ok(()) => return Ok(StateLock { file, mode }),
+                Err(err) if err.kind() != fs2::lock_contended_error().kind() => {
+                    return Err(err.into())
+                }
+                Err(_) if start.elapsed() >= timeout => {
+                    return Err(eyre::eyre!(
+                        "Another tagg process is using the state file (locked through {:?}). Try again once it has finished, or use `--lock-timeout <seconds>` to wait for it",
+                        path
+                    ));
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    }
+
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+}
+impl Drop for StateLock {
+    fn drop(&mut self) {
+        // Closing the file would release it anyway, this just makes it explicit
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::util::TempDir;
+
+    use super::{LockMode, StateLock};
+
+    #[test]
+    fn test_lock() {
+        let dir = TempDir::new();
+        let path = dir.join("state.toml");
+        let acquire = |mode| StateLock::acquire(&path, mode, Duration::ZERO);
+
+        let first = acquire(LockMode::Shared).unwrap();
+        let second = acquire(LockMode::Shared).unwrap();
+        assert!(acquire(LockMode::Exclusive).is_err());
+        drop(first);
+        drop(second);
+
+        let writer = acquire(LockMode::Exclusive).unwrap();
+        assert_eq!(writer.mode(), LockMode::Exclusive);
+        assert!(acquire(LockMode::Shared).is_err());
+        assert!(
+            StateLock::acquire(&path, LockMode::Exclusive, Duration::from_millis(250)).is_err()
+        );
+        drop(writer);
+        assert!(acquire(LockMode::Exclusive).is_ok());
+    }
+}
@@ -1,14 +1,35 @@
+pub mod aliases;
 pub mod commands;
 pub mod config;
+#[cfg(all(feature = "mount", target_os = "linux"))]
+pub mod fuse;
+pub mod hierarchy;
+pub mod implications;
+pub mod journal;
+pub mod lock;
+pub mod migrations;
+#[cfg(all(feature = "mount", target_os = "linux"))]
+pub mod mount;
+pub mod namespaces;
+pub mod query;
 pub mod state;
 pub mod storage;
 pub mod tagg;
 pub mod util;
+pub mod vfs;
+pub mod view;
+
+use std::time::Duration;
 
 use clap::{Parser, Subcommand};
+use commands::tags::TagSort;
 use config::Config;
-use state::State;
+use lock::{LockMode, StateLock};
+use state::Staging;
+use storage::StorageBackendKind;
 use tagg::Tagg;
+use util::{datetime_to_unix, now_datetime};
+use view::LinkKind;
 
 #[derive(Debug, Parser)]
 #[command(name = "tagg")]
@@ -16,6 +37,10 @@ use tagg::Tagg;
 struct Cli {
     #[arg(short, long)]
     verbose: bool,
+    /// How many seconds to wait for other tagg processes to finish with the state, rather than
+    /// failing immediately. Overrides the `lock-timeout` config option.
+    #[arg(long, global = true)]
+    lock_timeout: Option<u64>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -35,12 +60,23 @@ enum Commands {
         /// A list of space-separated tags
         #[arg(long, short, num_args = 1..)]
         tags: Vec<String>,
+        /// Accept tags that aren't on any file yet without asking, when `strict-vocabulary` is on
+        #[arg(long)]
+        new_tag: bool,
     },
     /// Drop files from the registration-area
     #[command(arg_required_else_help = true)]
     Drop {
         files: Vec<String>,
     },
+    /// Bring back files that expired out of the registration-area.  
+    /// Brings back all of them if no files are given.
+    RestoreStaged {
+        files: Vec<String>,
+        /// Forget about the expired files rather than bringing them back
+        #[arg(long)]
+        discard: bool,
+    },
     // /// Add a file immediately into the storage, without going into registration
     // #[command(arg_required_else_help = true)]
     // AddQ {
@@ -63,11 +99,44 @@ enum Commands {
         #[arg(long)]
         soft: bool,
     },
+    /// Add tags to files
     #[command(arg_required_else_help = true)]
     AddTags {
         files: Vec<String>,
         #[arg(long, short, num_args = 1..)]
         tags: Vec<String>,
+        /// Change files in the registration-area rather than stored files
+        #[arg(long)]
+        staged: bool,
+        /// Accept tags that aren't on any file yet without asking, when `strict-vocabulary` is on
+        #[arg(long)]
+        new_tag: bool,
+    },
+    /// Go through the files in the registration-area one at a time, asking for their tags,
+    /// comment, title and description
+    TagSession {},
+    /// Remove tags from files
+    #[command(arg_required_else_help = true)]
+    RemoveTags {
+        files: Vec<String>,
+        #[arg(long, short, num_args = 1..)]
+        tags: Vec<String>,
+        /// Change files in the registration-area rather than stored files
+        #[arg(long)]
+        staged: bool,
+    },
+    /// Replace all of the tags on files
+    #[command(arg_required_else_help = true)]
+    SetTags {
+        files: Vec<String>,
+        #[arg(long, short, num_args = 1..)]
+        tags: Vec<String>,
+        /// Change files in the registration-area rather than stored files
+        #[arg(long)]
+        staged: bool,
+        /// Accept tags that aren't on any file yet without asking, when `strict-vocabulary` is on
+        #[arg(long)]
+        new_tag: bool,
     },
     /// Set a comment on files.  
     /// Default title is 'comment'
@@ -99,15 +168,40 @@ enum Commands {
         file: String,
         message: String,
     },
-    /// Search based on tags
+    /// Search based on tags.  
+    /// Supports `and`/`or`/`not`, `-tag`, parentheses, quoted tags and `*` wildcards,
+    /// e.g. `(paper or book) and -draft and genre:*`
     #[command(arg_required_else_help = true)]
     Find {
-        tags: Vec<String>,
+        /// The query, which can be split over several arguments. Each argument is read on its
+        /// own, so tags containing spaces have to be quoted inside it, like `'"my tag"'`
+        query: Vec<String>,
         #[arg(long = "ignore-case", short = 'i')]
         /// case insensitive matching 
         case_insensitive: bool,
     },
     ListAll {},
+    /// List groups of stored files which have identical contents
+    Dupes {},
+    /// List stored images which look similar to the given image
+    #[command(arg_required_else_help = true)]
+    Similar {
+        file: String,
+        /// The maximum number of differing bits in the perceptual hashes, defaults to the config's `similar-distance`
+        #[arg(long, short)]
+        distance: Option<u32>,
+    },
+    /// Check the storage folder against the stored file information.  
+    /// Reports files that are missing from the storage folder, files in the storage folder that
+    /// nothing refers to, and files whose contents changed since they were committed.
+    Fsck {
+        /// Hash every file, rather than assuming files with the same size and modification time are unchanged
+        #[arg(long)]
+        full: bool,
+        /// Only report problems, don't offer to repair them
+        #[arg(long)]
+        dry: bool,
+    },
     /// Open a file in the program assigned to it via xdg-open (on Linux)
     #[command(arg_required_else_help = true)]
     Open {
@@ -117,6 +211,92 @@ enum Commands {
         #[arg(long, short)]
         using: Option<String>,
     },
+    /// Browse the storage as a virtual directory layout
+    /// (`/all/`, `/tags/<tag>/`, `/query/<expr>/`).  
+    /// Listing a file prints where it is in storage.
+    Ls {
+        #[arg(default_value = "/")]
+        path: String,
+    },
+    /// Mount the storage as a filesystem with the same layout as `tagg ls`, until it is unmounted
+    /// or tagg is stopped.  
+    /// Files can be read and edited through it, but not created, renamed or removed.
+    #[command(arg_required_else_help = true)]
+    Mount {
+        /// The empty directory to mount it on
+        dir: String,
+    },
+    /// Manage folders of links to the files matching a query
+    View {
+        #[command(subcommand)]
+        command: ViewCommand,
+    },
+    /// Manage the backups of the state file
+    State {
+        #[command(subcommand)]
+        command: StateCommand,
+    },
+    /// Copy the information about the stored files into another storage-backend, checking
+    /// that nothing was lost.  
+    /// The config has to be changed afterwards to start using the new backend.
+    #[command(arg_required_else_help = true)]
+    Migrate {
+        #[arg(long, value_enum)]
+        to: StorageBackendKind,
+        /// Remove the files from the current backend once the copy has been verified
+        #[arg(long)]
+        remove_source: bool,
+    },
+    /// Undo the most recent change to the stored files' information.  
+    /// Undoing a commit brings the original file back, from the trash or from the stored copy,
+    /// and returns it to the registration-area.
+    Undo {},
+    /// Redo the most recently undone change
+    Redo {},
+    /// List the changes recorded in the journal, most recent first
+    Log {
+        /// Only list this many entries
+        #[arg(long, short = 'n')]
+        limit: Option<usize>,
+    },
+    /// Show how a file's tags and comments changed over time, from when it was committed
+    #[command(arg_required_else_help = true)]
+    History {
+        file: String,
+    },
+    /// List every tag on the stored files, with how many files have it
+    Tags {
+        #[arg(long, value_enum, default_value = "count")]
+        sort: TagSort,
+        /// Only list tags which start with this
+        #[arg(long)]
+        prefix: Option<String>,
+        /// Only list tags in this namespace, like `genre` for `genre:fantasy`
+        #[arg(long, short)]
+        namespace: Option<String>,
+        /// List the tags which are most often on the same files as this tag instead
+        #[arg(long, short)]
+        related: Option<String>,
+        /// Show the tags as a hierarchy, with `topic/ml` under `topic`
+        #[arg(long)]
+        tree: bool,
+    },
+    /// Manage other names for tags, which are replaced by the tag they stand for when adding
+    /// tags and also match it when finding files
+    Alias {
+        #[command(subcommand)]
+        command: AliasCommand,
+    },
+    /// Change tags across every file
+    Tag {
+        #[command(subcommand)]
+        command: TagCommand,
+    },
+    /// Manage rules for tags which imply other tags, like `novel` implying `book`
+    Implies {
+        #[command(subcommand)]
+        command: ImpliesCommand,
+    },
     // /// Copy full file path to clipboard
     // #[command(arg_required_else_help = true)]
     // CopyPath {
@@ -126,6 +306,151 @@ enum Commands {
 
 }
 
+impl Commands {
+    /// Whether the command only reads, and so can run at the same time as other tagg processes
+    /// which only read.
+    fn is_read_only(&self) -> bool {
+        match self {
+            Commands::Status {}
+            | Commands::Find { .. }
+            | Commands::ListAll {}
+            | Commands::Dupes {}
+            | Commands::Open { .. }
+            | Commands::Ls { .. }
+            | Commands::Mount { .. }
+            | Commands::Log { .. }
+            | Commands::History { .. }
+            | Commands::Tags { .. }
+            | Commands::State {
+                command: StateCommand::Backups {},
+            }
+            | Commands::View {
+                command: ViewCommand::List {},
+            }
+            | Commands::Alias {
+                command: AliasCommand::List {},
+            }
+            | Commands::Implies {
+                command: ImpliesCommand::List {},
+            } => true,
+            Commands::Commit { dry, .. }
+            | Commands::GenerateTitles { dry }
+            | Commands::Fsck { dry, .. }
+            | Commands::Tag {
+                command: TagCommand::Rename { dry, .. } | TagCommand::Merge { dry, .. },
+            }
+            | Commands::Implies {
+                command: ImpliesCommand::Apply { dry },
+            } => *dry,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum TagCommand {
+    /// Rename a tag on every stored file and every file in the registration-area
+    #[command(arg_required_else_help = true)]
+    Rename {
+        old: String,
+        new: String,
+        /// Also rename the tags under it, like `old/child` to `new/child`
+        #[arg(long)]
+        descendants: bool,
+        /// Only show how many files would change
+        #[arg(long)]
+        dry: bool,
+        /// Rename without asking for confirmation, even if `new` is a new tag
+        #[arg(long, short)]
+        yes: bool,
+    },
+    /// Replace several tags with a single tag on every stored file and every file in the
+    /// registration-area
+    #[command(arg_required_else_help = true)]
+    Merge {
+        #[arg(required = true)]
+        tags: Vec<String>,
+        /// The tag to merge them into, which may be one of them
+        #[arg(long)]
+        into: String,
+        /// Also move the tags under them, like `old/child` to `into/child`
+        #[arg(long)]
+        descendants: bool,
+        /// Only show how many files would change
+        #[arg(long)]
+        dry: bool,
+        /// Merge without asking for confirmation, even if `into` is a new tag
+        #[arg(long, short)]
+        yes: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum AliasCommand {
+    /// Make `alias` another name for `tag`
+    #[command(arg_required_else_help = true)]
+    Add { alias: String, tag: String },
+    /// Stop `alias` from being another name for a tag
+    #[command(arg_required_else_help = true)]
+    Remove { alias: String },
+    /// List the aliases and the tags they stand for
+    List {},
+}
+
+#[derive(Debug, Subcommand)]
+enum ImpliesCommand {
+    /// Make files tagged `tag` also count as tagged `implied`
+    #[command(arg_required_else_help = true)]
+    Add { tag: String, implied: String },
+    /// Remove the rule that `tag` implies `implied`
+    #[command(arg_required_else_help = true)]
+    Remove { tag: String, implied: String },
+    /// List the rules
+    List {},
+    /// Add the implied tags to every stored file that is missing them
+    Apply {
+        /// Only show how many files would change
+        #[arg(long)]
+        dry: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum StateCommand {
+    /// List the backups of the state file, most recent first
+    Backups {},
+    /// Replace the state file with one of its backups.  
+    /// The backup can be given by its number (`1` is the most recent) or by its path.
+    /// The current state file becomes the most recent backup, so this can be undone.
+    #[command(arg_required_else_help = true)]
+    Restore { backup: String },
+}
+
+#[derive(Debug, Subcommand)]
+enum ViewCommand {
+    /// Create a folder of links to the files matching a query.  
+    /// If the folder is already a view, its query is replaced.
+    #[command(arg_required_else_help = true)]
+    Build {
+        dir: String,
+        #[arg(long, short)]
+        query: String,
+        #[arg(long, value_enum, default_value = "symlink")]
+        link: LinkKind,
+        /// Name files by their title, when they have one, rather than their original filename
+        #[arg(long)]
+        titles: bool,
+    },
+    /// Update the links in views, after tags have changed.  
+    /// Refreshes every view if no folders are given.
+    Refresh { dirs: Vec<String> },
+    /// List the views
+    List {},
+    /// Remove a view's links and stop tracking it
+    #[command(arg_required_else_help = true)]
+    Remove { dir: String },
+}
+
 fn main() -> eyre::Result<()> {
     let args = Cli::parse();
 
@@ -141,28 +466,68 @@ fn main() -> eyre::Result<()> {
     let config = Config::load_from(&config_path).expect("Failed to load config file");
 
     let state_path = config.state_path(&config_path)?;
+    let staging_path = config.staging_path(&config_path, &state_path)?;
+
+    // Lock before anything is loaded, so that we never act on a state that another process is
+    // about to overwrite
+    let lock_mode = if args.command.is_read_only() {
+        LockMode::Shared
+    } else {
+        LockMode::Exclusive
+    };
+    let lock_timeout = Duration::from_secs(args.lock_timeout.unwrap_or(config.lock_timeout));
+    let mut lock = StateLock::acquire(&state_path, lock_mode, lock_timeout)?;
     if args.verbose {
         eprintln!(
-            "Loading state from {:?}",
-            state_path
+            "Loading registration-area from {:?}",
+            staging_path
                 .canonicalize()
-                .unwrap_or_else(|_| state_path.clone())
+                .unwrap_or_else(|_| staging_path.clone())
         );
     }
-    let state = State::load_from(&state_path).expect("Failed to load state file");
+    let staging_existed = staging_path.exists();
+    let mut staging = None;
+    if lock_mode == LockMode::Shared && staging_existed {
+        let loaded =
+            Staging::load_from(&staging_path).expect("Failed to load registration-area file");
+        let now = datetime_to_unix(&now_datetime()).unwrap_or(0);
+        if !loaded.is_expired(config.registration_delay_limit, now) {
+            staging = Some(loaded);
+        }
+    }
+    let staging = match staging {
+        Some(staging) => staging,
+        None => {
+            // Creating, migrating or expiring the registration-area writes to it, which even
+            // commands that only read need exclusive access for
+            if lock_mode == LockMode::Shared {
+                drop(lock);
+                lock = StateLock::acquire(&state_path, LockMode::Exclusive, lock_timeout)?;
+            }
+            Staging::load_from(&staging_path).expect("Failed to load registration-area file")
+        }
+    };
 
     // TODO: Check that storage folder exists
 
-    let mut tagg = Tagg {
+    let mut tagg = Tagg::new(
         config_path,
         state_path,
+        staging_path,
         config,
-        state,
+        staging,
+        lock,
+        args.verbose,
+    );
 
-        verbose: args.verbose,
-    };
+    if !staging_existed {
+        tagg.migrate_staging()?;
+    }
+    tagg.expire_staging()?;
 
-    commands::dispatch(&mut tagg, args.command)?;
+    let result = commands::dispatch(&mut tagg, args.command);
+    // Whatever was saved before an error still has to be recorded, so that it can be undone
+    tagg.save_journal()?;
 
-    Ok(())
+    result
 }
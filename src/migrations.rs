@@ -0,0 +1,125 @@
+//! The state file records the version of its layout, so that state files written by older
+//! versions of tagg can be upgraded when they're loaded.
+//!
+//! Each migration works on the parsed toml, before it is turned into a [`State`], since the old
+//! layout might not deserialize as the new one. To change the layout of the state file, bump
+//! [`STATE_VERSION`] and add a function to [`MIGRATIONS`] which upgrades the previous version.
+//!
+//! [`State`]: crate::state::State
+
+use toml::value::Table;
+
+/// The version of the state file layout that this version of tagg writes
+pub const STATE_VERSION: u32 = 1;
+
+/// `MIGRATIONS[n]` upgrades a state file from version `n` to version `n + 1`
+const MIGRATIONS: [fn(&mut Table) -> eyre::Result<()>; STATE_VERSION as usize] = [v0_to_v1];
+
+/// Version 0 is every state file from before versions were recorded.
+/// Its layout is the same as version 1, other than possibly still holding the registration-area
+/// and the time it was last modified. Those are gathered into a `staging` table, which
+/// [`crate::tagg::Tagg::migrate_staging`] moves into the staging file since that needs the
+/// staging file as well.
+fn v0_to_v1(state: &mut Table) -> eyre::Result<()> {
+    let mut staging = Table::new();
+    for key in ["registration-area", "last-registration"] {
+        if let Some(value) = state.remove(key) {
+            staging.insert(key.to_string(), value);
+        }
+    }
+    if !staging.is_empty() {
+        state.insert("staging".to_string(), toml::Value::Table(staging));
+    }
+    Ok(())
+}
+
+/// Upgrade the parsed state file to [`STATE_VERSION`].
+/// Returns the version it was upgraded from.
+pub fn upgrade_state(state: &mut Table) -> eyre::Result<u32> {
+    let version = match state.get("version") {
+        Some(version) => version
+            .as_integer()
+            .and_then(|x| u32::try_from(x).ok())
+            .ok_or_else(|| eyre::eyre!("The state file's version is not a valid number"))?,
+        None => 0,
+    };
+
+    if version > STATE_VERSION {
+        return Err(eyre::eyre!(
+            "The state file is version {}, but this version of tagg only understands up to version {}. Update tagg to use it",
+            version,
+            STATE_VERSION
+        ));
+    }
+
+    for migration in MIGRATIONS[version as usize..].iter() {
+        migration(state)?;
+    }
+    state.insert(
+        "version".to_string(),
+        toml::Value::Integer(STATE_VERSION.into()),
+    );
+
+    Ok(version)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{state::State, util::datetime_to_unix};
+
+    use super::{upgrade_state, STATE_VERSION};
+
+    #[test]
+    fn test_upgrade() {
+        let old = r#"
+[[storage.files]]
+filename = "a0.txt"
+tags = ["kitchen"]
+"#;
+        let mut table: toml::value::Table = toml::from_str(old).unwrap();
+        assert_eq!(upgrade_state(&mut table).unwrap(), 0);
+        let state: State = toml::Value::Table(table).try_into().unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(state.storage.files[0].tags, ["kitchen"]);
+
+        let mut current: toml::value::Table =
+            toml::from_str(&format!("version = {}", STATE_VERSION)).unwrap();
+        assert_eq!(upgrade_state(&mut current).unwrap(), STATE_VERSION);
+
+        let mut newer: toml::value::Table =
+            toml::from_str(&format!("version = {}", STATE_VERSION + 1)).unwrap();
+        assert!(upgrade_state(&mut newer).is_err());
+    }
+
+    #[test]
+    fn test_upgrade_v0_staging() {
+        // A state file from before the registration-area had its own file
+        let old = r#"
+last-registration = 2022-11-09T13:20:00Z
+
+[[registration-area]]
+path = "toaster.txt"
+tags = ["kitchen"]
+
+[[storage.files]]
+filename = "a0.txt"
+tags = ["kitchen"]
+"#;
+        let state = State::parse(old).unwrap();
+        let staging = state.staging.expect("The registration-area to be kept");
+        assert_eq!(staging.registration_area.len(), 1);
+        assert_eq!(staging.registration_area[0].tags, ["kitchen"]);
+        assert_eq!(
+            staging
+                .last_registration
+                .as_ref()
+                .and_then(datetime_to_unix),
+            Some(1668000000)
+        );
+        assert_eq!(state.storage.files.len(), 1);
+
+        // Which isn't written back into the state file
+        let text = toml::to_string(&State::parse(old).unwrap()).unwrap();
+        assert!(!text.contains("registration"));
+    }
+}
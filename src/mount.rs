@@ -0,0 +1,349 @@
+//! `tagg mount`, which serves the layout of [`crate::vfs`] as a filesystem.
+//!
+//! The files are the files in storage: reads, writes and changes to their attributes go
+//! straight through to them, so editing a file through the mount edits it in storage (and
+//! `tagg fsck` reports that it changed since it was committed). The directories can't be
+//! changed, and list the files as they were tagged when the storage was mounted, since other
+//! tagg commands have to be able to run while it is mounted.
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::{File, FileTimes, Metadata},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+    },
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    fuse::{Attr, DirEntry, Filesystem, Session, SetAttr, ROOT_ID},
+    query::QueryContext,
+    storage::Storage,
+    tagg::Tagg,
+    util::expand_path,
+    vfs::{self, Entry},
+};
+
+pub fn mount(tagg: &mut Tagg, dir: &str) -> eyre::Result<()> {
+    let context = tagg.query_context()?;
+    let storage = Storage::from_backend(&tagg.storage()?)?;
+    let storage_path = tagg.config.storage_path(&tagg.config_path)?;
+    let mountpoint = expand_path(dir)
+        .canonicalize()
+        .map_err(|err| eyre::eyre!("Can't mount on {:?}: {}", dir, err))?;
+
+    // Everything has been loaded, and the mount can stay up for a long time
+    tagg.unlock();
+
+    let mut session = Session::mount(&mountpoint)?;
+    println!(
+        "Mounted the storage on {}, unmount it or press Ctrl-C to stop",
+        mountpoint.display()
+    );
+    let mut fs = TaggFs::new(&storage, &context, storage_path);
+    session.serve(&mut fs)
+}
+
+/// What a path in the layout is
+#[derive(Clone)]
+enum Node {
+    Dir,
+    /// A file, with its name in storage
+    File(String),
+}
+
+struct TaggFs<'a> {
+    storage: &'a Storage,
+    context: &'a QueryContext,
+    storage_path: PathBuf,
+    /// The path of every inode that was looked up, with inode `n` at index `n - 1`
+    paths: Vec<String>,
+    inodes: HashMap<String, u64>,
+    /// The directories that were listed, which don't change while mounted
+    listings: HashMap<String, Vec<(String, Node)>>,
+    files: HashMap<u64, File>,
+    next_file: u64,
+    /// The times of the directories
+    mounted_at: SystemTime,
+}
+impl<'a> TaggFs<'a> {
+    fn new(storage: &'a Storage, context: &'a QueryContext, storage_path: PathBuf) -> TaggFs<'a> {
+        TaggFs {
+            storage,
+            context,
+            storage_path,
+            paths: vec!["/".to_string()],
+            inodes: HashMap::from([("/".to_string(), ROOT_ID)]),
+            listings: HashMap::new(),
+            files: HashMap::new(),
+            next_file: 1,
+            mounted_at: SystemTime::now(),
+        }
+    }
+
+    fn path(&self, ino: u64) -> Result<String, i32> {
+        let index = ino.checked_sub(1).ok_or(libc::ENOENT)?;
+        self.paths.get(index as usize).cloned().ok_or(libc::ENOENT)
+    }
+
+    fn inode(&mut self, path: &str) -> u64 {
+        if let Some(ino) = self.inodes.get(path) {
+            return *ino;
+        }
+        self.paths.push(path.to_string());
+        let ino = self.paths.len() as u64;
+        self.inodes.insert(path.to_string(), ino);
+        ino
+    }
+
+    fn listing(&mut self, path: &str) -> Result<&[(String, Node)], i32> {
+        if !self.listings.contains_key(path) {
+            let entries = vfs::list(self.storage, path, self.context)
+                .map_err(|_| libc::ENOENT)?
+                .into_iter()
+                .map(|entry| match entry {
+                    Entry::Dir(name) => (name, Node::Dir),
+                    Entry::File { name, file } => (name, Node::File(file.filename.clone())),
+                })
+                .collect();
+            self.listings.insert(path.to_string(), entries);
+        }
+        Ok(&self.listings[path])
+    }
+
+    fn node(&mut self, path: &str) -> Result<Node, i32> {
+        let Some((dir, name)) = path.rsplit_once('/').filter(|_| path != "/") else {
+            return Ok(Node::Dir);
+        };
+        let dir = if dir.is_empty() { "/" } else { dir };
+        let found = self
+            .listing(dir)?
+            .iter()
+            .find(|(entry_name, _)| entry_name == name)
+            .map(|(_, node)| node.clone());
+        match found {
+            Some(node) => Ok(node),
+            // Queries can't be listed, so `/query/<expr>` isn't in the listing of `/query`
+            None => self.listing(path).map(|_| Node::Dir),
+        }
+    }
+
+    fn storage_file(&mut self, ino: u64) -> Result<PathBuf, i32> {
+        let path = self.path(ino)?;
+        match self.node(&path)? {
+            Node::File(filename) => Ok(self.storage_path.join(filename)),
+            Node::Dir => Err(libc::EISDIR),
+        }
+    }
+
+    fn attr(&mut self, ino: u64) -> Result<Attr, i32> {
+        let path = self.path(ino)?;
+        match self.node(&path)? {
+            Node::Dir => Ok(Attr {
+                ino,
+                size: 0,
+                blocks: 0,
+                atime: self.mounted_at,
+                mtime: self.mounted_at,
+                ctime: self.mounted_at,
+                mode: libc::S_IFDIR | 0o555,
+                nlink: 2,
+                uid: unsafe { libc::getuid() },
+                gid: unsafe { libc::getgid() },
+            }),
+            Node::File(filename) => {
+                let metadata =
+                    std::fs::metadata(self.storage_path.join(filename)).map_err(errno)?;
+                Ok(file_attr(ino, &metadata))
+            }
+        }
+    }
+
+    fn file(&self, fh: u64) -> Result<&File, i32> {
+        self.files.get(&fh).ok_or(libc::EBADF)
+    }
+}
+impl Filesystem for TaggFs<'_> {
+    fn lookup(&mut self, parent: u64, name: &str) -> Result<Attr, i32> {
+        let parent = self.path(parent)?;
+        let path = if parent == "/" {
+            format!("/{}", name)
+        } else {
+            format!("{}/{}", parent, name)
+        };
+        self.node(&path)?;
+        let ino = self.inode(&path);
+        self.attr(ino)
+    }
+
+    fn getattr(&mut self, ino: u64) -> Result<Attr, i32> {
+        self.attr(ino)
+    }
+
+    fn setattr(&mut self, ino: u64, fh: Option<u64>, changes: &SetAttr) -> Result<Attr, i32> {
+        let path = self.storage_file(ino).map_err(|err| match err {
+            libc::EISDIR => libc::EPERM,
+            err => err,
+        })?;
+
+        if let Some(mode) = changes.mode {
+            std::fs::set_permissions(&path, PermissionsExt::from_mode(mode & 0o7777))
+                .map_err(errno)?;
+        }
+        if changes.uid.is_some() || changes.gid.is_some() {
+            std::os::unix::fs::chown(&path, changes.uid, changes.gid).map_err(errno)?;
+        }
+        if let Some(size) = changes.size {
+            match fh.and_then(|fh| self.files.get(&fh)) {
+                Some(file) => file.set_len(size),
+                None => File::options()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|file| file.set_len(size)),
+            }
+            .map_err(errno)?;
+        }
+        if changes.atime.is_some() || changes.mtime.is_some() {
+            set_times(&path, changes.atime, changes.mtime)?;
+        }
+
+        self.attr(ino)
+    }
+
+    fn open(&mut self, ino: u64, flags: i32) -> Result<u64, i32> {
+        let path = self.storage_file(ino)?;
+        let access = flags & libc::O_ACCMODE;
+        let file = File::options()
+            .read(access != libc::O_WRONLY)
+            .write(access != libc::O_RDONLY)
+            .append(flags & libc::O_APPEND != 0)
+            .truncate(flags & libc::O_TRUNC != 0)
+            .custom_flags(flags & libc::O_SYNC)
+            .open(path)
+            .map_err(errno)?;
+
+        let fh = self.next_file;
+        self.next_file += 1;
+        self.files.insert(fh, file);
+        Ok(fh)
+    }
+
+    fn read(&mut self, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, i32> {
+        let file = self.file(fh)?;
+        let mut data = vec![0; size as usize];
+        // A short read means the end of the file, so fill as much as we can
+        let mut len = 0;
+        while len < data.len() {
+            match file.read_at(&mut data[len..], offset + len as u64) {
+                Ok(0) => break,
+                Ok(read) => len += read,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(errno(err)),
+            }
+        }
+        data.truncate(len);
+        Ok(data)
+    }
+
+    fn write(&mut self, fh: u64, offset: u64, data: &[u8]) -> Result<u32, i32> {
+        self.file(fh)?.write_all_at(data, offset).map_err(errno)?;
+        Ok(data.len() as u32)
+    }
+
+    fn fsync(&mut self, fh: u64) -> Result<(), i32> {
+        self.file(fh)?.sync_all().map_err(errno)
+    }
+
+    fn release(&mut self, fh: u64) {
+        self.files.remove(&fh);
+    }
+
+    fn list(&mut self, ino: u64) -> Result<Vec<DirEntry>, i32> {
+        let path = self.path(ino)?;
+        if let Node::File(_) = self.node(&path)? {
+            return Err(libc::ENOTDIR);
+        }
+        let parent = match path.rsplit_once('/') {
+            Some(("", _)) | None => "/".to_string(),
+            Some((parent, _)) => parent.to_string(),
+        };
+        let parent_ino = self.inode(&parent);
+
+        let mut entries = vec![
+            DirEntry {
+                ino,
+                name: ".".to_string(),
+                is_dir: true,
+            },
+            DirEntry {
+                ino: parent_ino,
+                name: "..".to_string(),
+                is_dir: true,
+            },
+        ];
+        for (name, node) in self.listing(&path)?.to_vec() {
+            let child = if path == "/" {
+                format!("/{}", name)
+            } else {
+                format!("{}/{}", path, name)
+            };
+            entries.push(DirEntry {
+                ino: self.inode(&child),
+                name,
+                is_dir: matches!(node, Node::Dir),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn statfs(&mut self) -> Result<libc::statvfs, i32> {
+        let path = CString::new(self.storage_path.as_os_str().as_bytes()).map_err(|_| libc::EIO)?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(errno(std::io::Error::last_os_error()));
+        }
+        Ok(stat)
+    }
+}
+
+fn file_attr(ino: u64, metadata: &Metadata) -> Attr {
+    let time = |secs: i64, nsecs: i64| {
+        UNIX_EPOCH + Duration::new(secs.max(0) as u64, nsecs.clamp(0, 999_999_999) as u32)
+    };
+    Attr {
+        ino,
+        size: metadata.size(),
+        blocks: metadata.blocks(),
+        atime: time(metadata.atime(), metadata.atime_nsec()),
+        mtime: time(metadata.mtime(), metadata.mtime_nsec()),
+        ctime: time(metadata.ctime(), metadata.ctime_nsec()),
+        mode: metadata.mode(),
+        nlink: 1,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+    }
+}
+
+fn set_times(
+    path: &PathBuf,
+    atime: Option<SystemTime>,
+    mtime: Option<SystemTime>,
+) -> Result<(), i32> {
+    let mut times = FileTimes::new();
+    if let Some(atime) = atime {
+        times = times.set_accessed(atime);
+    }
+    if let Some(mtime) = mtime {
+        times = times.set_modified(mtime);
+    }
+    File::open(path)
+        .and_then(|file| file.set_times(times))
+        .map_err(errno)
+}
+
+fn errno(err: std::io::Error) -> i32 {
+    err.raw_os_error().unwrap_or(libc::EIO)
+}
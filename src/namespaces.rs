@@ -0,0 +1,160 @@
+//! Tags like `year:2021` or `rating:4` are a value in a namespace. Namespaces can be given a type
+//! in the config, which lets `tagg find` compare their values (`year:>=2019`, `rating:4..5`) and
+//! stops tags with values that don't fit from being added:
+//! ```toml
+//! [namespaces]
+//! year = "integer"
+//! read = "date"
+//! status = { enum = ["todo", "reading", "done"] }
+//! ```
+//! Enum values are ordered as they're listed, so `status:>=reading` finds `reading` and `done`.
+//! Namespaces without a type can still be compared, as integers if both values are integers and
+//! alphabetically otherwise.
+
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use serde::{Deserialize, Serialize};
+
+/// The type of the values in a namespace
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NamespaceType {
+    /// Whole numbers, like `rating:4`
+    Integer,
+    /// Dates written as `YYYY-MM-DD`, like `read:2021-05-30`
+    Date,
+    /// One of the listed values, which are ordered as they're listed
+    Enum(Vec<String>),
+}
+impl NamespaceType {
+    /// Parse a value of the namespace, failing with a description of what was expected
+    pub fn parse(&self, value: &str) -> Result<TypedValue, String> {
+        match self {
+            NamespaceType::Integer => value
+                .parse()
+                .map(TypedValue::Integer)
+                .map_err(|_| "expected an integer".to_string()),
+            NamespaceType::Date => parse_date(value)
+                .map(|(year, month, day)| TypedValue::Date(year, month, day))
+                .ok_or_else(|| "expected a date like 2021-05-30".to_string()),
+            NamespaceType::Enum(values) => values
+                .iter()
+                .position(|x| x == value)
+                .map(TypedValue::Enum)
+                .ok_or_else(|| format!("expected one of {}", values.join(", "))),
+        }
+    }
+}
+
+/// A parsed value of a typed namespace
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TypedValue {
+    Integer(i64),
+    Date(i32, u32, u32),
+    /// The position of the value in the enum's list
+    Enum(usize),
+}
+
+/// The namespace and value of a `namespace:value` tag
+pub fn split(tag: &str) -> Option<(&str, &str)> {
+    tag.split_once(':')
+        .filter(|(namespace, value)| !namespace.is_empty() && !value.is_empty())
+}
+
+/// Compare two values of a namespace with the given type, or without one if it has no type.
+/// Returns `None` if either value doesn't fit the type.
+pub fn compare(kind: Option<&NamespaceType>, a: &str, b: &str) -> Option<Ordering> {
+    match kind {
+        Some(kind) => Some(kind.parse(a).ok()?.cmp(&kind.parse(b).ok()?)),
+        None => match (a.parse::<i64>(), b.parse::<i64>()) {
+            (Ok(a), Ok(b)) => Some(a.cmp(&b)),
+            _ => Some(a.cmp(b)),
+        },
+    }
+}
+
+/// Check that every tag in a typed namespace has a value that fits its type
+pub fn check_tags(
+    namespaces: &BTreeMap<String, NamespaceType>,
+    tags: &[String],
+) -> eyre::Result<()> {
+    for tag in tags {
+        let Some((namespace, value)) = split(tag) else {
+            continue;
+        };
+        if let Some(kind) = namespaces.get(namespace) {
+            if let Err(expected) = kind.parse(value) {
+                return Err(eyre::eyre!(
+                    "The tag {:?} doesn't fit the {:?} namespace, {}",
+                    tag,
+                    namespace,
+                    expected
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_date(value: &str) -> Option<(i32, u32, u32)> {
+    let mut parts = value.splitn(3, '-');
+    let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    let (year, month, day): (i32, u32, u32) =
+        (year.parse().ok()?, month.parse().ok()?, day.parse().ok()?);
+
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let days = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return None,
+    };
+    (1..=days).contains(&day).then_some((year, month, day))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cmp::Ordering, collections::BTreeMap};
+
+    use super::{check_tags, compare, NamespaceType, TypedValue};
+
+    #[test]
+    fn test_namespaces() {
+        let status = NamespaceType::Enum(vec!["todo".to_string(), "done".to_string()]);
+        assert_eq!(
+            NamespaceType::Integer.parse("-4"),
+            Ok(TypedValue::Integer(-4))
+        );
+        assert!(NamespaceType::Integer.parse("4.5").is_err());
+        assert_eq!(
+            NamespaceType::Date.parse("2020-02-29"),
+            Ok(TypedValue::Date(2020, 2, 29))
+        );
+        assert!(NamespaceType::Date.parse("2021-02-29").is_err());
+        assert!(NamespaceType::Date.parse("2021-5-3").is_err());
+        assert_eq!(status.parse("done"), Ok(TypedValue::Enum(1)));
+        assert!(status.parse("reading").is_err());
+
+        assert_eq!(compare(None, "9", "10"), Some(Ordering::Less));
+        assert_eq!(compare(None, "b", "a"), Some(Ordering::Greater));
+        assert_eq!(compare(Some(&status), "todo", "done"), Some(Ordering::Less));
+        assert_eq!(compare(Some(&status), "todo", "later"), None);
+
+        let namespaces = BTreeMap::from([
+            ("year".to_string(), NamespaceType::Integer),
+            ("status".to_string(), status),
+        ]);
+        let tags = |tags: &[&str]| tags.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        assert!(check_tags(
+            &namespaces,
+            &tags(&["year:2021", "status:todo", "genre:x", "year"])
+        )
+        .is_ok());
+        assert!(check_tags(&namespaces, &tags(&["year:soon"])).is_err());
+        assert!(check_tags(&namespaces, &tags(&["status:reading"])).is_err());
+    }
+}
@@ -0,0 +1,1292 @@
+//! The query language used by `tagg find`.
+//!
+//! A query is a boolean expression over tags:
+//! - `paper book` both tags must be present (terms next to each other are implicitly `and`ed)
+//! - `paper and book`, `paper or book`, `not draft`
+//! - `+paper` is the same as `paper`, `-draft` is the same as `not draft`
+//! - `(paper or book) and -draft` parentheses group expressions
+//! - `genre:*` unquoted tags can use `*` (any amount of characters) and `?` (a single character)
+//! - `"and"` or `'my tag'` quoted tags are matched literally, which lets you search for tags that
+//!   contain spaces, look like keywords, or contain `*`
+//!
+//! It can also look at the original filename and comments of a file, with `orig` referring to
+//! the original filename and anything else referring to the comment with that title:
+//! - `title~neural` or `title~"neural net"` the field contains the text, ignoring case
+//! - `desc:contains(neural net)` the same as `~`
+//! - `orig:*.epub` the original filename matches the pattern
+//! - `has:desc` / `missing:title` the field exists / does not exist
+//!
+//! Because of this, tags starting with `has:`, `missing:` or `orig:`, or containing `~`, have
+//! to be quoted to be searched for.
+//!
+//! Tags match their descendants too, so `topic` finds files tagged `topic/ml` or `topic:ml`, see
+//! [`crate::hierarchy`].
+//!
+//! The values of `namespace:value` tags can be compared, see [`crate::namespaces`]:
+//! - `year:>=2019`, `year:>2019`, `year:<=2019`, `year:<2019`
+//! - `rating:4..5` includes both ends, and either end can be left out like `rating:4..`
+//!
+//! This applies to every `namespace:value` tag, not just the namespaces with a type, so tags like
+//! `version:1..2` or `note:<draft>` have to be quoted to be searched for.
+//!
+//! `not` binds tighter than `and`, which binds tighter than `or`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    ops::Bound,
+};
+
+use crate::{
+    aliases::Aliases,
+    hierarchy::tag_and_ancestors,
+    implications::Implications,
+    namespaces::{self, NamespaceType},
+    storage::FileInfo,
+};
+
+/// A parsed query that can be matched against files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    pub expr: Expr,
+    /// Whether tags should be compared ignoring ascii case
+    pub case_insensitive: bool,
+}
+impl Query {
+    /// Parse a query from its textual form.
+    /// An empty query matches every file.
+    pub fn parse(text: &str) -> Result<Query, QueryError> {
+        Query::parse_args(&[text])
+    }
+
+    /// Parse a query given as several command line arguments.  
+    /// Each argument is lexed on its own, so a quote or `contains(` can't run on from one
+    /// argument into the next. Within an argument spaces separate terms as usual, so a tag
+    /// containing spaces still has to be quoted inside the query, like `'"my tag"'`.  
+    /// Error columns refer to the arguments joined with single spaces.
+    pub fn parse_args<T: AsRef<str>>(args: &[T]) -> Result<Query, QueryError> {
+        let mut tokens = Vec::new();
+        let mut offset = 0;
+        for arg in args {
+            let arg = arg.as_ref();
+            let shift = |column: usize| column + offset;
+            let mut arg_tokens = lex(arg).map_err(|err| QueryError {
+                column: shift(err.column),
+                ..err
+            })?;
+            for token in arg_tokens.iter_mut() {
+                token.column = shift(token.column);
+            }
+            tokens.extend(arg_tokens);
+            offset += arg.chars().count() + 1;
+        }
+
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            end_column: offset.max(1),
+        };
+        let expr = parser.parse_query()?;
+        Ok(Query {
+            expr,
+            case_insensitive: false,
+        })
+    }
+
+    pub fn with_case_insensitive(mut self, case_insensitive: bool) -> Query {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Make the tags in the query also match the tags they're aliases of, and the other aliases
+    /// of those tags, see [`crate::aliases`]
+    pub fn with_aliases(mut self, aliases: &Aliases) -> Query {
+        if !aliases.is_empty() {
+            self.expr = self.expr.with_aliases(aliases, self.case_insensitive);
+        }
+        self
+    }
+
+    /// Make the tags in the query also match the tags that imply them, for when implied tags
+    /// aren't stored, see [`crate::implications`]
+    pub fn with_implications(mut self, implications: &Implications) -> Query {
+        if !implications.is_empty() {
+            self.expr = self
+                .expr
+                .with_implications(implications, self.case_insensitive);
+        }
+        self
+    }
+
+    /// Compare the values in ranges like `year:>=2019` according to the types of their
+    /// namespaces, failing if a bound doesn't fit the type.  
+    /// Without this, values are compared as integers if they are integers and alphabetically
+    /// otherwise.
+    pub fn with_namespaces(
+        mut self,
+        namespaces: &BTreeMap<String, NamespaceType>,
+    ) -> eyre::Result<Query> {
+        if !namespaces.is_empty() {
+            self.expr = self.expr.with_namespaces(namespaces)?;
+        }
+        Ok(self)
+    }
+
+    /// Check whether the file satisfies the query
+    pub fn matches(&self, file: &FileInfo) -> bool {
+        self.expr.matches(file, self.case_insensitive)
+    }
+
+    /// Check whether the tag is one that the query is positively looking for.
+    /// This is used for highlighting the tags that caused a file to be found.
+    pub fn matches_tag(&self, tag: &str) -> bool {
+        self.expr
+            .matches_tag_positively(tag, self.case_insensitive, false)
+    }
+
+    /// The exact tags that every matching file must have, or have a descendant of.  
+    /// Storage backends with a tag index use this to narrow down which files to check, so it
+    /// can be left empty whenever the query is too complicated to tell.
+    pub fn required_tags(&self) -> Vec<&str> {
+        if self.case_insensitive {
+            return Vec::new();
+        }
+
+        let mut tags = Vec::new();
+        self.expr.required_tags(&mut tags);
+        tags
+    }
+}
+
+/// What queries are prepared with before they're run, so that `tagg find`, views and `tagg ls`
+/// all take aliases, implied tags and typed namespaces into account the same way.
+#[derive(Debug, Clone, Default)]
+pub struct QueryContext {
+    pub namespaces: BTreeMap<String, NamespaceType>,
+    pub aliases: Aliases,
+    /// The implication rules, if implied tags aren't stored and so have to be found through
+    /// the tags that imply them
+    pub implications: Option<Implications>,
+}
+impl QueryContext {
+    /// Parse the query and prepare it with [`QueryContext::prepare`]
+    pub fn parse(&self, text: &str) -> eyre::Result<Query> {
+        self.prepare(Query::parse(text)?)
+    }
+
+    /// Apply the typed namespaces, aliases and implication rules to a parsed query
+    pub fn prepare(&self, query: Query) -> eyre::Result<Query> {
+        let query = query
+            .with_namespaces(&self.namespaces)?
+            .with_aliases(&self.aliases);
+        Ok(match &self.implications {
+            Some(implications) => query.with_implications(implications),
+            None => query,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    /// Matches everything, this is what an empty query parses to
+    All,
+    Tag(TagPattern),
+    Field(FieldPredicate),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+impl Expr {
+    fn matches(&self, file: &FileInfo, case_insensitive: bool) -> bool {
+        match self {
+            Expr::All => true,
+            Expr::Tag(pattern) => file
+                .tags
+                .iter()
+                .any(|tag| pattern.matches(tag, case_insensitive)),
+            Expr::Field(predicate) => predicate.matches(file, case_insensitive),
+            Expr::Not(expr) => !expr.matches(file, case_insensitive),
+            Expr::And(exprs) => exprs.iter().all(|x| x.matches(file, case_insensitive)),
+            Expr::Or(exprs) => exprs.iter().any(|x| x.matches(file, case_insensitive)),
+        }
+    }
+
+    fn matches_tag_positively(&self, tag: &str, case_insensitive: bool, negated: bool) -> bool {
+        match self {
+            Expr::All | Expr::Field(_) => false,
+            Expr::Tag(pattern) => !negated && pattern.matches(tag, case_insensitive),
+            Expr::Not(expr) => expr.matches_tag_positively(tag, case_insensitive, !negated),
+            Expr::And(exprs) | Expr::Or(exprs) => exprs
+                .iter()
+                .any(|x| x.matches_tag_positively(tag, case_insensitive, negated)),
+        }
+    }
+
+    fn with_aliases(self, aliases: &Aliases, case_insensitive: bool) -> Expr {
+        match self {
+            Expr::Tag(TagPattern::Exact(tag)) => {
+                let canonical = aliases.resolve(&tag);
+                let mut names = vec![canonical];
+                names.extend(aliases.aliases_of(canonical));
+                if names.len() == 1 {
+                    return Expr::Tag(TagPattern::Exact(tag));
+                }
+
+                Expr::Or(
+                    names
+                        .into_iter()
+                        .map(|name| Expr::Tag(TagPattern::Exact(name.to_string())))
+                        .collect(),
+                )
+            }
+            // Patterns can match an alias or the tag it stands for, and either way every name
+            // of that tag should be found
+            Expr::Tag(pattern) => {
+                let canonical: BTreeSet<&str> = aliases
+                    .iter()
+                    .filter(|(alias, tag)| {
+                        pattern.matches(alias, case_insensitive)
+                            || pattern.matches(tag, case_insensitive)
+                    })
+                    .map(|(_, tag)| tag)
+                    .collect();
+                if canonical.is_empty() {
+                    return Expr::Tag(pattern);
+                }
+
+                let mut exprs = vec![Expr::Tag(pattern)];
+                for tag in canonical {
+                    exprs.push(Expr::Tag(TagPattern::Exact(tag.to_string())));
+                    exprs.extend(
+                        aliases
+                            .aliases_of(tag)
+                            .into_iter()
+                            .map(|name| Expr::Tag(TagPattern::Exact(name.to_string()))),
+                    );
+                }
+                Expr::Or(exprs)
+            }
+            Expr::Not(expr) => Expr::Not(Box::new(expr.with_aliases(aliases, case_insensitive))),
+            Expr::And(exprs) => Expr::And(
+                exprs
+                    .into_iter()
+                    .map(|x| x.with_aliases(aliases, case_insensitive))
+                    .collect(),
+            ),
+            Expr::Or(exprs) => Expr::Or(
+                exprs
+                    .into_iter()
+                    .map(|x| x.with_aliases(aliases, case_insensitive))
+                    .collect(),
+            ),
+            expr => expr,
+        }
+    }
+
+    fn with_implications(self, implications: &Implications, case_insensitive: bool) -> Expr {
+        match self {
+            Expr::Tag(TagPattern::Exact(tag)) => {
+                let implying = implications.implying(&tag);
+                if implying.is_empty() {
+                    return Expr::Tag(TagPattern::Exact(tag));
+                }
+
+                let mut exprs: Vec<Expr> = implying
+                    .into_iter()
+                    .map(|name| Expr::Tag(TagPattern::Exact(name.to_string())))
+                    .collect();
+                exprs.insert(0, Expr::Tag(TagPattern::Exact(tag)));
+                Expr::Or(exprs)
+            }
+            Expr::Tag(pattern) => {
+                let implying =
+                    implications.implying_matching(|tag| pattern.matches(tag, case_insensitive));
+                if implying.is_empty() {
+                    return Expr::Tag(pattern);
+                }
+
+                let mut exprs = vec![Expr::Tag(pattern)];
+                exprs.extend(
+                    implying
+                        .into_iter()
+                        .map(|name| Expr::Tag(TagPattern::Exact(name.to_string()))),
+                );
+                Expr::Or(exprs)
+            }
+            Expr::Not(expr) => Expr::Not(Box::new(
+                expr.with_implications(implications, case_insensitive),
+            )),
+            Expr::And(exprs) => Expr::And(
+                exprs
+                    .into_iter()
+                    .map(|x| x.with_implications(implications, case_insensitive))
+                    .collect(),
+            ),
+            Expr::Or(exprs) => Expr::Or(
+                exprs
+                    .into_iter()
+                    .map(|x| x.with_implications(implications, case_insensitive))
+                    .collect(),
+            ),
+            expr => expr,
+        }
+    }
+
+    fn with_namespaces(self, namespaces: &BTreeMap<String, NamespaceType>) -> eyre::Result<Expr> {
+        Ok(match self {
+            Expr::Tag(TagPattern::Range(mut range)) => {
+                if let Some(kind) = namespaces.get(&range.namespace) {
+                    for bound in [&range.start, &range.end] {
+                        if let Bound::Included(value) | Bound::Excluded(value) = bound {
+                            if let Err(expected) = kind.parse(value) {
+                                return Err(eyre::eyre!(
+                                    "{:?} in the query doesn't fit the {:?} namespace, {}",
+                                    value,
+                                    range.namespace,
+                                    expected
+                                ));
+                            }
+                        }
+                    }
+                    range.kind = Some(kind.clone());
+                }
+                Expr::Tag(TagPattern::Range(range))
+            }
+            Expr::Not(expr) => Expr::Not(Box::new(expr.with_namespaces(namespaces)?)),
+            Expr::And(exprs) => Expr::And(
+                exprs
+                    .into_iter()
+                    .map(|x| x.with_namespaces(namespaces))
+                    .collect::<eyre::Result<_>>()?,
+            ),
+            Expr::Or(exprs) => Expr::Or(
+                exprs
+                    .into_iter()
+                    .map(|x| x.with_namespaces(namespaces))
+                    .collect::<eyre::Result<_>>()?,
+            ),
+            expr => expr,
+        })
+    }
+
+    fn required_tags<'a>(&'a self, tags: &mut Vec<&'a str>) {
+        match self {
+            Expr::Tag(TagPattern::Exact(tag)) => tags.push(tag),
+            Expr::And(exprs) => {
+                for expr in exprs {
+                    expr.required_tags(tags);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagPattern {
+    /// The tag must be exactly this
+    Exact(String),
+    /// A pattern with `*` and `?` wildcards
+    Glob(String),
+    /// A range of values in a namespace, like `year:>=2019`
+    Range(ValueRange),
+}
+impl TagPattern {
+    /// Check whether the tag, or any of its ancestors (see [`crate::hierarchy`]), matches
+    pub fn matches(&self, tag: &str, case_insensitive: bool) -> bool {
+        tag_and_ancestors(tag).any(|tag| self.matches_exactly(tag, case_insensitive))
+    }
+
+    fn matches_exactly(&self, tag: &str, case_insensitive: bool) -> bool {
+        match self {
+            TagPattern::Exact(pattern) => {
+                if case_insensitive {
+                    pattern.eq_ignore_ascii_case(tag)
+                } else {
+                    pattern == tag
+                }
+            }
+            TagPattern::Glob(pattern) => glob_match(pattern, tag, case_insensitive),
+            TagPattern::Range(range) => range.matches(tag, case_insensitive),
+        }
+    }
+}
+
+/// The values of a namespace between two bounds, see [`crate::namespaces`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueRange {
+    pub namespace: String,
+    pub start: Bound<String>,
+    pub end: Bound<String>,
+    /// The type of the namespace, which is set by [`Query::with_namespaces`]
+    pub kind: Option<NamespaceType>,
+}
+impl ValueRange {
+    fn matches(&self, tag: &str, case_insensitive: bool) -> bool {
+        let Some((namespace, value)) = namespaces::split(tag) else {
+            return false;
+        };
+        let same_namespace = if case_insensitive {
+            namespace.eq_ignore_ascii_case(&self.namespace)
+        } else {
+            namespace == self.namespace
+        };
+        if !same_namespace || self.kind.as_ref().is_some_and(|x| x.parse(value).is_err()) {
+            return false;
+        }
+
+        let compare = |bound: &str| namespaces::compare(self.kind.as_ref(), value, bound);
+        let after_start = match &self.start {
+            Bound::Included(start) => compare(start).is_some_and(|x| x.is_ge()),
+            Bound::Excluded(start) => compare(start).is_some_and(|x| x.is_gt()),
+            Bound::Unbounded => true,
+        };
+        let before_end = match &self.end {
+            Bound::Included(end) => compare(end).is_some_and(|x| x.is_le()),
+            Bound::Excluded(end) => compare(end).is_some_and(|x| x.is_lt()),
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+}
+
+/// The name of the field that refers to the original filename, rather than a comment
+pub const FIELD_ORIGINAL_FILENAME: &str = "orig";
+
+/// A predicate on the non-tag information about a file.  
+/// A field is either the original filename (`orig`) or the title of a comment
+/// (`title`, `desc`, `comment`, or any custom title).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldPredicate {
+    /// `key~text` or `key:contains(text)`, the field contains the text, ignoring case
+    Contains { key: String, text: String },
+    /// `orig:pattern`, the original filename matches the glob pattern
+    OriginalFilename(String),
+    /// `has:key`, the field exists.  
+    /// `missing:key` is parsed as the negation of this.
+    Has(String),
+}
+impl FieldPredicate {
+    pub fn matches(&self, file: &FileInfo, case_insensitive: bool) -> bool {
+        match self {
+            FieldPredicate::Contains { key, text } => field_value(file, key)
+                .map(|value| value.to_lowercase().contains(&text.to_lowercase()))
+                .unwrap_or(false),
+            FieldPredicate::OriginalFilename(pattern) => file
+                .original_filename
+                .as_deref()
+                .map(|name| glob_match(pattern, name, case_insensitive))
+                .unwrap_or(false),
+            FieldPredicate::Has(key) => field_value(file, key).is_some(),
+        }
+    }
+}
+
+fn field_value<'a>(file: &'a FileInfo, key: &str) -> Option<&'a str> {
+    if key == FIELD_ORIGINAL_FILENAME {
+        file.original_filename.as_deref()
+    } else {
+        file.comments.get(key).map(String::as_str)
+    }
+}
+
+/// Match `text` against a pattern where `*` matches any amount of characters and `?` matches a single character.
+pub fn glob_match(pattern: &str, text: &str, case_insensitive: bool) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let eq = |a: char, b: char| {
+        if case_insensitive {
+            a.eq_ignore_ascii_case(&b)
+        } else {
+            a == b
+        }
+    };
+
+    // Iterative matching with backtracking to the last star
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len()
+            && (pattern[p] == '?' || (pattern[p] != '*' && eq(pattern[p], text[t])))
+        {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+/// An error in parsing a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryError {
+    /// The column (1-based, in characters) where the error occurred
+    pub column: usize,
+    pub message: String,
+}
+impl QueryError {
+    fn new(column: usize, message: impl Into<String>) -> QueryError {
+        QueryError {
+            column,
+            message: message.into(),
+        }
+    }
+
+    /// Render the query text with a caret pointing at the offending column
+    pub fn display_with_source(&self, source: &str) -> String {
+        format!(
+            "  {}\n  {}^ {}",
+            source,
+            " ".repeat(self.column.saturating_sub(1)),
+            self.message
+        )
+    }
+}
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+impl std::error::Error for QueryError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TokenKind {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    /// A leading `-` on a term
+    Minus,
+    /// A leading `+` on a term
+    Plus,
+    Word(String),
+    Quoted(String),
+    /// `key~text` or `key:contains(text)` with quoted or parenthesized text
+    Contains {
+        key: String,
+        text: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    column: usize,
+}
+
+fn lex(text: &str) -> Result<Vec<Token>, QueryError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    // Whether we're at the start of a term, where `-` and `+` are operators rather than part of a tag
+    let mut term_start = true;
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        if c.is_whitespace() {
+            term_start = true;
+            i += 1;
+            continue;
+        }
+
+        match c {
+            '(' => {
+                tokens.push(Token {
+                    kind: TokenKind::LParen,
+                    column,
+                });
+                term_start = true;
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token {
+                    kind: TokenKind::RParen,
+                    column,
+                });
+                i += 1;
+            }
+            '-' | '+' if term_start => {
+                let kind = if c == '-' {
+                    TokenKind::Minus
+                } else {
+                    TokenKind::Plus
+                };
+                tokens.push(Token { kind, column });
+                i += 1;
+            }
+            '"' | '\'' if c == '"' || term_start => {
+                let value = lex_quoted(&chars, &mut i)?;
+                if value.is_empty() {
+                    return Err(QueryError::new(column, "empty quoted tag"));
+                }
+                tokens.push(Token {
+                    kind: TokenKind::Quoted(value),
+                    column,
+                });
+                term_start = false;
+            }
+            _ => {
+                let mut word = String::new();
+                let mut contains = None;
+                while let Some(&x) = chars.get(i) {
+                    if x == '"' && word.ends_with('~') {
+                        // `key~"some text"`
+                        word.pop();
+                        contains = Some(lex_quoted(&chars, &mut i)?);
+                        break;
+                    } else if x == '(' && word.ends_with(CONTAINS_SUFFIX) {
+                        // `key:contains(some text)`
+                        word.truncate(word.len() - CONTAINS_SUFFIX.len());
+                        contains = Some(lex_contains_argument(&chars, &mut i)?);
+                        break;
+                    } else if x.is_whitespace() || x == '(' || x == ')' || x == '"' {
+                        break;
+                    }
+                    word.push(x);
+                    i += 1;
+                }
+
+                let kind = if let Some(text) = contains {
+                    TokenKind::Contains { key: word, text }
+                } else {
+                    match word.as_str() {
+                        "and" | "AND" => TokenKind::And,
+                        "or" | "OR" => TokenKind::Or,
+                        "not" | "NOT" => TokenKind::Not,
+                        _ => TokenKind::Word(word),
+                    }
+                };
+                tokens.push(Token { kind, column });
+                term_start = false;
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+const CONTAINS_SUFFIX: &str = ":contains";
+
+/// Lex a quoted string starting at the quote character at `i`, leaving `i` just past the closing quote.
+fn lex_quoted(chars: &[char], i: &mut usize) -> Result<String, QueryError> {
+    let column = *i + 1;
+    let quote = chars[*i];
+    let mut value = String::new();
+    *i += 1;
+    loop {
+        match chars.get(*i) {
+            None => return Err(QueryError::new(column, "unterminated quoted text")),
+            Some('\\') if matches!(chars.get(*i + 1), Some(&x) if x == quote || x == '\\') => {
+                value.push(chars[*i + 1]);
+                *i += 2;
+            }
+            Some(&x) if x == quote => {
+                *i += 1;
+                break;
+            }
+            Some(&x) => {
+                value.push(x);
+                *i += 1;
+            }
+        }
+    }
+
+    Ok(value)
+}
+
+/// Lex the `(text)` argument of `key:contains(text)`, starting at the `(` at `i`.  
+/// The text may itself be quoted if it needs to contain a `)`.
+fn lex_contains_argument(chars: &[char], i: &mut usize) -> Result<String, QueryError> {
+    let column = *i + 1;
+    *i += 1;
+    let text = if matches!(chars.get(*i), Some('"' | '\'')) {
+        lex_quoted(chars, i)?
+    } else {
+        let mut text = String::new();
+        while let Some(&x) = chars.get(*i) {
+            if x == ')' {
+                break;
+            }
+            text.push(x);
+            *i += 1;
+        }
+        text
+    };
+
+    if chars.get(*i) != Some(&')') {
+        return Err(QueryError::new(
+            column,
+            "unclosed `contains(`, expected a matching `)`",
+        ));
+    }
+    *i += 1;
+
+    Ok(text)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// The column just past the end of the text, used for errors at the end of the query
+    end_column: usize,
+}
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn parse_query(&mut self) -> Result<Expr, QueryError> {
+        if self.tokens.is_empty() {
+            return Ok(Expr::All);
+        }
+
+        let expr = self.parse_or()?;
+        if let Some(token) = self.peek() {
+            // The only thing that can stop an expression early is an unmatched `)`
+            return Err(QueryError::new(token.column, "unmatched `)`"));
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, QueryError> {
+        let mut exprs = vec![self.parse_and()?];
+        while matches!(
+            self.peek(),
+            Some(Token {
+                kind: TokenKind::Or,
+                ..
+            })
+        ) {
+            self.next();
+            exprs.push(self.parse_and()?);
+        }
+
+        Ok(if exprs.len() == 1 {
+            exprs.remove(0)
+        } else {
+            Expr::Or(exprs)
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, QueryError> {
+        let mut exprs = vec![self.parse_unary()?];
+        loop {
+            match self.peek().map(|x| &x.kind) {
+                Some(TokenKind::And) => {
+                    self.next();
+                    exprs.push(self.parse_unary()?);
+                }
+                // Implicit `and` between adjacent terms
+                Some(
+                    TokenKind::Not
+                    | TokenKind::Minus
+                    | TokenKind::Plus
+                    | TokenKind::LParen
+                    | TokenKind::Word(_)
+                    | TokenKind::Quoted(_)
+                    | TokenKind::Contains { .. },
+                ) => {
+                    exprs.push(self.parse_unary()?);
+                }
+                _ => break,
+            }
+        }
+
+        Ok(if exprs.len() == 1 {
+            exprs.remove(0)
+        } else {
+            Expr::And(exprs)
+        })
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, QueryError> {
+        match self.peek().map(|x| &x.kind) {
+            Some(TokenKind::Not | TokenKind::Minus) => {
+                self.next();
+                Ok(Expr::Not(Box::new(self.parse_unary()?)))
+            }
+            Some(TokenKind::Plus) => {
+                self.next();
+                self.parse_unary()
+            }
+            _ => self.parse_atom(),
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<Expr, QueryError> {
+        let end_column = self.end_column;
+        let token = self
+            .next()
+            .ok_or_else(|| QueryError::new(end_column, "expected a tag or `(`"))?;
+        match token.kind {
+            TokenKind::LParen => {
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(Token {
+                        kind: TokenKind::RParen,
+                        ..
+                    }) => Ok(expr),
+                    Some(other) => Err(QueryError::new(other.column, "expected `)`")),
+                    None => Err(QueryError::new(
+                        token.column,
+                        "unclosed `(`, expected a matching `)`",
+                    )),
+                }
+            }
+            TokenKind::Word(word) => parse_word(word, token.column),
+            TokenKind::Contains { key, text } => contains_expr(key, text, token.column),
+            TokenKind::Quoted(value) => Ok(Expr::Tag(TagPattern::Exact(value))),
+            TokenKind::RParen => Err(QueryError::new(token.column, "unexpected `)`")),
+            TokenKind::And | TokenKind::Or => Err(QueryError::new(
+                token.column,
+                "expected a tag before the operator",
+            )),
+            TokenKind::Not | TokenKind::Minus | TokenKind::Plus => {
+                unreachable!("unary operators are handled by parse_unary")
+            }
+        }
+    }
+}
+
+/// Parse an unquoted word, which is either a field predicate or a tag
+fn parse_word(word: String, column: usize) -> Result<Expr, QueryError> {
+    if let Some(key) = word.strip_prefix("has:") {
+        return Ok(Expr::Field(FieldPredicate::Has(field_key(key, column)?)));
+    } else if let Some(key) = word.strip_prefix("missing:") {
+        return Ok(Expr::Not(Box::new(Expr::Field(FieldPredicate::Has(
+            field_key(key, column)?,
+        )))));
+    } else if let Some(pattern) = word.strip_prefix("orig:") {
+        if pattern.is_empty() {
+            return Err(QueryError::new(
+                column,
+                "expected a filename pattern after `orig:`",
+            ));
+        }
+        return Ok(Expr::Field(FieldPredicate::OriginalFilename(
+            pattern.to_string(),
+        )));
+    } else if let Some((key, text)) = word.split_once('~') {
+        return contains_expr(key.to_string(), text.to_string(), column);
+    } else if let Some(range) = parse_range(&word, column)? {
+        return Ok(Expr::Tag(TagPattern::Range(range)));
+    }
+
+    if word.contains(['*', '?']) {
+        Ok(Expr::Tag(TagPattern::Glob(word)))
+    } else {
+        Ok(Expr::Tag(TagPattern::Exact(word)))
+    }
+}
+
+/// Parse `namespace:>=value` and the other comparisons, or `namespace:start..end`
+fn parse_range(word: &str, column: usize) -> Result<Option<ValueRange>, QueryError> {
+    let Some((namespace, value)) = namespaces::split(word) else {
+        return Ok(None);
+    };
+
+    let (start, end) = if let Some(op) = [">=", "<=", ">", "<"]
+        .into_iter()
+        .find(|op| value.starts_with(op))
+    {
+        let x = value[op.len()..].to_string();
+        if x.is_empty() {
+            return Err(QueryError::new(
+                column,
+                format!("expected a value after `{}`", op),
+            ));
+        }
+        match op {
+            ">=" => (Bound::Included(x), Bound::Unbounded),
+            "<=" => (Bound::Unbounded, Bound::Included(x)),
+            ">" => (Bound::Excluded(x), Bound::Unbounded),
+            _ => (Bound::Unbounded, Bound::Excluded(x)),
+        }
+    } else if let Some((start, end)) = value.split_once("..") {
+        if start.is_empty() && end.is_empty() {
+            return Err(QueryError::new(
+                column,
+                "expected a value on at least one side of `..`",
+            ));
+        }
+        let bound = |x: &str| {
+            if x.is_empty() {
+                Bound::Unbounded
+            } else {
+                Bound::Included(x.to_string())
+            }
+        };
+        (bound(start), bound(end))
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(ValueRange {
+        namespace: namespace.to_string(),
+        start,
+        end,
+        kind: None,
+    }))
+}
+
+fn field_key(key: &str, column: usize) -> Result<String, QueryError> {
+    if key.is_empty() {
+        Err(QueryError::new(column, "expected a field name"))
+    } else {
+        Ok(key.to_string())
+    }
+}
+
+fn contains_expr(key: String, text: String, column: usize) -> Result<Expr, QueryError> {
+    let key = field_key(&key, column)?;
+    if text.is_empty() {
+        return Err(QueryError::new(
+            column,
+            format!("expected text to search for in `{}`", key),
+        ));
+    }
+
+    Ok(Expr::Field(FieldPredicate::Contains { key, text }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        aliases::Aliases, implications::Implications, namespaces::NamespaceType, storage::FileInfo,
+    };
+
+    use super::{glob_match, Expr, FieldPredicate, Query, TagPattern};
+
+    fn file(tags: &[&str]) -> FileInfo {
+        FileInfo {
+            filename: "file".to_string(),
+            tags: tags.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn file_with_fields(original_filename: &str, comments: &[(&str, &str)]) -> FileInfo {
+        FileInfo {
+            filename: "file".to_string(),
+            original_filename: Some(original_filename.to_string()),
+            comments: comments
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn tag(x: &str) -> Expr {
+        Expr::Tag(TagPattern::Exact(x.to_string()))
+    }
+
+    #[test]
+    fn test_hierarchy() {
+        let transformers = file(&["topic/ml/transformers", "genre:fantasy"]);
+        for text in [
+            "topic",
+            "topic/ml",
+            "topic/ml/transformers",
+            "genre",
+            "topic/m?",
+        ] {
+            assert!(
+                Query::parse(text).unwrap().matches(&transformers),
+                "{}",
+                text
+            );
+        }
+        for text in ["topic/ml/rnn", "topic/m", "-topic", "transformers"] {
+            assert!(
+                !Query::parse(text).unwrap().matches(&transformers),
+                "{}",
+                text
+            );
+        }
+
+        let query = Query::parse("topic -draft").unwrap();
+        assert!(query.matches_tag("topic/ml/transformers"));
+        assert!(!query.matches_tag("topical"));
+    }
+
+    #[test]
+    fn test_aliases() {
+        let mut aliases = Aliases::default();
+        aliases.add("dl", "deep-learning").unwrap();
+        aliases.add("deeplearning", "deep-learning").unwrap();
+
+        let query = Query::parse("dl -draft").unwrap().with_aliases(&aliases);
+        assert!(query.matches(&file(&["deep-learning"])));
+        assert!(query.matches(&file(&["deeplearning"])));
+        assert!(!query.matches(&file(&["deep-learning", "draft"])));
+        assert!(!query.matches(&file(&["paper"])));
+        assert!(query.matches_tag("dl"));
+
+        let query = Query::parse("deep-learning")
+            .unwrap()
+            .with_aliases(&aliases);
+        assert!(query.matches(&file(&["dl"])));
+
+        // Patterns find every name of the tags whose alias or canonical name they match
+        let query = Query::parse("d?").unwrap().with_aliases(&aliases);
+        assert!(query.matches(&file(&["deep-learning"])));
+        assert!(query.matches(&file(&["deeplearning"])));
+        let query = Query::parse("deep-*").unwrap().with_aliases(&aliases);
+        assert!(query.matches(&file(&["dl"])));
+        assert!(!query.matches(&file(&["paper"])));
+    }
+
+    #[test]
+    fn test_ranges() {
+        let matches = |text: &str, tags: &[&str]| Query::parse(text).unwrap().matches(&file(tags));
+        assert!(matches("year:>=2019", &["year:2019"]));
+        assert!(matches("year:>2019", &["year:2021"]));
+        assert!(!matches("year:>2019", &["year:2019"]));
+        assert!(matches("year:<2019 book", &["year:999", "book"]));
+        assert!(matches("rating:4..5", &["rating:5"]));
+        assert!(!matches("rating:4..5", &["rating:3", "other:4"]));
+        assert!(matches("rating:..3", &["rating:3"]));
+        assert!(matches("-rating:4..", &["rating:3"]));
+        // Untyped values that aren't integers are compared alphabetically
+        assert!(matches("read:>=2021-01-01", &["read:2021-05-30"]));
+        assert!(Query::parse("year:>=").is_err());
+        assert!(Query::parse("year:..").is_err());
+        assert_eq!(
+            Query::parse("year:2019").unwrap().expr,
+            Expr::Tag(TagPattern::Exact("year:2019".to_string()))
+        );
+        // Quoting tags that look like ranges matches them literally
+        assert!(matches!(
+            Query::parse("version:1..2").unwrap().expr,
+            Expr::Tag(TagPattern::Range(_))
+        ));
+        assert_eq!(
+            Query::parse("\"version:1..2\" 'note:<draft>'")
+                .unwrap()
+                .expr,
+            Expr::And(vec![
+                Expr::Tag(TagPattern::Exact("version:1..2".to_string())),
+                Expr::Tag(TagPattern::Exact("note:<draft>".to_string())),
+            ])
+        );
+
+        let namespaces = BTreeMap::from([
+            ("year".to_string(), NamespaceType::Integer),
+            (
+                "status".to_string(),
+                NamespaceType::Enum(vec![
+                    "todo".to_string(),
+                    "reading".to_string(),
+                    "done".to_string(),
+                ]),
+            ),
+        ]);
+        let query = Query::parse("status:>=reading")
+            .unwrap()
+            .with_namespaces(&namespaces)
+            .unwrap();
+        assert!(query.matches(&file(&["status:done"])));
+        assert!(!query.matches(&file(&["status:todo"])));
+        assert!(!query.matches(&file(&["status:unknown"])));
+        assert!(query.matches_tag("status:reading"));
+        assert!(Query::parse("year:>=soon")
+            .unwrap()
+            .with_namespaces(&namespaces)
+            .is_err());
+    }
+
+    #[test]
+    fn test_implications() {
+        let mut implications = Implications::default();
+        implications.add("novel", "book").unwrap();
+        implications.add("book", "text").unwrap();
+        implications.add("epic", "genre:fantasy").unwrap();
+
+        let query = Query::parse("text -draft")
+            .unwrap()
+            .with_implications(&implications);
+        assert!(query.matches(&file(&["novel"])));
+        assert!(query.matches(&file(&["text"])));
+        assert!(!query.matches(&file(&["novel", "draft"])));
+        assert!(!query.matches(&file(&["paper"])));
+
+        let query = Query::parse("genre")
+            .unwrap()
+            .with_implications(&implications);
+        assert!(query.matches(&file(&["epic"])));
+        assert!(!Query::parse("book")
+            .unwrap()
+            .with_implications(&implications)
+            .matches(&file(&["text"])));
+
+        // Patterns and ranges are found through the tags that imply what they match too
+        let query = Query::parse("te*")
+            .unwrap()
+            .with_implications(&implications);
+        assert!(query.matches(&file(&["novel"])));
+        assert!(!query.matches(&file(&["paper"])));
+        implications.add("2nd-edition", "edition:2").unwrap();
+        let query = Query::parse("edition:>=2")
+            .unwrap()
+            .with_implications(&implications);
+        assert!(query.matches(&file(&["2nd-edition"])));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Query::parse("").unwrap().expr, Expr::All);
+        assert_eq!(
+            Query::parse("a b -c").unwrap().expr,
+            Expr::And(vec![tag("a"), tag("b"), Expr::Not(Box::new(tag("c")))])
+        );
+        assert_eq!(
+            Query::parse("a or b and c").unwrap().expr,
+            Expr::Or(vec![tag("a"), Expr::And(vec![tag("b"), tag("c")])])
+        );
+        assert_eq!(
+            Query::parse("\"and\" deep-learning").unwrap().expr,
+            Expr::And(vec![tag("and"), tag("deep-learning")])
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = Query::parse("(paper or book").unwrap_err();
+        assert_eq!(err.column, 1);
+        let err = Query::parse("paper or").unwrap_err();
+        assert_eq!(err.column, 9);
+        let err = Query::parse("paper )").unwrap_err();
+        assert_eq!(err.column, 7);
+        let err = Query::parse("paper \"draft").unwrap_err();
+        assert_eq!(err.column, 7);
+    }
+
+    #[test]
+    fn test_matches() {
+        let query = Query::parse("(paper or book) and -draft and genre:*").unwrap();
+        assert!(query.matches(&file(&["paper", "genre:fantasy"])));
+        assert!(!query.matches(&file(&["paper", "genre:fantasy", "draft"])));
+        assert!(!query.matches(&file(&["book"])));
+        assert!(!query.matches(&file(&["article", "genre:scifi"])));
+
+        let query = Query::parse("Paper").unwrap().with_case_insensitive(true);
+        assert!(query.matches(&file(&["paper"])));
+        assert!(query.matches_tag("PAPER"));
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            Query::parse_args(&["paper", "(book or draft)"]).unwrap(),
+            Query::parse("paper (book or draft)").unwrap()
+        );
+        assert_eq!(
+            Query::parse_args(&["title~\"a b\"", "c"]).unwrap(),
+            Query::parse("title~\"a b\" c").unwrap()
+        );
+        // Quotes can't join arguments together
+        let err = Query::parse_args(&["paper", "\"my", "tag\""]).unwrap_err();
+        assert_eq!(err.column, 7);
+        assert!(Query::parse_args(&["desc:contains(a", "b)"]).is_err());
+        assert_eq!(Query::parse_args(&["a", "and"]).unwrap_err().column, 6);
+        assert_eq!(Query::parse_args::<&str>(&[]).unwrap().expr, Expr::All);
+    }
+
+    #[test]
+    fn test_fields() {
+        let contains = |key: &str, text: &str| {
+            Expr::Field(FieldPredicate::Contains {
+                key: key.to_string(),
+                text: text.to_string(),
+            })
+        };
+        assert_eq!(
+            Query::parse("title~\"neural net\"").unwrap().expr,
+            contains("title", "neural net")
+        );
+        assert_eq!(
+            Query::parse("desc:contains(neural net) book").unwrap().expr,
+            Expr::And(vec![contains("desc", "neural net"), tag("book")])
+        );
+        assert_eq!(
+            Query::parse("title~neural").unwrap().expr,
+            contains("title", "neural")
+        );
+        // A field predicate can follow a tag like any other term
+        assert_eq!(
+            Query::parse("book title~\"neural\"").unwrap().expr,
+            Expr::And(vec![tag("book"), contains("title", "neural")])
+        );
+        assert_eq!(
+            Query::parse("book desc:contains(x)").unwrap().expr,
+            Expr::And(vec![tag("book"), contains("desc", "x")])
+        );
+        assert_eq!(Query::parse("\"has:desc\"").unwrap().expr, tag("has:desc"));
+        assert_eq!(Query::parse("a desc:contains(x").unwrap_err().column, 16);
+
+        let file = file_with_fields("Story.epub", &[("title", "Neural Networks")]);
+        assert!(Query::parse("title~neural").unwrap().matches(&file));
+        assert!(Query::parse("orig:*.epub has:title missing:desc")
+            .unwrap()
+            .matches(&file));
+        assert!(!Query::parse("has:desc").unwrap().matches(&file));
+        assert!(!Query::parse("orig:*.EPUB").unwrap().matches(&file));
+        assert!(Query::parse("orig:*.EPUB")
+            .unwrap()
+            .with_case_insensitive(true)
+            .matches(&file));
+    }
+
+    #[test]
+    fn test_required_tags() {
+        let required = |text: &str| -> Vec<String> {
+            Query::parse(text)
+                .unwrap()
+                .required_tags()
+                .into_iter()
+                .map(str::to_string)
+                .collect()
+        };
+        assert_eq!(
+            required("paper (book and long) -draft"),
+            ["paper", "book", "long"]
+        );
+        assert!(required("paper or book").is_empty());
+        assert!(required("genre:* has:desc").is_empty());
+        assert!(Query::parse("paper")
+            .unwrap()
+            .with_case_insensitive(true)
+            .required_tags()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob_match("genre:*", "genre:fantasy", false));
+        assert!(glob_match("*.epub", "story.epub", false));
+        assert!(glob_match("b?ok", "book", false));
+        assert!(!glob_match("b?ok", "brook", false));
+        assert!(glob_match("*a*b*", "xxaxxbxx", false));
+        assert!(!glob_match("*a*b", "xxaxxbxx", false));
+    }
+}
@@ -23,15 +23,134 @@ use std::{
 use serde::{Deserialize, Serialize};
 use toml::value::Datetime;
 
-use crate::storage::Storage;
+use crate::{
+    aliases::Aliases,
+    implications::Implications,
+    migrations::{upgrade_state, STATE_VERSION},
+    storage::Storage,
+    util::{datetime_to_unix, hash_file, write_atomic},
+    view::View,
+};
 
 /// The currently active state.  
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct State {
+    /// The version of the layout of the state file, see [`crate::migrations`]
+    #[serde(default)]
+    pub version: u32,
+
+    /// The registration-area used to be stored in the state file. This is only read, so that
+    /// old state files can have it moved into the [`Staging`] file, see
+    /// [`crate::tagg::Tagg::migrate_staging`].
+    #[serde(default, skip_serializing)]
+    pub staging: Option<Staging>,
+
+    /// Other names for tags, see [`crate::aliases`]
+    #[serde(default, skip_serializing_if = "Aliases::is_empty")]
+    pub aliases: Aliases,
+
+    /// Rules for tags that imply other tags, see [`crate::implications`]
+    #[serde(default, skip_serializing_if = "Implications::is_empty")]
+    pub implications: Implications,
+
+    /// The folders of links which are kept up to date by `tagg view refresh`
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
-    pub registration_area: Vec<AddedFile>,
+    pub views: Vec<View>,
+
+    /// The stored files, when using the toml storage-backend
+    #[serde(default)]
+    pub storage: Storage,
+}
+impl State {
+    /// Load the state from the state file, creating it if it doesn't already exist.  
+    pub fn load_from(state_path: &Path) -> eyre::Result<State> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(state_path)?;
+        let mut buf = String::new();
+        file.read_to_string(&mut buf)?;
 
+        State::parse(&buf)
+    }
+
+    /// Parse the contents of a state file, upgrading it if it is from an older version of tagg
+    pub fn parse(buf: &str) -> eyre::Result<State> {
+        let mut table: toml::value::Table = toml::from_str(buf)?;
+        let version = upgrade_state(&mut table)?;
+        if version == STATE_VERSION {
+            return Ok(toml::from_str(buf)?);
+        }
+        if !buf.trim().is_empty() {
+            eprintln!(
+                "INFO: Upgraded the state file from version {} to version {}, it will be written in the new format the next time it is saved",
+                version, STATE_VERSION
+            );
+        }
+
+        // Deserializing straight from a `toml::Value` turns datetimes into strings, so the
+        // upgraded table goes back through its text instead
+        let state = toml::from_str(&toml::to_string(&toml::Value::Table(table))?)?;
+        Ok(state)
+    }
+
+    /// Save the state, keeping the previous `backups` versions of the file around as
+    /// `<state-file>.1` (the most recent) to `<state-file>.<backups>`.  
+    /// Nothing is written if the file already holds the same state, so that the backups aren't
+    /// pushed out by identical copies.
+    pub fn save_to(&self, path: &Path, backups: usize) -> eyre::Result<()> {
+        let data = toml::to_string_pretty(self)?;
+        if std::fs::read(path).is_ok_and(|current| current == data.as_bytes()) {
+            return Ok(());
+        }
+        rotate_backups(path, backups)?;
+        write_atomic(path, data.as_bytes())
+    }
+}
+
+/// The path of the `n`th most recent backup of the state file
+pub fn backup_path(state_path: &Path, n: usize) -> PathBuf {
+    let mut name = state_path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Shift each backup of the file back by one, dropping the oldest, and make the current file
+/// the most recent backup.
+pub fn rotate_backups(path: &Path, backups: usize) -> eyre::Result<()> {
+    if backups == 0 || !path.exists() {
+        return Ok(());
+    }
+
+    let oldest = backup_path(path, backups);
+    if oldest.exists() {
+        std::fs::remove_file(&oldest)?;
+    }
+    for n in (1..backups).rev() {
+        let backup = backup_path(path, n);
+        if backup.exists() {
+            std::fs::rename(&backup, backup_path(path, n + 1))?;
+        }
+    }
+
+    // The file is only ever replaced by renaming over it, never written in place, so a hardlink
+    // keeps the old contents without having to copy them.
+    let newest = backup_path(path, 1);
+    if std::fs::hard_link(path, &newest).is_err() {
+        std::fs::copy(path, &newest)?;
+    }
+
+    Ok(())
+}
+
+/// The registration-area, which is stored separately from the [`State`] so that adding files
+/// doesn't require reading and rewriting the entire storage catalogue.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Staging {
     /// The time that the registration was last modified.  
     /// If this was more than [`Config::registration_delay_limit`] then we dump the registration
     /// state. This is to avoid accidentally leaving a file in the registration without adding it,
@@ -39,45 +158,83 @@ pub struct State {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub last_registration: Option<Datetime>,
 
-    pub storage: Storage,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub registration_area: Vec<AddedFile>,
+
+    /// Files that were moved out of the registration-area because it went untouched for longer
+    /// than [`Config::registration_delay_limit`].  
+    /// These are kept so that they can be brought back with `tagg restore-staged`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expired: Vec<AddedFile>,
 }
-impl State {
-    /// Load the state from the state file, creating it if it doesn't already exist.  
-    pub fn load_from(state_path: &Path) -> eyre::Result<State> {
+impl Staging {
+    /// Load the registration-area from its file, creating it if it doesn't already exist.  
+    pub fn load_from(staging_path: &Path) -> eyre::Result<Staging> {
         let mut file = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(state_path)?;
+            .truncate(false)
+            .open(staging_path)?;
         let mut buf = String::new();
         file.read_to_string(&mut buf)?;
 
-        let state = toml::from_str(&buf)?;
-        Ok(state)
+        let staging = toml::from_str(&buf)?;
+        Ok(staging)
     }
 
     pub fn save_to(&self, path: &Path) -> eyre::Result<()> {
         let data = toml::to_string_pretty(self)?;
-        std::fs::write(path, data)?;
-        Ok(())
+        write_atomic(path, data.as_bytes())
+    }
+
+    /// Whether the registration-area has files and was last modified more than `limit` seconds
+    /// before `now`
+    pub fn is_expired(&self, limit: u64, now: u64) -> bool {
+        let last = self.last_registration.as_ref().and_then(datetime_to_unix);
+        let last = if let Some(last) = last {
+            last
+        } else {
+            return false;
+        };
+
+        limit != 0 && now.saturating_sub(last) > limit && !self.registration_area.is_empty()
+    }
+
+    /// If the registration-area was last modified more than `limit` seconds before `now`, then
+    /// move its files into the expired list.  
+    /// Returns the number of files that were expired.
+    pub fn expire(&mut self, limit: u64, now: u64) -> usize {
+        if !self.is_expired(limit, now) {
+            return 0;
+        }
+
+        let count = self.registration_area.len();
+        self.expired.append(&mut self.registration_area);
+        count
     }
 }
 
 /// The name of the main 'comment' field
 pub const COMMENT_MAIN: &str = "comment";
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 /// This is data for a file that has yet to be registered.
 pub struct AddedFile {
     /// The absolute path to the file location
     pub path: PathBuf,
 
-    /// The hash of the file when it was added.  
+    /// The hash of the file when it was added, as a lowercase hex BLAKE3 digest.  
     /// We aren't strict about this, but it does let us alert the user that they seem to
     /// be adding a file that has changed.  
     /// This can be `None` if it is disabled.
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub hash: Option<u64>,
+    pub hash: Option<String>,
+
+    /// Whether the user already chose to store this file even though an identical file is
+    /// in storage, so that we don't ask again when committing.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub allow_duplicate: bool,
 
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub tags: Vec<String>,
@@ -90,7 +247,11 @@ pub struct AddedFile {
 impl AddedFile {
     /// Check if the file still exists
     pub fn exists(&self) -> eyre::Result<bool> {
-        todo!()
+        match std::fs::metadata(&self.path) {
+            Ok(meta) => Ok(meta.is_file()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
     }
 
     /// Check if the file still exists (returns `Some(_)`) and
@@ -101,17 +262,33 @@ impl AddedFile {
     /// `Some(false)` if file exists and hash is incorrect
     /// `None` if file does not exist  
     pub fn exists_hash_correct(&self) -> eyre::Result<Option<bool>> {
-        todo!()
+        if !self.exists()? {
+            return Ok(None);
+        }
+
+        if let Some(hash) = &self.hash {
+            Ok(Some(hash_file(&self.path)? == *hash))
+        } else {
+            Ok(Some(true))
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::{collections::HashMap, path::PathBuf};
+    use std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+    };
 
     use crate::{
-        state::{AddedFile, State},
-        storage::Storage,
+        aliases::Aliases,
+        implications::Implications,
+        migrations::STATE_VERSION,
+        state::{backup_path, AddedFile, Staging, State},
+        storage::{FileInfo, Storage},
+        util::{datetime_to_unix, hash_file, unix_to_datetime, TempDir},
+        view::{LinkKind, View},
     };
 
     // This test ensures that we can properly serialize the structures.
@@ -119,7 +296,19 @@ mod test {
     // And will error if the ordering is ''bad''
     #[test]
     fn test_serialize() {
-        let storage = Storage { files: Vec::new() };
+        let stored_file = FileInfo {
+            filename: "a0.txt".to_string(),
+            original_filename: Some("toaster.txt".to_string()),
+            hash: Some("af13".to_string()),
+            size: Some(4),
+            modified: Some(1668000000),
+            perceptual_hash: None,
+            tags: vec!["kitchen".to_string()],
+            comments: HashMap::from([("title".to_string(), "Toaster".to_string())]),
+        };
+        let storage = Storage {
+            files: vec![stored_file],
+        };
 
         let storage_text = toml::to_string(&storage).unwrap();
         println!("Storage: {}", storage_text);
@@ -127,19 +316,140 @@ mod test {
         let file = AddedFile {
             path: PathBuf::from("toaster.txt"),
             hash: None,
+            allow_duplicate: false,
             comment: HashMap::new(),
             tags: vec![],
         };
         let file_text = toml::to_string(&file).unwrap();
         println!("File: {}", file_text);
 
+        let staging = Staging {
+            last_registration: Some(unix_to_datetime(1668000000)),
+            registration_area: vec![file.clone()],
+            expired: vec![file],
+        };
+        let staging_text = toml::to_string(&staging).unwrap();
+        println!("Staging: {}", staging_text);
+
+        let mut aliases = Aliases::default();
+        aliases.add("dl", "deep-learning").unwrap();
+        let mut implications = Implications::default();
+        implications.add("novel", "book").unwrap();
         let state = State {
-            registration_area: vec![file],
-            last_registration: None,
+            version: STATE_VERSION,
+            staging: None,
+            aliases,
+            implications,
+            views: vec![View {
+                path: PathBuf::from("/views/papers"),
+                query: "paper -draft".to_string(),
+                link: LinkKind::Symlink,
+                titles: false,
+            }],
             storage,
         };
 
-        let state = toml::to_string(&state).unwrap();
-        println!("State: {}", state);
+        let state_text = toml::to_string(&state).unwrap();
+        println!("State: {}", state_text);
+
+        let state: State = toml::from_str(&state_text).unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(state.storage.files[0].hash.as_deref(), Some("af13"));
+        assert_eq!(state.aliases.resolve("dl"), "deep-learning");
+        assert_eq!(state.implications.implied_by("novel").len(), 1);
+    }
+
+    #[test]
+    fn test_datetime() {
+        let datetime = unix_to_datetime(1668000000);
+        assert_eq!(datetime.to_string(), "2022-11-09T13:20:00Z");
+        assert_eq!(datetime_to_unix(&datetime), Some(1668000000));
+
+        let offset: toml::value::Datetime = "2022-11-09T14:20:00+01:00".parse().unwrap();
+        assert_eq!(datetime_to_unix(&offset), Some(1668000000));
+        let leap: toml::value::Datetime = "2024-02-29T00:00:00Z".parse().unwrap();
+        assert_eq!(unix_to_datetime(datetime_to_unix(&leap).unwrap()), leap);
+    }
+
+    #[test]
+    fn test_expire() {
+        let file = AddedFile {
+            path: PathBuf::from("toaster.txt"),
+            hash: None,
+            allow_duplicate: false,
+            comment: HashMap::new(),
+            tags: vec![],
+        };
+        let mut staging = Staging {
+            last_registration: Some(unix_to_datetime(1000)),
+            registration_area: vec![file],
+            expired: Vec::new(),
+        };
+
+        assert_eq!(staging.expire(100, 1050), 0);
+        assert_eq!(staging.expire(0, 5000), 0);
+        assert_eq!(staging.expire(100, 1101), 1);
+        assert!(staging.registration_area.is_empty());
+        assert_eq!(staging.expired.len(), 1);
+    }
+
+    #[test]
+    fn test_backups() {
+        let dir = TempDir::new();
+        let path = dir.join("state.toml");
+
+        let mut state = State::parse("").unwrap();
+        for i in 0..4 {
+            state.views = (0..i)
+                .map(|_| View {
+                    path: PathBuf::from("/views/papers"),
+                    query: "paper".to_string(),
+                    link: LinkKind::Symlink,
+                    titles: false,
+                })
+                .collect();
+            state.save_to(&path, 2).unwrap();
+        }
+
+        let views = |path: &Path| {
+            State::parse(&std::fs::read_to_string(path).unwrap())
+                .unwrap()
+                .views
+                .len()
+        };
+        assert_eq!(views(&path), 3);
+        assert_eq!(views(&backup_path(&path, 1)), 2);
+        assert_eq!(views(&backup_path(&path, 2)), 1);
+        assert!(!backup_path(&path, 3).exists());
+
+        // Saving the same state again keeps the backups as they are
+        state.save_to(&path, 2).unwrap();
+        assert_eq!(views(&backup_path(&path, 1)), 2);
+    }
+
+    #[test]
+    fn test_exists_hash_correct() {
+        let dir = TempDir::new();
+        let path = dir.join("file.txt");
+        std::fs::write(&path, "original").unwrap();
+
+        let mut file = AddedFile {
+            path: path.clone(),
+            hash: None,
+            allow_duplicate: false,
+            comment: HashMap::new(),
+            tags: vec![],
+        };
+        assert_eq!(file.exists_hash_correct().unwrap(), Some(true));
+
+        file.hash = Some(hash_file(&path).unwrap());
+        assert_eq!(file.exists_hash_correct().unwrap(), Some(true));
+
+        std::fs::write(&path, "modified").unwrap();
+        assert_eq!(file.exists_hash_correct().unwrap(), Some(false));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(!file.exists().unwrap());
+        assert_eq!(file.exists_hash_correct().unwrap(), None);
     }
 }
@@ -0,0 +1,322 @@
+//! The information about the stored files (their tags, comments, hashes and so on) is kept behind
+//! the [`StorageBackend`] trait, while the files themselves are stuffed into a single folder.  
+//!
+//! There are two backends, chosen with `storage-backend` in the config:
+//! - [`Storage`] (`toml`, the default) keeps the entries inside the state file, which is simple
+//!   and easy to read or fix by hand, but has to be parsed by every command and rewritten in
+//!   full whenever anything changes.
+//! - [`SqliteStorage`] (`sqlite`) keeps them in an SQLite database with indexes for ids, hashes,
+//!   tags and comments, for catalogues too large to comfortably rewrite as toml.
+//!
+//! `tagg migrate` moves a catalogue between the two.
+
+use std::{collections::HashMap, path::Path, time::UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    query::Query,
+    util::{hash_file, is_image_extension, perceptual_hash},
+};
+
+mod sqlite;
+pub use sqlite::SqliteStorage;
+
+/// Which [`StorageBackend`] the file information is kept in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageBackendKind {
+    /// Kept in the state file, see [`Storage`]
+    #[default]
+    Toml,
+    /// Kept in an SQLite database, see [`SqliteStorage`]
+    Sqlite,
+}
+impl StorageBackendKind {
+    /// The name used for it in the config
+    pub fn name(self) -> &'static str {
+        match self {
+            StorageBackendKind::Toml => "toml",
+            StorageBackendKind::Sqlite => "sqlite",
+        }
+    }
+}
+
+/// A place that the information about the stored files is kept in.  
+/// This works with owned [`FileInfo`]s rather than references, since a database can't hand out
+/// references into itself. Modified files have to be given back with [`StorageBackend::update`].
+pub trait StorageBackend {
+    /// Given some prefix (or exact version) of the id, get the files it could refer to
+    fn find_from_prefix(&self, prefix: &str) -> eyre::Result<Vec<FileInfo>>;
+
+    /// Get all the files whose contents have the given hash
+    fn find_by_hash(&self, hash: &str) -> eyre::Result<Vec<FileInfo>>;
+
+    /// Get all the files which match the query
+    fn find(&self, query: &Query) -> eyre::Result<Vec<FileInfo>>;
+
+    /// Get every stored file
+    fn all(&self) -> eyre::Result<Vec<FileInfo>>;
+
+    /// The number of stored files
+    fn count(&self) -> eyre::Result<usize>;
+
+    /// Add a new file. Errors if there is already a file with the same id.
+    fn insert(&mut self, file: FileInfo) -> eyre::Result<()>;
+
+    /// Add several new files at once
+    fn insert_all(&mut self, files: Vec<FileInfo>) -> eyre::Result<()> {
+        for file in files {
+            self.insert(file)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the information of the file with the same id. Errors if there is no such file.
+    fn update(&mut self, file: &FileInfo) -> eyre::Result<()>;
+
+    /// Replace the information of several files at once
+    fn update_all(&mut self, files: &[FileInfo]) -> eyre::Result<()> {
+        for file in files {
+            self.update(file)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the file with the id, returning whether there was one
+    fn delete(&mut self, filename: &str) -> eyre::Result<bool>;
+}
+
+/// The file information kept directly in the state file.  
+/// Lookups are linear scans, which is fine until the catalogue gets large, at which point
+/// [`SqliteStorage`] should be used instead.  
+/// This is also used to run the algorithms that need the entire catalogue at once.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename = "kebab-case")]
+pub struct Storage {
+    pub files: Vec<FileInfo>,
+}
+impl Storage {
+    /// Load every file from the backend
+    pub fn from_backend(backend: &dyn StorageBackend) -> eyre::Result<Storage> {
+        Ok(Storage {
+            files: backend.all()?,
+        })
+    }
+
+    /// Get the groups of files which have identical contents.  
+    /// Files without a stored hash are not included.
+    pub fn duplicate_groups(&self) -> Vec<Vec<&FileInfo>> {
+        let mut by_hash: HashMap<&str, Vec<&FileInfo>> = HashMap::new();
+        for file in self.files.iter() {
+            if let Some(hash) = file.hash.as_deref() {
+                by_hash.entry(hash).or_default().push(file);
+            }
+        }
+
+        let mut groups: Vec<Vec<&FileInfo>> =
+            by_hash.into_values().filter(|x| x.len() > 1).collect();
+        // Keep the output stable between runs
+        groups.sort_by(|a, b| a[0].filename.cmp(&b[0].filename));
+        groups
+    }
+
+    /// Get the files whose perceptual hash is within `max_distance` bits of `hash`, closest first
+    pub fn find_similar(&self, hash: u64, max_distance: u32) -> Vec<(u32, &FileInfo)> {
+        let mut similar: Vec<(u32, &FileInfo)> = self
+            .files
+            .iter()
+            .filter_map(|file| {
+                let distance = (file.perceptual_hash()? ^ hash).count_ones();
+                (distance <= max_distance).then_some((distance, file))
+            })
+            .collect();
+        similar.sort_by_key(|(distance, _)| *distance);
+        similar
+    }
+
+    /// Get all the files which match the query
+    pub fn query<'a>(&'a self, query: &'a Query) -> impl Iterator<Item = &'a FileInfo> + 'a {
+        self.files.iter().filter(move |x| query.matches(x))
+    }
+}
+impl StorageBackend for Storage {
+    fn find_from_prefix(&self, prefix: &str) -> eyre::Result<Vec<FileInfo>> {
+        Ok(self
+            .files
+            .iter()
+            .filter(|x| x.filename.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    fn find_by_hash(&self, hash: &str) -> eyre::Result<Vec<FileInfo>> {
+        Ok(self
+            .files
+            .iter()
+            .filter(|x| x.hash.as_deref() == Some(hash))
+            .cloned()
+            .collect())
+    }
+
+    fn find(&self, query: &Query) -> eyre::Result<Vec<FileInfo>> {
+        Ok(self.query(query).cloned().collect())
+    }
+
+    fn all(&self) -> eyre::Result<Vec<FileInfo>> {
+        Ok(self.files.clone())
+    }
+
+    fn count(&self) -> eyre::Result<usize> {
+        Ok(self.files.len())
+    }
+
+    fn insert(&mut self, file: FileInfo) -> eyre::Result<()> {
+        if self.files.iter().any(|x| x.filename == file.filename) {
+            return Err(eyre::eyre!(
+                "There is already a file with the id {:?}",
+                file.filename
+            ));
+        }
+        self.files.push(file);
+        Ok(())
+    }
+
+    fn update(&mut self, file: &FileInfo) -> eyre::Result<()> {
+        let existing = self
+            .files
+            .iter_mut()
+            .find(|x| x.filename == file.filename)
+            .ok_or_else(|| eyre::eyre!("There is no file with the id {:?}", file.filename))?;
+        *existing = file.clone();
+        Ok(())
+    }
+
+    fn delete(&mut self, filename: &str) -> eyre::Result<bool> {
+        let len = self.files.len();
+        self.files.retain(|x| x.filename != filename);
+        Ok(self.files.len() != len)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FileInfo {
+    // TODO: Use strings that are references into some loaded toml file
+    // this would avoid lots of individual allocations.
+    /// The name of the file in the storage folder. Note that this is not the original filename.  
+    /// Tagg replaces the filename with a randomly generated id. This avoids issues of name collision,
+    /// because Tagg stores them in a flat structure (aka a single folder).  
+    /// This includes the file extension.  
+    pub filename: String,
+
+    /// The filename that it originally had.  
+    /// Unlike some tagging software, this is kept around because it can be useful to know.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_filename: Option<String>,
+
+    /// The BLAKE3 hash (lowercase hex) of the file's contents when it was committed, or
+    /// when it was last refreshed by `tagg fsck`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+
+    /// The size of the file in bytes, at the same time as `hash`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+
+    /// The modification time of the file in seconds since the unix epoch, at the same time as `hash`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<u64>,
+
+    /// The perceptual hash (dHash, as 16 hex digits) of the file, if it is an image.  
+    /// Unlike `hash`, similar looking images have similar perceptual hashes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub perceptual_hash: Option<String>,
+
+    // TODO: Should we make this a `HashSet`?
+    /// The tag list for the file
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+
+    /// Various comment information about the file.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub comments: HashMap<String, String>,
+}
+impl FileInfo {
+    /// Store the current hash, size and modification time of the file at `path`.
+    pub fn refresh_content_info(&mut self, path: &Path) -> eyre::Result<()> {
+        self.hash = Some(hash_file(path)?);
+        self.refresh_perceptual_hash(path);
+        self.refresh_metadata(path)
+    }
+
+    /// Compute the perceptual hash of the file at `path` if it is an image.  
+    /// Images that fail to decode are warned about and left without a perceptual hash.
+    pub fn refresh_perceptual_hash(&mut self, path: &Path) {
+        if !self.is_image() {
+            return;
+        }
+
+        match perceptual_hash(path) {
+            Ok(hash) => self.perceptual_hash = Some(format!("{:016x}", hash)),
+            Err(err) => {
+                eprintln!(
+                    "WARN: Failed to compute the perceptual hash of {:?}: {}",
+                    self.filename, err
+                );
+                self.perceptual_hash = None;
+            }
+        }
+    }
+
+    /// Whether the file is an image, judging by its extension
+    pub fn is_image(&self) -> bool {
+        Path::new(&self.filename)
+            .extension()
+            .map(|x| is_image_extension(&x.to_string_lossy()))
+            .unwrap_or(false)
+    }
+
+    pub fn perceptual_hash(&self) -> Option<u64> {
+        self.perceptual_hash
+            .as_deref()
+            .and_then(|x| u64::from_str_radix(x, 16).ok())
+    }
+
+    /// Store the current size and modification time of the file at `path`, for when the hash
+    /// is already known.
+    pub fn refresh_metadata(&mut self, path: &Path) -> eyre::Result<()> {
+        let meta = std::fs::metadata(path)?;
+        self.size = Some(meta.len());
+        self.modified = modified_secs(&meta);
+        Ok(())
+    }
+
+    /// Check whether the file at `path` differs from the stored content information.  
+    /// If `full` is false then a file with the same size and modification time is assumed
+    /// to be unchanged, without hashing it.  
+    /// Returns `None` if there is no stored hash to compare against.
+    pub fn content_changed(&self, path: &Path, full: bool) -> eyre::Result<Option<bool>> {
+        let hash = if let Some(hash) = &self.hash {
+            hash
+        } else {
+            return Ok(None);
+        };
+
+        if !full {
+            let meta = std::fs::metadata(path)?;
+            if Some(meta.len()) == self.size && modified_secs(&meta) == self.modified {
+                return Ok(Some(false));
+            }
+        }
+
+        Ok(Some(hash_file(path)? != *hash))
+    }
+}
+
+fn modified_secs(meta: &std::fs::Metadata) -> Option<u64> {
+    meta.modified()
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| x.as_secs())
+}
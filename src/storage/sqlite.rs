@@ -0,0 +1,364 @@
+//! A [`StorageBackend`] that keeps the file information in an SQLite database, for catalogues
+//! that are too large to comfortably parse and rewrite as toml on every command.
+//!
+//! Tags and comments are kept in their own tables, indexed by tag and by comment title, so that
+//! queries which require specific tags only have to look at the files which have them.
+//!
+//! Changes are written to the database straight away, and unlike the state file it is not
+//! backed up by `state-backups`. Only the journal can undo changes to it.
+
+use std::{collections::HashMap, path::Path};
+
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row, Transaction};
+
+use crate::query::Query;
+
+use super::{FileInfo, StorageBackend};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS files (
+    filename TEXT PRIMARY KEY NOT NULL,
+    original_filename TEXT,
+    hash TEXT,
+    size INTEGER,
+    modified INTEGER,
+    perceptual_hash TEXT
+);
+CREATE INDEX IF NOT EXISTS files_hash ON files (hash);
+
+CREATE TABLE IF NOT EXISTS tags (
+    filename TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (filename, position)
+);
+CREATE INDEX IF NOT EXISTS tags_tag ON tags (tag);
+
+CREATE TABLE IF NOT EXISTS comments (
+    filename TEXT NOT NULL,
+    title TEXT NOT NULL,
+    comment TEXT NOT NULL,
+    PRIMARY KEY (filename, title)
+);
+CREATE INDEX IF NOT EXISTS comments_title ON comments (title);
+";
+
+const FILE_COLUMNS: &str = "filename, original_filename, hash, size, modified, perceptual_hash";
+
+pub struct SqliteStorage {
+    conn: Connection,
+}
+impl SqliteStorage {
+    /// Open the database at `path`, creating it if it doesn't exist
+    pub fn open(path: &Path) -> eyre::Result<SqliteStorage> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteStorage { conn })
+    }
+
+    /// Get the files matching the `WHERE` clause, in the order they were inserted.  
+    /// The tags and comments of all of them are loaded with a single query each, rather than one
+    /// per file.
+    fn select(
+        &self,
+        condition: &str,
+        params: impl rusqlite::Params + Clone,
+    ) -> eyre::Result<Vec<FileInfo>> {
+        let mut statement = self.conn.prepare(&format!(
+            "SELECT {} FROM files WHERE {} ORDER BY rowid",
+            FILE_COLUMNS, condition
+        ))?;
+        let mut files = statement
+            .query_map(params.clone(), file_from_row)?
+            .collect::<Result<Vec<FileInfo>, _>>()?;
+        if files.is_empty() {
+            return Ok(files);
+        }
+
+        let index: HashMap<String, usize> = files
+            .iter()
+            .enumerate()
+            .map(|(i, file)| (file.filename.clone(), i))
+            .collect();
+        let selected = format!("SELECT filename FROM files WHERE {}", condition);
+
+        let mut tags = self.conn.prepare(&format!(
+            "SELECT filename, tag FROM tags WHERE filename IN ({}) ORDER BY filename, position",
+            selected
+        ))?;
+        let mut rows = tags.query(params.clone())?;
+        while let Some(row) = rows.next()? {
+            let filename: String = row.get(0)?;
+            if let Some(&i) = index.get(&filename) {
+                files[i].tags.push(row.get(1)?);
+            }
+        }
+
+        let mut comments = self.conn.prepare(&format!(
+            "SELECT filename, title, comment FROM comments WHERE filename IN ({})",
+            selected
+        ))?;
+        let mut rows = comments.query(params)?;
+        while let Some(row) = rows.next()? {
+            let filename: String = row.get(0)?;
+            if let Some(&i) = index.get(&filename) {
+                files[i].comments.insert(row.get(1)?, row.get(2)?);
+            }
+        }
+
+        Ok(files)
+    }
+}
+impl StorageBackend for SqliteStorage {
+    fn find_from_prefix(&self, prefix: &str) -> eyre::Result<Vec<FileInfo>> {
+        // A range rather than `LIKE` so that it can use the primary key index
+        let end = format!("{}\u{10FFFF}", prefix);
+        self.select("filename >= ?1 AND filename < ?2", params![prefix, end])
+    }
+
+    fn find_by_hash(&self, hash: &str) -> eyre::Result<Vec<FileInfo>> {
+        self.select("hash = ?1", [hash])
+    }
+
+    fn find(&self, query: &Query) -> eyre::Result<Vec<FileInfo>> {
+        let required = query.required_tags();
+        let files = if required.is_empty() {
+            self.all()?
+        } else {
+            // Files with a descendant of the tag count too, which are the tags starting with it
+            // and then a separator. The ranges are from the separator up to the character after it,
+            // so that they can use the index.
+            let condition = (1..=required.len())
+                .map(|i| {
+                    format!(
+                        "filename IN (SELECT filename FROM tags WHERE tag = ?{i} \
+                        OR (tag >= ?{i} || '/' AND tag < ?{i} || '0') \
+                        OR (tag >= ?{i} || ':' AND tag < ?{i} || ';'))"
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(" AND ");
+            self.select(&condition, params_from_iter(required.iter()))?
+        };
+
+        Ok(files.into_iter().filter(|x| query.matches(x)).collect())
+    }
+
+    fn all(&self) -> eyre::Result<Vec<FileInfo>> {
+        self.select("1", [])
+    }
+
+    fn count(&self) -> eyre::Result<usize> {
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    fn insert(&mut self, file: FileInfo) -> eyre::Result<()> {
+        self.insert_all(vec![file])
+    }
+
+    fn insert_all(&mut self, files: Vec<FileInfo>) -> eyre::Result<()> {
+        let tx = self.conn.transaction()?;
+        for file in files {
+            let exists = tx
+                .query_row(
+                    "SELECT 1 FROM files WHERE filename = ?1",
+                    [&file.filename],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            if exists {
+                return Err(eyre::eyre!(
+                    "There is already a file with the id {:?}",
+                    file.filename
+                ));
+            }
+
+            tx.execute(
+                &format!(
+                    "INSERT INTO files ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    FILE_COLUMNS
+                ),
+                params![
+                    file.filename,
+                    file.original_filename,
+                    file.hash,
+                    file.size.map(|x| x as i64),
+                    file.modified.map(|x| x as i64),
+                    file.perceptual_hash,
+                ],
+            )?;
+            insert_details(&tx, &file)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn update(&mut self, file: &FileInfo) -> eyre::Result<()> {
+        self.update_all(std::slice::from_ref(file))
+    }
+
+    fn update_all(&mut self, files: &[FileInfo]) -> eyre::Result<()> {
+        // A single transaction, since committing each file separately is slow
+        let tx = self.conn.transaction()?;
+        for file in files {
+            let changed = tx.execute(
+                "UPDATE files SET original_filename = ?2, hash = ?3, size = ?4, modified = ?5, perceptual_hash = ?6 WHERE filename = ?1",
+                params![
+                    file.filename,
+                    file.original_filename,
+                    file.hash,
+                    file.size.map(|x| x as i64),
+                    file.modified.map(|x| x as i64),
+                    file.perceptual_hash,
+                ],
+            )?;
+            if changed == 0 {
+                return Err(eyre::eyre!(
+                    "There is no file with the id {:?}",
+                    file.filename
+                ));
+            }
+
+            delete_details(&tx, &file.filename)?;
+            insert_details(&tx, file)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete(&mut self, filename: &str) -> eyre::Result<bool> {
+        let tx = self.conn.transaction()?;
+        let changed = tx.execute("DELETE FROM files WHERE filename = ?1", [filename])?;
+        delete_details(&tx, filename)?;
+        tx.commit()?;
+        Ok(changed > 0)
+    }
+}
+
+fn file_from_row(row: &Row) -> rusqlite::Result<FileInfo> {
+    Ok(FileInfo {
+        filename: row.get(0)?,
+        original_filename: row.get(1)?,
+        hash: row.get(2)?,
+        size: row.get::<_, Option<i64>>(3)?.map(|x| x as u64),
+        modified: row.get::<_, Option<i64>>(4)?.map(|x| x as u64),
+        perceptual_hash: row.get(5)?,
+        tags: Vec::new(),
+        comments: HashMap::new(),
+    })
+}
+
+fn insert_details(tx: &Transaction, file: &FileInfo) -> rusqlite::Result<()> {
+    let mut tags =
+        tx.prepare_cached("INSERT INTO tags (filename, position, tag) VALUES (?1, ?2, ?3)")?;
+    for (position, tag) in file.tags.iter().enumerate() {
+        tags.execute(params![file.filename, position as i64, tag])?;
+    }
+
+    let mut comments =
+        tx.prepare_cached("INSERT INTO comments (filename, title, comment) VALUES (?1, ?2, ?3)")?;
+    for (title, comment) in file.comments.iter() {
+        comments.execute(params![file.filename, title, comment])?;
+    }
+
+    Ok(())
+}
+
+fn delete_details(tx: &Transaction, filename: &str) -> rusqlite::Result<()> {
+    tx.execute("DELETE FROM tags WHERE filename = ?1", [filename])?;
+    tx.execute("DELETE FROM comments WHERE filename = ?1", [filename])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, path::Path};
+
+    use crate::{
+        query::Query,
+        storage::{FileInfo, StorageBackend},
+    };
+
+    use super::SqliteStorage;
+
+    fn file(filename: &str, hash: &str, tags: &[&str]) -> FileInfo {
+        FileInfo {
+            filename: filename.to_string(),
+            original_filename: Some("story.epub".to_string()),
+            hash: Some(hash.to_string()),
+            size: Some(12),
+            modified: Some(1668000000),
+            perceptual_hash: None,
+            tags: tags.iter().map(|x| x.to_string()).collect(),
+            comments: HashMap::from([("title".to_string(), "A Story".to_string())]),
+        }
+    }
+
+    #[test]
+    fn test_sqlite() {
+        let mut storage = SqliteStorage::open(Path::new(":memory:")).unwrap();
+        storage
+            .insert(file("0ad415fd", "af13", &["book", "long"]))
+            .unwrap();
+        storage
+            .insert(file("0b67ee61", "af13", &["paper"]))
+            .unwrap();
+        storage.insert(file("a4c1bec8", "7e2d", &["book"])).unwrap();
+        assert!(storage.insert(file("a4c1bec8", "7e2d", &[])).is_err());
+        assert_eq!(storage.count().unwrap(), 3);
+        let all = storage.all().unwrap();
+        let tags: Vec<&[String]> = all.iter().map(|x| x.tags.as_slice()).collect();
+        assert_eq!(tags, [&["book", "long"][..], &["paper"], &["book"]]);
+        assert!(all.iter().all(|x| x.comments["title"] == "A Story"));
+
+        let found = storage.find_from_prefix("0").unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].tags, ["book", "long"]);
+        assert_eq!(found[0].comments["title"], "A Story");
+        assert_eq!(found[0].modified, Some(1668000000));
+        assert_eq!(storage.find_by_hash("af13").unwrap().len(), 2);
+
+        let query = Query::parse("book -long").unwrap();
+        let found = storage.find(&query).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].filename, "a4c1bec8");
+
+        // The tag index still finds files by their tags' ancestors
+        storage
+            .insert(file("c0ffee00", "3b1d", &["topic/ml/rnn", "genre:fantasy"]))
+            .unwrap();
+        for text in ["topic", "topic/ml", "genre topic/ml/rnn"] {
+            let found = storage.find(&Query::parse(text).unwrap()).unwrap();
+            assert_eq!(found.len(), 1, "{}", text);
+        }
+        assert!(storage
+            .find(&Query::parse("topic/m").unwrap())
+            .unwrap()
+            .is_empty());
+        assert!(storage.delete("c0ffee00").unwrap());
+
+        let mut changed = found[0].clone();
+        changed.tags = vec!["book".to_string(), "long".to_string()];
+        changed.comments.clear();
+        storage.update(&changed).unwrap();
+        assert!(storage.find(&query).unwrap().is_empty());
+        let stored = storage.find_from_prefix("a4c1bec8").unwrap();
+        assert_eq!(stored[0].tags, changed.tags);
+        assert!(stored[0].comments.is_empty());
+
+        assert!(storage.delete("0ad415fd").unwrap());
+        assert!(!storage.delete("0ad415fd").unwrap());
+        assert!(storage.update(&file("0ad415fd", "af13", &[])).is_err());
+        let remaining: Vec<String> = storage
+            .all()
+            .unwrap()
+            .into_iter()
+            .map(|x| x.filename)
+            .collect();
+        assert_eq!(remaining, ["0b67ee61", "a4c1bec8"]);
+    }
+}
@@ -1,22 +1,407 @@
 use std::path::{Path, PathBuf};
 
-use crate::{config::Config, state::State};
+use crate::{
+    config::Config,
+    implications::{Implications, ImpliedTags},
+    journal::{
+        CommittedFile, EntryKind, Journal, JournalEntry, PendingEntry, RecordingStorage,
+        RulesChange, StagedChange, TagRules,
+    },
+    lock::StateLock,
+    query::{Query, QueryContext},
+    state::{AddedFile, Staging, State},
+    storage::{SqliteStorage, StorageBackend, StorageBackendKind},
+    util::{datetime_to_unix, now_datetime},
+};
 
 pub struct Tagg {
     pub config_path: PathBuf,
     pub state_path: PathBuf,
+    pub staging_path: PathBuf,
 
     pub config: Config,
-    pub state: State,
+    /// The state is only loaded when it is first used, since commands that only touch the
+    /// registration-area don't need to parse the entire storage catalogue.
+    state: Option<State>,
+    /// Whether the state was changed since it was loaded or last saved, through
+    /// [`Tagg::state_mut`]
+    state_changed: bool,
+    /// Whether the stored files were changed since the state was loaded or last saved, which
+    /// means the state has to be saved when the toml storage-backend is used
+    storage_changed: bool,
+    /// The aliases and implication rules as they were loaded, so that changes to them can be
+    /// recorded in the journal. `None` if the changes to them shouldn't be recorded.
+    rules_before: Option<TagRules>,
+    /// Whether the changes made by the command are being made by undo or redo, and so
+    /// shouldn't be recorded in the journal
+    untracked: bool,
+    /// The database, if the sqlite storage-backend is used and it has been opened
+    database: Option<SqliteStorage>,
+    pub staging: Staging,
+    /// The registration-area from before the command changed it, so that the change can be
+    /// recorded in the journal. `None` if the changes to it shouldn't be recorded.
+    staging_before: Option<Vec<AddedFile>>,
+    /// The changes made through [`Tagg::storage`] which haven't been saved yet
+    pending: PendingEntry,
+    /// The changes which have been saved, and will be written to the journal as a single entry
+    /// once the command finishes
+    saved: PendingEntry,
+    /// The command line, to describe the changes in the journal
+    pub command_line: String,
+    /// Held for as long as tagg runs, so that other tagg processes don't modify the state under
+    /// us, see [`crate::lock`]. `None` once released by [`Tagg::unlock`].
+    lock: Option<StateLock>,
 
     pub verbose: bool,
 }
 impl Tagg {
-    pub fn save_state(&self) -> eyre::Result<()> {
+    pub fn new(
+        config_path: PathBuf,
+        state_path: PathBuf,
+        staging_path: PathBuf,
+        config: Config,
+        staging: Staging,
+        lock: StateLock,
+        verbose: bool,
+    ) -> Tagg {
+        let staging_before = Some(staging.registration_area.clone());
+        Tagg {
+            config_path,
+            state_path,
+            staging_path,
+            config,
+            state: None,
+            state_changed: false,
+            storage_changed: false,
+            rules_before: None,
+            untracked: false,
+            database: None,
+            staging,
+            staging_before,
+            pending: PendingEntry::default(),
+            saved: PendingEntry::default(),
+            command_line: command_line(),
+            lock: Some(lock),
+            verbose,
+        }
+    }
+
+    /// Release the lock on the state before tagg exits, for commands that keep running long after
+    /// they've loaded what they need, like `tagg mount`.  
+    /// Nothing can be saved afterwards, since other tagg processes may have changed the state.
+    pub fn unlock(&mut self) {
+        self.lock = None;
+    }
+
+    fn check_locked(&self) -> eyre::Result<()> {
+        if self.lock.is_none() {
+            return Err(eyre::eyre!(
+                "Can't save changes after the lock on the state was released"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Get the state, loading it if this is the first time it was needed
+    pub fn state(&mut self) -> eyre::Result<&State> {
+        self.load_state()?;
+        Ok(self.state.as_ref().expect("State to have just been loaded"))
+    }
+
+    /// Get the state to modify it, which marks it as needing to be saved by
+    /// [`Tagg::save_state`]
+    pub fn state_mut(&mut self) -> eyre::Result<&mut State> {
+        self.load_state()?;
+        self.state_changed = true;
+        Ok(self.state.as_mut().expect("State to have just been loaded"))
+    }
+
+    fn load_state(&mut self) -> eyre::Result<()> {
+        if self.state.is_none() {
+            if self.verbose {
+                eprintln!(
+                    "Loading state from {:?}",
+                    self.state_path
+                        .canonicalize()
+                        .unwrap_or_else(|_| self.state_path.clone())
+                );
+            }
+            let state = State::load_from(&self.state_path)?;
+            if !self.untracked {
+                self.rules_before = Some(TagRules::of(&state));
+            }
+            self.state = Some(state);
+        }
+        Ok(())
+    }
+
+    /// Get the storage-backend chosen in the config, loading it if this is the first time it was
+    /// needed.  
+    /// Changes to the toml backend are only written by [`Tagg::save_state`], while the sqlite
+    /// backend writes them immediately. Either way, they are recorded in the journal once the
+    /// state is saved.
+    pub fn storage(&mut self) -> eyre::Result<RecordingStorage<'_>> {
+        let (storage, _) = self.storage_and_staging()?;
+        Ok(storage)
+    }
+
+    /// Get both the storage-backend and the registration-area at once
+    pub fn storage_and_staging(&mut self) -> eyre::Result<(RecordingStorage<'_>, &mut Staging)> {
+        self.load_backend()?;
+        let storage = backend(&self.config, &mut self.state, &mut self.database);
+        Ok((
+            RecordingStorage::new(
+                storage,
+                &mut self.pending.changes,
+                &mut self.storage_changed,
+            ),
+            &mut self.staging,
+        ))
+    }
+
+    /// Get the storage-backend and the registration-area without recording the changes made to
+    /// them in the journal, for when the changes are being undone or redone.
+    pub fn untracked_storage_and_staging(
+        &mut self,
+    ) -> eyre::Result<(&mut dyn StorageBackend, &mut Staging)> {
+        self.load_backend()?;
+        self.storage_changed = true;
+        self.staging_before = None;
+        self.rules_before = None;
+        self.untracked = true;
+        let storage = backend(&self.config, &mut self.state, &mut self.database);
+        Ok((storage, &mut self.staging))
+    }
+
+    fn load_backend(&mut self) -> eyre::Result<()> {
+        match self.config.storage_backend {
+            StorageBackendKind::Toml => {
+                self.load_state()?;
+            }
+            StorageBackendKind::Sqlite => {
+                if self.database.is_none() {
+                    let path = self
+                        .config
+                        .database_path(&self.config_path, &self.state_path)?;
+                    if self.verbose {
+                        eprintln!("Opening database {:?}", path);
+                    }
+                    self.database = Some(SqliteStorage::open(&path)?);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Note that a file was committed from the registration-area, so that it can be undone
+    pub fn record_commit(&mut self, commit: CommittedFile) {
+        self.pending.commits.push(commit);
+    }
+
+    /// Save the state, if it has been changed since it was loaded.  
+    /// If it was never loaded, then it can't have been modified.
+    pub fn save_state(&mut self) -> eyre::Result<()> {
+        let changed = self.state_changed
+            || (self.storage_changed && self.config.storage_backend == StorageBackendKind::Toml);
+        if let Some(state) = self.state.as_ref().filter(|_| changed) {
+            self.check_locked()?;
+            if self.verbose {
+                eprintln!("INFO: Saving state file");
+            }
+            state.save_to(&self.state_path, self.config.state_backups)?;
+
+            if let Some(before) = self.rules_before.as_ref().filter(|_| self.state_changed) {
+                self.saved.rules = Some(RulesChange {
+                    before: before.clone(),
+                    after: TagRules::of(state),
+                });
+            }
+        }
+        self.state_changed = false;
+        self.storage_changed = false;
+
+        // Only changes which were actually saved should end up in the journal
+        let pending = std::mem::take(&mut self.pending);
+        self.saved.changes.extend(pending.changes);
+        self.saved.commits.extend(pending.commits);
+        Ok(())
+    }
+
+    /// Write the changes which were saved to the journal as a single entry
+    pub fn save_journal(&mut self) -> eyre::Result<()> {
+        let mut saved = std::mem::take(&mut self.saved);
+        // Undoing a commit already puts its files back in the registration-area
+        let staging_unchanged = saved.staged.as_ref().is_some_and(|x| x.before == x.after);
+        if staging_unchanged || !saved.commits.is_empty() {
+            saved.staged = None;
+        }
+        if saved.rules.as_ref().is_some_and(|x| x.before == x.after) {
+            saved.rules = None;
+        }
+        if saved.is_empty() {
+            return Ok(());
+        }
+
+        self.append_journal(EntryKind::Change, None, saved)
+    }
+
+    pub fn journal_path(&self) -> eyre::Result<PathBuf> {
+        self.config
+            .journal_path(&self.config_path, &self.state_path)
+    }
+
+    pub fn load_journal(&self) -> eyre::Result<Journal> {
+        Journal::load_from(&self.journal_path()?)
+    }
+
+    /// Add an entry to the end of the journal
+    pub fn append_journal(
+        &mut self,
+        kind: EntryKind,
+        target: Option<u64>,
+        entry: PendingEntry,
+    ) -> eyre::Result<()> {
+        self.check_locked()?;
+        let path = self.journal_path()?;
+        let id = Journal::next_id_in(&path)?;
+        if self.verbose {
+            eprintln!("INFO: Recording the changes in the journal");
+        }
+        Journal::append_to(
+            &path,
+            JournalEntry {
+                id,
+                time: now_datetime(),
+                kind,
+                command: self.command_line.clone(),
+                target,
+                changes: entry.changes,
+                commits: entry.commits,
+                staged: entry.staged,
+                rules: entry.rules,
+            },
+        )
+    }
+
+    /// Replace any aliases in the tags with the tag they stand for, see [`crate::aliases`]
+    pub fn resolve_aliases(&mut self, tags: &mut [String]) -> eyre::Result<()> {
+        if tags.is_empty() {
+            // Which doesn't need the state to be loaded
+            return Ok(());
+        }
+        let verbose = self.verbose;
+        for (alias, tag) in self.state()?.aliases.resolve_all(tags) {
+            if verbose {
+                eprintln!("INFO: Using {:?} for its alias {:?}", tag, alias);
+            }
+        }
+        Ok(())
+    }
+
+    /// What queries have to be prepared with, see [`QueryContext`]
+    pub fn query_context(&mut self) -> eyre::Result<QueryContext> {
+        let implied_tags = self.config.implied_tags;
+        let namespaces = self.config.namespaces.clone();
+        let state = self.state()?;
+        Ok(QueryContext {
+            namespaces,
+            aliases: state.aliases.clone(),
+            implications: (implied_tags == ImpliedTags::Virtual)
+                .then(|| state.implications.clone()),
+        })
+    }
+
+    /// Parse a query and prepare it to be run, printing where it failed to parse
+    pub fn prepare_query(&mut self, text: &str) -> eyre::Result<Query> {
+        match Query::parse(text) {
+            Ok(query) => self.query_context()?.prepare(query),
+            Err(err) => {
+                eprintln!("Failed to parse query:");
+                eprintln!("{}", err.display_with_source(text));
+                Err(err.into())
+            }
+        }
+    }
+
+    /// The implication rules, if the tags they imply should be stored on files rather than only
+    /// taken into account when finding them, see [`crate::implications`]
+    pub fn stored_implications(&mut self) -> eyre::Result<Option<Implications>> {
+        if self.config.implied_tags != ImpliedTags::Stored {
+            return Ok(None);
+        }
+        let implications = &self.state()?.implications;
+        Ok((!implications.is_empty()).then(|| implications.clone()))
+    }
+
+    /// Save the registration-area, marking it as having been modified now
+    pub fn save_staging(&mut self) -> eyre::Result<()> {
+        self.check_locked()?;
+        if self.verbose {
+            eprintln!("INFO: Saving registration-area file");
+        }
+        self.staging.last_registration = Some(now_datetime());
+        self.staging.save_to(&self.staging_path)?;
+
+        if let Some(before) = &self.staging_before {
+            self.saved.staged = Some(StagedChange {
+                before: before.clone(),
+                after: self.staging.registration_area.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Treat the registration-area as it is now as where the command started from, for changes
+    /// made before the command runs which aren't worth undoing
+    fn restart_staging_record(&mut self) {
+        if self.staging_before.is_some() {
+            self.staging_before = Some(self.staging.registration_area.clone());
+        }
+        self.saved.staged = None;
+    }
+
+    /// Move the files in the registration-area into the expired list if it has gone untouched
+    /// for longer than [`Config::registration_delay_limit`]
+    pub fn expire_staging(&mut self) -> eyre::Result<()> {
+        let now = datetime_to_unix(&now_datetime()).unwrap_or(0);
+        let expired = self
+            .staging
+            .expire(self.config.registration_delay_limit, now);
+        if expired > 0 {
+            eprintln!(
+                "INFO: The registration-area went untouched for longer than the registration-delay-limit, so its {} files were moved out of it. Use `tagg restore-staged` to bring them back",
+                expired
+            );
+            self.save_staging()?;
+            self.restart_staging_record();
+        }
+
+        Ok(())
+    }
+
+    /// Move any files in the registration-area from the state file into the separate
+    /// registration-area file, from before they were stored separately.
+    pub fn migrate_staging(&mut self) -> eyre::Result<()> {
+        if self.state()?.staging.is_none() {
+            return Ok(());
+        }
+        let mut old = self
+            .state_mut()?
+            .staging
+            .take()
+            .expect("Old staging to exist");
+
+        let staging = &mut self.staging;
+        staging.registration_area.append(&mut old.registration_area);
+        staging.last_registration = staging.last_registration.take().or(old.last_registration);
         if self.verbose {
-            eprintln!("INFO: Saving state file");
+            eprintln!("INFO: Moving the registration-area out of the state file");
         }
-        self.state.save_to(&self.state_path)
+        // Not through `save_staging`, which would mark the registration-area as modified now
+        // and keep it from expiring
+        self.staging.save_to(&self.staging_path)?;
+        self.restart_staging_record();
+        self.save_state()
     }
 
     pub fn choose_filename(&self, ext: &str) -> String {
@@ -36,3 +421,29 @@ impl Tagg {
         Ok(storage_path)
     }
 }
+
+/// The storage-backend chosen in the config, which must have been loaded already
+fn backend<'a>(
+    config: &Config,
+    state: &'a mut Option<State>,
+    database: &'a mut Option<SqliteStorage>,
+) -> &'a mut dyn StorageBackend {
+    match config.storage_backend {
+        StorageBackendKind::Toml => &mut state.as_mut().expect("State to have been loaded").storage,
+        StorageBackendKind::Sqlite => database.as_mut().expect("Database to have been opened"),
+    }
+}
+
+/// The arguments tagg was run with, quoting those which wouldn't read as a single argument
+fn command_line() -> String {
+    let mut line = "tagg".to_string();
+    for arg in std::env::args().skip(1) {
+        line.push(' ');
+        if arg.is_empty() || arg.contains(char::is_whitespace) {
+            line.push_str(&format!("{:?}", arg));
+        } else {
+            line.push_str(&arg);
+        }
+    }
+    line
+}
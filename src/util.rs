@@ -1,4 +1,10 @@
-use std::path::{Path, PathBuf};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use toml::value::Datetime;
 
 pub fn expand_path(path: impl AsRef<str>) -> PathBuf {
     // TODO: Do i need to specialize this to just linux/unix/bsd?
@@ -6,6 +12,207 @@ pub fn expand_path(path: impl AsRef<str>) -> PathBuf {
     PathBuf::from(path.as_ref())
 }
 
+/// The current time, in UTC
+pub(crate) fn now_datetime() -> Datetime {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+    unix_to_datetime(secs)
+}
+
+pub(crate) fn unix_to_datetime(secs: u64) -> Datetime {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem / 60) % 60,
+        rem % 60
+    )
+    .parse()
+    .expect("Formatted datetime to be valid")
+}
+
+/// Convert a datetime into seconds since the unix epoch.  
+/// Returns `None` for datetimes without a date, or from before the epoch.
+pub(crate) fn datetime_to_unix(datetime: &Datetime) -> Option<u64> {
+    // `Datetime`'s fields aren't public, so we go through its rfc3339 representation
+    let text = datetime.to_string();
+    let (date, time) = text.split_once(['T', ' ']).unwrap_or((&text, "00:00:00Z"));
+
+    let mut date_parts = date.splitn(3, '-').map(|x| x.parse::<i64>().ok());
+    let year = date_parts.next()??;
+    let month = date_parts.next()??;
+    let day = date_parts.next()??;
+
+    let (time, offset) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        (time, 0)
+    } else if let Some(index) = time.rfind(['+', '-']) {
+        let (time, offset) = time.split_at(index);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let (hours, minutes) = offset[1..].split_once(':')?;
+        (
+            time,
+            sign * (hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60),
+        )
+    } else {
+        // A local time, which we treat as UTC
+        (time, 0)
+    };
+    let mut time_parts = time.splitn(3, ':');
+    let hour = time_parts.next()?.parse::<i64>().ok()?;
+    let minute = time_parts.next()?.parse::<i64>().ok()?;
+    // Ignore fractional seconds
+    let second = time_parts
+        .next()
+        .map(|x| x.split('.').next().unwrap_or("0"))
+        .unwrap_or("0")
+        .parse::<i64>()
+        .ok()?;
+
+    let secs =
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+    u64::try_from(secs).ok()
+}
+
+// These are Howard Hinnant's algorithms for converting between days since the epoch and dates
+// in the proleptic Gregorian calendar.
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Write the file by writing to a temporary file next to it, syncing it to disk, and then renaming
+/// it over the original. A crash or full disk part way through leaves either the old or the new
+/// contents, rather than a truncated mix of both.
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> eyre::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| eyre::eyre!("{:?} is not a path to a file", path))?;
+    // The process id keeps two processes saving at once from writing into the same file
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    if let Err(err) = write_synced(&temp_path, data) {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(err.into());
+    }
+
+    std::fs::rename(&temp_path, path)?;
+    sync_parent_dir(path);
+    Ok(())
+}
+
+fn write_synced(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// Make sure a rename in the folder has reached the disk.  
+/// This is best-effort, since not every platform can open a folder to sync it.
+fn sync_parent_dir(path: &Path) {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        if let Ok(dir) = std::fs::File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+/// Format a size in bytes for showing to the user, like `12.3 MiB`
+pub(crate) fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+
+    let mut size = size as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Hash the contents of a file with BLAKE3, returning the digest as lowercase hex.
+pub(crate) fn hash_file(path: &Path) -> eyre::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// The extensions of files that we compute a perceptual hash for
+pub(crate) const IMAGE_EXTENSIONS: &[&str] =
+    &["png", "jpg", "jpeg", "gif", "bmp", "webp", "tiff", "tif"];
+
+pub(crate) fn is_image_extension(extension: &str) -> bool {
+    IMAGE_EXTENSIONS
+        .iter()
+        .any(|x| x.eq_ignore_ascii_case(extension))
+}
+
+/// Compute the difference hash (dHash) of an image.  
+/// The image is shrunk to 9x8 greyscale, and each bit is whether a pixel is brighter than the
+/// pixel to its right. Rescaled or re-encoded copies of an image end up with hashes that
+/// differ in only a few bits.
+pub(crate) fn perceptual_hash(path: &Path) -> eyre::Result<u64> {
+    let image = image::open(path)?
+        .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+        .to_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = image.get_pixel(x, y)[0];
+            let right = image.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | u64::from(left > right);
+        }
+    }
+
+    Ok(hash)
+}
+
 pub(crate) fn extract_pdf_title(path: &Path, page_number: usize) -> Option<String> {
     let res = std::process::Command::new("pdftitle")
         // Max2 managed to extract the most in my experience
@@ -76,3 +283,26 @@ pub(crate) fn extract_title(path: &Path, extension: &str) -> Option<String> {
         None
     }
 }
+
+/// A fresh directory for a test to put files in, which is removed when dropped so that it is
+/// cleaned up even if the test panics
+#[cfg(test)]
+pub(crate) struct TempDir(PathBuf);
+#[cfg(test)]
+impl TempDir {
+    pub fn new() -> TempDir {
+        let path = std::env::temp_dir().join(format!("tagg-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&path).expect("Failed to create the test directory");
+        TempDir(path)
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,228 @@
+//! The layout of the virtual filesystem view of the storage.
+//!
+//! ```text
+//! /all/                every stored file
+//! /tags/               a directory for every tag, and every ancestor of one
+//! /tags/<tag>/         the files with that tag or one of its descendants, like `/query/<tag>/`
+//! /query/<expr>/       the files matching the query (see [`crate::query`])
+//! ```
+//!
+//! Files are shown with their original filename, with the start of their storage id added
+//! before the extension (`story-0ad415fd.epub`), so that files which had the same name don't
+//! collide, and so that the same file always gets the same name.
+//! Since tags and queries can contain `/`, it is written as `%2F` in path components (and `%`
+//! as `%25`).
+//!
+//! The layout can be browsed with `tagg ls`, or mounted as a filesystem with `tagg mount`.
+
+use std::{collections::BTreeSet, fmt, path::Path};
+
+use crate::{
+    hierarchy::tag_and_ancestors,
+    query::{Expr, Query, QueryContext, TagPattern},
+    storage::{FileInfo, Storage},
+};
+
+/// How many characters of the storage id are added to the displayed name
+pub const ID_SUFFIX_LEN: usize = 8;
+
+/// The name the file is shown with
+pub fn display_name(file: &FileInfo) -> String {
+    let id: String = file.filename.chars().take(ID_SUFFIX_LEN).collect();
+    let original = if let Some(original) = &file.original_filename {
+        original
+    } else {
+        // Without an original name the storage name is already unique
+        return file.filename.clone();
+    };
+
+    let original = Path::new(original);
+    let stem = original
+        .file_stem()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    match original.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, id, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, id),
+    }
+}
+
+/// Escape a tag or query so that it can be used as a single path component
+pub fn encode_component(text: &str) -> String {
+    text.replace('%', "%25").replace('/', "%2F")
+}
+
+pub fn decode_component(text: &str) -> String {
+    text.replace("%2F", "/")
+        .replace("%2f", "/")
+        .replace("%25", "%")
+}
+
+#[derive(Debug)]
+pub enum VfsError {
+    NotFound,
+    Query(eyre::Report),
+}
+impl fmt::Display for VfsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VfsError::NotFound => write!(f, "no such file or directory"),
+            VfsError::Query(err) => write!(f, "invalid query: {}", err),
+        }
+    }
+}
+impl std::error::Error for VfsError {}
+
+pub enum Entry<'a> {
+    Dir(String),
+    File { name: String, file: &'a FileInfo },
+}
+impl Entry<'_> {
+    pub fn name(&self) -> &str {
+        match self {
+            Entry::Dir(name) => name,
+            Entry::File { name, .. } => name,
+        }
+    }
+}
+
+/// List the contents of a directory in the virtual filesystem, with queries prepared with the
+/// context
+pub fn list<'a>(
+    storage: &'a Storage,
+    path: &str,
+    context: &QueryContext,
+) -> Result<Vec<Entry<'a>>, VfsError> {
+    let components: Vec<&str> = path.split('/').filter(|x| !x.is_empty()).collect();
+    match components.as_slice() {
+        [] => Ok(vec![
+            Entry::Dir("all".to_string()),
+            Entry::Dir("query".to_string()),
+            Entry::Dir("tags".to_string()),
+        ]),
+        ["all"] => Ok(files(storage.files.iter())),
+        ["tags"] => {
+            let tags: BTreeSet<&str> = storage
+                .files
+                .iter()
+                .flat_map(|x| x.tags.iter())
+                .flat_map(|x| tag_and_ancestors(x))
+                .collect();
+            Ok(tags
+                .into_iter()
+                .map(|x| Entry::Dir(encode_component(x)))
+                .collect())
+        }
+        ["tags", tag] => {
+            // The same files as `/query/<tag>/`, so aliases and virtual implied tags count too
+            let query = Query {
+                expr: Expr::Tag(TagPattern::Exact(decode_component(tag))),
+                case_insensitive: false,
+            };
+            let query = context.prepare(query).map_err(VfsError::Query)?;
+            let found: Vec<&FileInfo> = storage
+                .files
+                .iter()
+                .filter(|x| query.matches(x))
+                .collect();
+            if found.is_empty() {
+                return Err(VfsError::NotFound);
+            }
+            Ok(files(found.into_iter()))
+        }
+        // Queries can't be listed, they have to be asked for
+        ["query"] => Ok(Vec::new()),
+        ["query", expr] => {
+            let query = context
+                .parse(&decode_component(expr))
+                .map_err(VfsError::Query)?;
+            Ok(files(storage.files.iter().filter(|x| query.matches(x))))
+        }
+        _ => Err(VfsError::NotFound),
+    }
+}
+
+/// Find the stored file that a path in the virtual filesystem refers to
+pub fn resolve<'a>(
+    storage: &'a Storage,
+    path: &str,
+    context: &QueryContext,
+) -> Result<&'a FileInfo, VfsError> {
+    let (dir, name) = path
+        .trim_end_matches('/')
+        .rsplit_once('/')
+        .ok_or(VfsError::NotFound)?;
+    list(storage, dir, context)?
+        .into_iter()
+        .find_map(|entry| match entry {
+            Entry::File { name: entry_name, file } if entry_name == name => Some(file),
+            _ => None,
+        })
+        .ok_or(VfsError::NotFound)
+}
+
+fn files<'a>(files: impl Iterator<Item = &'a FileInfo>) -> Vec<Entry<'a>> {
+    let mut entries: Vec<Entry<'a>> = files
+        .map(|file| Entry::File {
+            name: display_name(file),
+            file,
+        })
+        .collect();
+    entries.sort_by(|a, b| a.name().cmp(b.name()));
+    entries
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        query::QueryContext,
+        storage::{FileInfo, Storage},
+    };
+
+    use super::{decode_component, display_name, encode_component, list, resolve};
+
+    fn file(filename: &str, original: &str, tags: &[&str]) -> FileInfo {
+        FileInfo {
+            filename: filename.to_string(),
+            original_filename: Some(original.to_string()),
+            tags: tags.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        let storage = Storage {
+            files: vec![
+                file("0ad415fd-a361.epub", "story.epub", &["book", "topic/ml"]),
+                file("67ee6142-bec8.epub", "story.epub", &["paper"]),
+            ],
+        };
+
+        assert_eq!(display_name(&storage.files[0]), "story-0ad415fd.epub");
+        assert_eq!(decode_component(&encode_component("topic/ml%")), "topic/ml%");
+
+        let mut context = QueryContext::default();
+        context.aliases.add("novel", "book").unwrap();
+
+        let names = |path: &str| -> Vec<String> {
+            list(&storage, path, &context)
+                .unwrap()
+                .iter()
+                .map(|x| x.name().to_string())
+                .collect()
+        };
+        assert_eq!(names("/all"), ["story-0ad415fd.epub", "story-67ee6142.epub"]);
+        assert_eq!(names("/tags"), ["book", "paper", "topic", "topic%2Fml"]);
+        assert_eq!(names("/tags/topic%2Fml/"), ["story-0ad415fd.epub"]);
+        assert_eq!(names("/tags/topic"), ["story-0ad415fd.epub"]);
+        assert_eq!(names("/tags/novel"), names("/query/novel"));
+        assert_eq!(names("/query/book or paper"), names("/all"));
+        assert_eq!(names("/query/novel"), ["story-0ad415fd.epub"]);
+
+        let found = resolve(&storage, "/tags/paper/story-67ee6142.epub", &context).unwrap();
+        assert_eq!(found.filename, "67ee6142-bec8.epub");
+        assert!(resolve(&storage, "/tags/paper/story-0ad415fd.epub", &context).is_err());
+        assert!(list(&storage, "/tags/missing", &context).is_err());
+    }
+}
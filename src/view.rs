@@ -0,0 +1,238 @@
+//! Views are folders of links into the storage, for the files matching a query.
+//! They let the files be opened from a normal file manager without keeping the virtual
+//! filesystem of [`crate::vfs`] mounted, and keep working on systems where it can't be mounted.
+//!
+//! Each view folder has a `.tagg-view` file listing the links that tagg created in it, so that
+//! refreshing only ever removes links that tagg made, and never anything else that was put in
+//! the folder.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    query::QueryContext,
+    storage::{FileInfo, Storage},
+    vfs::{display_name, ID_SUFFIX_LEN},
+};
+
+/// The name of the file in a view folder which lists the links that tagg created
+pub const VIEW_MANIFEST: &str = ".tagg-view";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkKind {
+    Symlink,
+    /// Hardlinks only work if the view is on the same filesystem as the storage.
+    /// Note that programs which save by replacing the file will break the link.
+    Hardlink,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct View {
+    /// The absolute path to the view folder
+    pub path: PathBuf,
+    /// The query that decides which files are in the view
+    pub query: String,
+    pub link: LinkKind,
+    /// Whether to name files by their `title` comment, when they have one, rather than their
+    /// original filename
+    #[serde(default)]
+    pub titles: bool,
+}
+impl View {
+    /// The name the file has in this view
+    pub fn file_name(&self, file: &FileInfo) -> String {
+        let title = if self.titles {
+            file.comments.get("title")
+        } else {
+            None
+        };
+
+        if let Some(title) = title {
+            let id: String = file.filename.chars().take(ID_SUFFIX_LEN).collect();
+            let title = sanitize_name(title);
+            match Path::new(&file.filename).extension() {
+                Some(ext) => format!("{}-{}.{}", title, id, ext.to_string_lossy()),
+                None => format!("{}-{}", title, id),
+            }
+        } else {
+            display_name(file)
+        }
+    }
+
+    /// Bring the links in the view folder up to date with the storage, creating the folder if
+    /// it doesn't exist. The view's query is prepared with the context.
+    pub fn refresh(
+        &self,
+        storage: &Storage,
+        storage_path: &Path,
+        context: &QueryContext,
+    ) -> eyre::Result<ViewChanges> {
+        let query = context.parse(&self.query)?;
+        let wanted: BTreeMap<String, PathBuf> = storage
+            .query(&query)
+            .map(|file| (self.file_name(file), storage_path.join(&file.filename)))
+            .collect();
+
+        let manifest_path = self.path.join(VIEW_MANIFEST);
+        let previous: BTreeSet<String> = if manifest_path.exists() {
+            std::fs::read_to_string(&manifest_path)?
+                .lines()
+                .filter(|x| !x.is_empty())
+                .map(str::to_string)
+                .collect()
+        } else if self.path.exists() && std::fs::read_dir(&self.path)?.next().is_some() {
+            return Err(eyre::eyre!(
+                "{:?} is not empty and is not a tagg view, refusing to put links in it",
+                self.path
+            ));
+        } else {
+            std::fs::create_dir_all(&self.path)?;
+            BTreeSet::new()
+        };
+
+        let mut changes = ViewChanges::default();
+        // The names of the links that tagg made, which are the only ones it will ever remove
+        let mut owned = BTreeSet::new();
+        for name in previous.iter() {
+            if !wanted.contains_key(name) {
+                let link_path = self.path.join(name);
+                if link_path.symlink_metadata().is_ok() {
+                    std::fs::remove_file(&link_path)?;
+                }
+                changes.removed += 1;
+            }
+        }
+
+        for (name, target) in wanted.iter() {
+            let link_path = self.path.join(name);
+            if link_path.symlink_metadata().is_ok() {
+                if previous.contains(name) {
+                    owned.insert(name);
+                    changes.unchanged += 1;
+                    continue;
+                }
+
+                eprintln!(
+                    "WARN: {:?} already exists in the view and was not made by tagg, so it was left alone",
+                    link_path
+                );
+                continue;
+            }
+
+            match self.link {
+                LinkKind::Symlink => symlink_file(target, &link_path)?,
+                LinkKind::Hardlink => std::fs::hard_link(target, &link_path)?,
+            }
+            owned.insert(name);
+            changes.added += 1;
+        }
+
+        let mut manifest = String::new();
+        for name in owned {
+            manifest.push_str(name);
+            manifest.push('\n');
+        }
+        std::fs::write(manifest_path, manifest)?;
+
+        Ok(changes)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ViewChanges {
+    pub added: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
+
+/// Make a title usable as a filename
+fn sanitize_name(title: &str) -> String {
+    let name: String = title
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '\0' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .take(100)
+        .collect();
+    name.trim().to_string()
+}
+
+#[cfg(unix)]
+fn symlink_file(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink_file(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        query::QueryContext,
+        storage::{FileInfo, Storage},
+        util::TempDir,
+    };
+
+    use super::{LinkKind, View, VIEW_MANIFEST};
+
+    #[test]
+    fn test_refresh_keeps_other_files() {
+        let dir = TempDir::new();
+        let storage_path = dir.join("storage");
+        std::fs::create_dir(&storage_path).unwrap();
+        std::fs::write(storage_path.join("0ad415fd-a361.txt"), "story").unwrap();
+
+        let mut storage = Storage {
+            files: vec![FileInfo {
+                filename: "0ad415fd-a361.txt".to_string(),
+                original_filename: Some("story.txt".to_string()),
+                tags: vec!["book".to_string()],
+                ..Default::default()
+            }],
+        };
+        let view = View {
+            path: dir.join("view"),
+            query: "book".to_string(),
+            link: LinkKind::Symlink,
+            titles: false,
+        };
+        let context = QueryContext::default();
+
+        // A file which tagg didn't make is in the way of the link
+        view.refresh(&Storage::default(), &storage_path, &context)
+            .unwrap();
+        let other = view.path.join("story-0ad415fd.txt");
+        std::fs::write(&other, "mine").unwrap();
+
+        let changes = view.refresh(&storage, &storage_path, &context).unwrap();
+        assert_eq!((changes.added, changes.unchanged), (0, 0));
+        let manifest = std::fs::read_to_string(view.path.join(VIEW_MANIFEST)).unwrap();
+        assert!(manifest.is_empty());
+
+        // Leaving the query doesn't remove it
+        storage.files[0].tags.clear();
+        let changes = view.refresh(&storage, &storage_path, &context).unwrap();
+        assert_eq!(changes.removed, 0);
+        assert_eq!(std::fs::read_to_string(&other).unwrap(), "mine");
+
+        // Once it is gone, tagg makes and owns the link
+        std::fs::remove_file(&other).unwrap();
+        storage.files[0].tags.push("book".to_string());
+        let changes = view.refresh(&storage, &storage_path, &context).unwrap();
+        assert_eq!(changes.added, 1);
+        storage.files[0].tags.clear();
+        let changes = view.refresh(&storage, &storage_path, &context).unwrap();
+        assert_eq!(changes.removed, 1);
+        assert!(other.symlink_metadata().is_err());
+    }
+}